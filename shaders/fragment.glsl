@@ -2,11 +2,21 @@
 
 in vec2 f_uv;
 in vec2 f_block;
+in vec2 f_light;
+in float f_ao;
 
 out vec4 color;
 uniform sampler2DArray t;
 
 void main() {
   vec2 uv = vec2(f_uv.x, f_uv.y);
-  color = texture(t, vec3(uv, f_block.x * 16 + (15 - f_block.y)));
+  vec4 texel = texture(t, vec3(uv, f_block.x * 16 + (15 - f_block.y)));
+  // holes in cutout textures like leaves
+  if (texel.a < 0.1) discard;
+  // each light level is 20% darker than the one above it, never fully black
+  float level = max(f_light.x, f_light.y) * 15.0;
+  float brightness = max(pow(0.8, 15.0 - level), 0.05);
+  // fully occluded corners keep 40% of their light
+  brightness *= 0.4 + 0.6 * f_ao;
+  color = vec4(texel.rgb * brightness, texel.a);
 }
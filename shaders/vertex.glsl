@@ -1,18 +1,37 @@
 #version 150
 
-in vec3 position;
-in vec2 uv;
-in vec2 block;
+// packed chunk vertex, see `ChunkVertex`
+in uint position;
+in uint face;
+// world position of the chunk's origin, one per drawn chunk
+in vec3 offset;
 
-uniform mat4 m;
 uniform mat4 v;
 uniform mat4 p;
 
 out vec2 f_uv;
 out vec2 f_block;
+out vec2 f_light;
+out float f_ao;
+
+// texture axes of each face, in `Direction` order
+const vec3 U_AXES[6] = vec3[6](vec3(0, 0, -1), vec3(0, 0, 1), vec3(-1, 0, 0), vec3(1, 0, 0), vec3(1, 0, 0), vec3(-1, 0, 0));
+const vec3 V_AXES[6] = vec3[6](vec3(0, 1, 0), vec3(0, 1, 0), vec3(0, 0, 1), vec3(0, 0, 1), vec3(0, 1, 0), vec3(0, 1, 0));
 
 void main() {
+    vec3 local = vec3(position & 1023u, (position >> 10) & 1023u, (position >> 20) & 1023u) / 16.0;
+    uint direction = face & 7u;
+    uint rotation = (face >> 3) & 3u;
+
+    // textures are projected on the face's axes then rotated clockwise by the face's `Rotation`
+    vec2 uv = vec2(dot(local, U_AXES[int(direction)]), dot(local, V_AXES[int(direction)]));
+    if (rotation == 1u) uv = vec2(-uv.y, uv.x);
+    else if (rotation == 2u) uv = -uv;
+    else if (rotation == 3u) uv = vec2(uv.y, -uv.x);
+
     f_uv = uv;
-    f_block = block;
-    gl_Position = p * v * m * vec4(position, 1.0);
+    f_block = vec2((face >> 5) & 15u, (face >> 9) & 15u);
+    f_light = vec2((face >> 17) & 15u, (face >> 13) & 15u) / 15.0;
+    f_ao = float(position >> 30) / 3.0;
+    gl_Position = p * v * vec4(local + offset, 1.0);
 }
@@ -0,0 +1,276 @@
+use std::collections::BTreeMap;
+
+/// Span of a buffer handed out by an `Allocator`, in elements
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation{
+    pub offset: usize,
+    pub size: usize
+}
+
+impl Allocation{
+    pub fn end(&self) -> usize{
+        self.offset + self.size
+    }
+}
+
+/// Allocation moved by `Allocator::defragment`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation{
+    pub from: usize,
+    pub to: usize,
+    pub size: usize
+}
+
+/// First-fit free-list allocator over a buffer of `capacity` elements.
+///
+/// It only does the bookkeeping: allocations are identified by their offset and
+/// copying data around is up to the owner of the buffer.
+#[derive(Debug, Clone)]
+pub struct Allocator{
+    capacity: usize,
+    /// Free spans sorted by offset, never empty nor touching each other
+    free: Vec<Allocation>,
+    /// Size of each live allocation, by offset
+    allocations: BTreeMap<usize, usize>,
+    used: usize
+}
+
+impl Allocator{
+    pub fn new(capacity: usize) -> Self{
+        let free = if capacity > 0 { vec![Allocation{ offset: 0, size: capacity }] } else { Vec::new() };
+
+        Self{
+            capacity,
+            free,
+            allocations: BTreeMap::new(),
+            used: 0
+        }
+    }
+
+    pub fn capacity(&self) -> usize{
+        self.capacity
+    }
+
+    /// Elements in live allocations
+    pub fn used(&self) -> usize{
+        self.used
+    }
+
+    pub fn free_space(&self) -> usize{
+        self.capacity - self.used()
+    }
+
+    /// Biggest allocation that would currently succeed
+    pub fn largest_free(&self) -> usize{
+        self.free.iter().map(|span| span.size).max().unwrap_or(0)
+    }
+
+    /// Free space outside of the largest free span, which only smaller
+    /// allocations can use
+    pub fn wasted(&self) -> usize{
+        self.free_space() - self.largest_free()
+    }
+
+    /// Share of the free space that can't be used by an allocation as big as all
+    /// of it, from 0 (one free span) to almost 1
+    pub fn fragmentation(&self) -> f32{
+        let free = self.free_space();
+        if free == 0 { return 0. }
+        1. - self.largest_free() as f32 / free as f32
+    }
+
+    /// Takes `size` elements from the first free span big enough, `None` when
+    /// there's none or `size` is zero
+    pub fn allocate(&mut self, size: usize) -> Option<Allocation>{
+        if size == 0 { return None }
+        let index = self.free.iter().position(|span| span.size >= size)?;
+
+        let span = &mut self.free[index];
+        let allocation = Allocation{ offset: span.offset, size };
+        span.offset += size;
+        span.size -= size;
+        if span.size == 0{
+            self.free.remove(index);
+        }
+
+        self.allocations.insert(allocation.offset, size);
+        self.used += size;
+        Some(allocation)
+    }
+
+    /// Gives an allocation back, merging it with the free spans around it.
+    ///
+    /// Returns `false` if nothing was allocated at its offset.
+    pub fn free(&mut self, allocation: Allocation) -> bool{
+        let size = match self.allocations.remove(&allocation.offset){
+            Some(size) => size,
+            None => return false
+        };
+        self.used -= size;
+
+        let mut span = Allocation{ offset: allocation.offset, size };
+        let index = self.free.iter().position(|free| free.offset > span.offset).unwrap_or(self.free.len());
+        if index < self.free.len() && self.free[index].offset == span.end(){
+            span.size += self.free.remove(index).size;
+        }
+        if index > 0 && self.free[index - 1].end() == span.offset{
+            self.free[index - 1].size += span.size;
+        }else{
+            self.free.insert(index, span);
+        }
+
+        true
+    }
+
+    /// Makes room for more elements at the end of the buffer, never shrinking it
+    pub fn grow(&mut self, capacity: usize){
+        if capacity <= self.capacity { return }
+
+        match self.free.last_mut(){
+            Some(last) if last.end() == self.capacity => last.size += capacity - self.capacity,
+            _ => self.free.push(Allocation{ offset: self.capacity, size: capacity - self.capacity }),
+        }
+        self.capacity = capacity;
+    }
+
+    /// Packs every allocation at the start of the buffer, in offset order, leaving
+    /// a single free span at the end.
+    ///
+    /// Returns where each allocation went, including the ones that didn't move,
+    /// so they can all be copied to a new buffer.
+    pub fn defragment(&mut self) -> Vec<Relocation>{
+        let mut relocations = Vec::with_capacity(self.allocations.len());
+        let mut offset = 0;
+        for (from, size) in &self.allocations{
+            relocations.push(Relocation{ from: *from, to: offset, size: *size });
+            offset += size;
+        }
+
+        self.allocations = relocations.iter().map(|relocation| (relocation.to, relocation.size)).collect();
+        self.free.clear();
+        if offset < self.capacity{
+            self.free.push(Allocation{ offset, size: self.capacity - offset });
+        }
+
+        relocations
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn spans(allocator: &Allocator) -> Vec<(usize, usize)>{
+        allocator.free.iter().map(|span| (span.offset, span.size)).collect()
+    }
+
+    #[test]
+    fn first_fit(){
+        let mut allocator = Allocator::new(100);
+        let a = allocator.allocate(10).unwrap();
+        let b = allocator.allocate(20).unwrap();
+        let c = allocator.allocate(30).unwrap();
+        assert_eq!((a.offset, b.offset, c.offset), (0, 10, 30));
+        assert_eq!(allocator.used(), 60);
+
+        // the hole left by `a` is the first one big enough
+        allocator.free(a);
+        assert_eq!(allocator.allocate(5).unwrap().offset, 0);
+        // too big for what's left of it, taken from the end
+        assert_eq!(allocator.allocate(8).unwrap().offset, 60);
+        assert_eq!(allocator.allocate(5).unwrap().offset, 5);
+        assert_eq!(allocator.allocate(33), None);
+        assert_eq!(allocator.largest_free(), 32);
+    }
+
+    #[test]
+    fn zero_size(){
+        let mut allocator = Allocator::new(10);
+        assert_eq!(allocator.allocate(0), None);
+        assert_eq!(spans(&allocator), vec![(0, 10)]);
+
+        let mut empty = Allocator::new(0);
+        assert_eq!(empty.allocate(1), None);
+        assert_eq!(empty.largest_free(), 0);
+        assert_eq!(empty.fragmentation(), 0.);
+    }
+
+    #[test]
+    fn free_merges_with_neighbours(){
+        let mut allocator = Allocator::new(50);
+        let blocks: Vec<Allocation> = (0..5).map(|_| allocator.allocate(10).unwrap()).collect();
+        assert!(spans(&allocator).is_empty());
+
+        allocator.free(blocks[0]);
+        allocator.free(blocks[2]);
+        assert_eq!(spans(&allocator), vec![(0, 10), (20, 10)]);
+
+        // with the previous span
+        allocator.free(blocks[3]);
+        assert_eq!(spans(&allocator), vec![(0, 10), (20, 20)]);
+        assert_eq!(allocator.wasted(), 10);
+
+        // with the next span
+        let mut other = Allocator::new(30);
+        let parts: Vec<Allocation> = (0..3).map(|_| other.allocate(10).unwrap()).collect();
+        other.free(parts[2]);
+        other.free(parts[1]);
+        assert_eq!(spans(&other), vec![(10, 20)]);
+
+        // with both
+        allocator.free(blocks[1]);
+        assert_eq!(spans(&allocator), vec![(0, 40)]);
+        allocator.free(blocks[4]);
+        assert_eq!(spans(&allocator), vec![(0, 50)]);
+        assert_eq!(allocator.used(), 0);
+    }
+
+    #[test]
+    fn double_free(){
+        let mut allocator = Allocator::new(20);
+        let a = allocator.allocate(10).unwrap();
+        assert!(allocator.free(a));
+        assert!(!allocator.free(a));
+        assert!(!allocator.free(Allocation{ offset: 5, size: 5 }));
+        assert_eq!(allocator.used(), 0);
+        assert_eq!(spans(&allocator), vec![(0, 20)]);
+    }
+
+    #[test]
+    fn grow(){
+        let mut allocator = Allocator::new(20);
+        allocator.allocate(15).unwrap();
+        allocator.grow(40);
+        assert_eq!(spans(&allocator), vec![(15, 25)]);
+
+        // a full buffer gets a new span
+        allocator.allocate(25).unwrap();
+        allocator.grow(50);
+        assert_eq!(spans(&allocator), vec![(40, 10)]);
+
+        allocator.grow(30);
+        assert_eq!(allocator.capacity(), 50);
+    }
+
+    #[test]
+    fn defragment(){
+        let mut allocator = Allocator::new(100);
+        let blocks: Vec<Allocation> = [10, 20, 5, 15].iter().map(|size| allocator.allocate(*size).unwrap()).collect();
+        allocator.free(blocks[0]);
+        allocator.free(blocks[2]);
+        assert!(allocator.fragmentation() > 0.);
+
+        let relocations = allocator.defragment();
+        assert_eq!(relocations, vec![
+            Relocation{ from: 10, to: 0, size: 20 },
+            Relocation{ from: 35, to: 20, size: 15 }
+        ]);
+        assert_eq!(spans(&allocator), vec![(35, 65)]);
+        assert_eq!(allocator.fragmentation(), 0.);
+
+        // allocations are known by their new offsets
+        assert!(allocator.free(Allocation{ offset: 20, size: 15 }));
+        assert!(!allocator.free(Allocation{ offset: 35, size: 15 }));
+        assert_eq!(spans(&allocator), vec![(20, 80)]);
+    }
+}
@@ -0,0 +1,343 @@
+use crate::engine::allocator::{Allocation, Allocator};
+use crate::engine::mesh::MeshData;
+use crate::engine::renderer::{FrameUniforms, MeshDraw, MeshHandle};
+use crate::utils::texture::TextureArray;
+use glium::index::{DrawCommandIndices, DrawCommandsIndicesBuffer, PrimitiveType};
+use glium::uniforms::Uniforms;
+use glium::Surface;
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Smallest buffers an arena starts with, in elements
+const MIN_CAPACITY: usize = 1 << 16;
+/// Smallest amount of draws the instance offsets and draw commands buffers hold
+const MIN_DRAWS: usize = 256;
+/// Fragmentation past which `BufferArena::maintain` compacts the buffers
+const MAX_FRAGMENTATION: f32 = 0.5;
+/// Elements that must be wasted in holes before `BufferArena::maintain` compacts
+/// the buffers, so small ones aren't copied over and over for a few holes
+const MIN_WASTE: usize = MIN_CAPACITY / 4;
+
+/// Whether enough of a buffer is lost to fragmentation to be worth copying it
+fn needs_compaction(allocator: &Allocator) -> bool{
+    allocator.fragmentation() > MAX_FRAGMENTATION && allocator.wasted() >= MIN_WASTE
+}
+
+/// Per-instance attribute of a mesh drawn from an arena: the world position its
+/// vertices are offset by
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct InstanceOffset{
+    pub offset: [f32; 3]
+}
+
+implement_vertex!(InstanceOffset, offset);
+
+/// State a pass draws an arena's meshes with
+pub struct ArenaDraw<'a>{
+    pub program: &'a glium::Program,
+    pub parameters: glium::DrawParameters<'a>,
+    pub uniforms: &'a FrameUniforms,
+    /// Texture array bound as `t`, if one was loaded
+    pub texture: Option<&'a TextureArray>,
+    /// Whether all meshes can be drawn with one multi-draw call, otherwise
+    /// they're drawn one by one
+    pub multi_draw: bool
+}
+
+/// Arena of meshes of any vertex type, so a renderer can keep one for each type
+/// of mesh it's given
+pub trait MeshArena{
+    fn remove(&mut self, mesh: &MeshHandle) -> bool;
+
+    /// Draws the stored meshes of `draws`, skipping the others
+    fn draw(&mut self, frame: &mut glium::Frame, display: &glium::Display, draws: &[MeshDraw], state: &ArenaDraw);
+
+    fn maintain(&mut self, display: &glium::Display);
+
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+/// Where a mesh lives in an arena's buffers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArenaMesh{
+    pub vertices: Allocation,
+    pub indices: Allocation
+}
+
+/// Meshes sub-allocated out of one shared vertex buffer and one shared index
+/// buffer, so they can all be drawn with a single multi-draw call.
+///
+/// Indices stay relative to their mesh's first vertex, draw commands add it as
+/// their base vertex. Buffers are only created by the first `insert`, and are
+/// replaced by bigger or compacted copies when space runs out.
+///
+/// The instance offsets and draw commands are rewritten every draw into buffers
+/// kept between frames, which are replaced by bigger ones when a draw doesn't fit.
+pub struct BufferArena<K, V: Copy>{
+    buffers: Option<(glium::VertexBuffer<V>, glium::IndexBuffer<u32>)>,
+    instances: Option<glium::VertexBuffer<InstanceOffset>>,
+    commands: Option<DrawCommandsIndicesBuffer>,
+    vertex_allocator: Allocator,
+    index_allocator: Allocator,
+    meshes: HashMap<K, ArenaMesh>
+}
+
+#[allow(dead_code)]
+impl<K: Eq + Hash + Copy, V: glium::Vertex> BufferArena<K, V>{
+    pub fn new() -> Self{
+        Self{
+            buffers: None,
+            instances: None,
+            commands: None,
+            vertex_allocator: Allocator::new(0),
+            index_allocator: Allocator::new(0),
+            meshes: HashMap::new()
+        }
+    }
+
+    /// Uploads a mesh, replacing the one stored under `key`. Empty meshes are removed.
+    pub fn insert(&mut self, display: &glium::Display, key: K, data: &MeshData<V>){
+        self.remove(&key);
+        if data.vertices.is_empty() || data.indices.is_empty() { return }
+
+        let (vertex_count, index_count) = (data.vertices.len(), data.indices.len());
+        if self.vertex_allocator.largest_free() < vertex_count || self.index_allocator.largest_free() < index_count{
+            let capacity = |allocator: &Allocator, size: usize|{
+                if allocator.free_space() >= size{
+                    allocator.capacity()
+                }else{
+                    (allocator.capacity() * 2).max(allocator.used() + size).max(MIN_CAPACITY)
+                }
+            };
+            let capacities = (capacity(&self.vertex_allocator, vertex_count), capacity(&self.index_allocator, index_count));
+            self.relocate(display, capacities.0, capacities.1);
+        }
+
+        let vertices = self.vertex_allocator.allocate(vertex_count).expect("Couldn't allocate vertices after growing the arena");
+        let indices = self.index_allocator.allocate(index_count).expect("Couldn't allocate indices after growing the arena");
+        let (vb, ib) = self.buffers.as_ref().expect("Arena buffers missing after growing");
+        vb.slice(vertices.offset..vertices.end()).expect("Vertex allocation out of the buffer").write(&data.vertices);
+        ib.slice(indices.offset..indices.end()).expect("Index allocation out of the buffer").write(&data.indices);
+
+        self.meshes.insert(key, ArenaMesh{ vertices, indices });
+    }
+
+    pub fn remove(&mut self, key: &K) -> bool{
+        match self.meshes.remove(key){
+            Some(mesh) => {
+                self.vertex_allocator.free(mesh.vertices);
+                self.index_allocator.free(mesh.indices);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn contains(&self, key: &K) -> bool{
+        self.meshes.contains_key(key)
+    }
+
+    pub fn get(&self, key: &K) -> Option<&ArenaMesh>{
+        self.meshes.get(key)
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K>{
+        self.meshes.keys()
+    }
+
+    pub fn len(&self) -> usize{
+        self.meshes.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.meshes.is_empty()
+    }
+
+    /// Command drawing a mesh with the given instance of the per-instance attributes
+    pub fn draw_command(&self, key: &K, instance: u32) -> Option<DrawCommandIndices>{
+        self.meshes.get(key).map(|mesh| DrawCommandIndices{
+            count: mesh.indices.size as u32,
+            instance_count: 1,
+            first_index: mesh.indices.offset as u32,
+            base_vertex: mesh.vertices.offset as u32,
+            base_instance: instance
+        })
+    }
+
+    /// Shared buffers to draw commands from, `None` until something was inserted
+    pub fn buffers(&self) -> Option<(&glium::VertexBuffer<V>, &glium::IndexBuffer<u32>)>{
+        self.buffers.as_ref().map(|(vb, ib)| (vb, ib))
+    }
+
+    /// Size of the shared buffers, used or not, in bytes
+    pub fn memory_usage(&self) -> usize{
+        self.vertex_allocator.capacity() * std::mem::size_of::<V>() + self.index_allocator.capacity() * std::mem::size_of::<u32>()
+    }
+
+    /// Compacts the buffers once too much of their free space is scattered in
+    /// holes too small to be used
+    pub fn maintain(&mut self, display: &glium::Display){
+        if needs_compaction(&self.vertex_allocator) || needs_compaction(&self.index_allocator){
+            self.relocate(display, self.vertex_allocator.capacity(), self.index_allocator.capacity());
+        }
+    }
+
+    /// Moves every mesh to the start of new buffers with the given capacities.
+    ///
+    /// Copying to new buffers rather than within the old ones keeps the ranges
+    /// from overlapping.
+    fn relocate(&mut self, display: &glium::Display, vertex_capacity: usize, index_capacity: usize){
+        self.vertex_allocator.grow(vertex_capacity);
+        self.index_allocator.grow(index_capacity);
+        let vertex_moves: HashMap<usize, usize> = self.vertex_allocator.defragment().iter().map(|relocation| (relocation.from, relocation.to)).collect();
+        let index_moves: HashMap<usize, usize> = self.index_allocator.defragment().iter().map(|relocation| (relocation.from, relocation.to)).collect();
+
+        let vb = glium::VertexBuffer::empty_dynamic(display, self.vertex_allocator.capacity()).expect("Couldn't create the arena's VB");
+        let ib = glium::IndexBuffer::empty_dynamic(display, PrimitiveType::TrianglesList, self.index_allocator.capacity()).expect("Couldn't create the arena's IB");
+        if let Some((old_vb, old_ib)) = self.buffers.take(){
+            for mesh in self.meshes.values_mut(){
+                let vertices = Allocation{ offset: vertex_moves[&mesh.vertices.offset], size: mesh.vertices.size };
+                let indices = Allocation{ offset: index_moves[&mesh.indices.offset], size: mesh.indices.size };
+
+                old_vb.slice(mesh.vertices.offset..mesh.vertices.end()).expect("Vertex allocation out of the buffer")
+                    .copy_to(vb.slice(vertices.offset..vertices.end()).expect("Vertex allocation out of the buffer"))
+                    .expect("Couldn't copy vertices between arena buffers");
+                old_ib.slice(mesh.indices.offset..mesh.indices.end()).expect("Index allocation out of the buffer")
+                    .copy_to(ib.slice(indices.offset..indices.end()).expect("Index allocation out of the buffer"))
+                    .expect("Couldn't copy indices between arena buffers");
+
+                *mesh = ArenaMesh{ vertices, indices };
+            }
+        }
+
+        self.buffers = Some((vb, ib));
+    }
+}
+
+impl<V: glium::Vertex + 'static> BufferArena<MeshHandle, V>{
+    /// Makes sure the instance offsets and draw commands buffers hold `draws` draws
+    fn reserve_draws(&mut self, display: &glium::Display, draws: usize){
+        let capacity = draws.next_power_of_two().max(MIN_DRAWS);
+        if self.instances.as_ref().map(|instances| instances.len() < draws).unwrap_or(true){
+            self.instances = Some(glium::VertexBuffer::empty_dynamic(display, capacity).expect("Couldn't create the instance offsets VB"));
+        }
+        if self.commands.as_ref().map(|commands| commands.len() < draws).unwrap_or(true){
+            self.commands = Some(DrawCommandsIndicesBuffer::empty_dynamic(display, capacity).expect("Couldn't create the draw commands buffer"));
+        }
+    }
+
+    fn draw_with<U: Uniforms>(&mut self, frame: &mut glium::Frame, display: &glium::Display, draws: &[MeshDraw], state: &ArenaDraw, uniforms: &U){
+        if self.buffers.is_none() || !draws.iter().any(|draw| self.contains(&draw.mesh)) { return }
+        self.reserve_draws(display, draws.len());
+        let (vb, ib) = self.buffers().expect("Arena buffers missing");
+
+        // every draw is an instance of its offset
+        let offsets: Vec<InstanceOffset> = draws.iter().map(|draw| InstanceOffset{ offset: draw.offset }).collect();
+        let instances = self.instances.as_ref().expect("Instance offsets VB missing after reserving it");
+        instances.slice(0..offsets.len()).expect("Instance offsets out of the buffer").write(&offsets);
+
+        if !state.multi_draw{
+            // each mesh is drawn from its slices, with a one instance slice of the offsets
+            for (instance, draw) in draws.iter().enumerate(){
+                let mesh = match self.get(&draw.mesh){
+                    Some(mesh) => mesh,
+                    None => continue,
+                };
+                let vertices = vb.slice(mesh.vertices.offset..mesh.vertices.end()).expect("Vertex allocation out of the buffer");
+                let indices = ib.slice(mesh.indices.offset..mesh.indices.end()).expect("Index allocation out of the buffer");
+                let offset = instances.slice(instance..instance + 1).expect("Instance offset out of the buffer");
+                let offset = offset.per_instance().expect("Instancing isn't supported");
+                frame.draw((vertices, offset), indices, state.program, uniforms, &state.parameters).unwrap();
+            }
+            return;
+        }
+
+        // the rest of the buffer is filled with commands drawing nothing
+        let buffer = self.commands.as_ref().expect("Draw commands buffer missing after reserving it");
+        let mut commands: Vec<DrawCommandIndices> = draws.iter()
+            .enumerate()
+            .filter_map(|(instance, draw)| self.draw_command(&draw.mesh, instance as u32))
+            .collect();
+        commands.resize(buffer.len(), DrawCommandIndices{
+            count: 0,
+            instance_count: 0,
+            first_index: 0,
+            base_vertex: 0,
+            base_instance: 0
+        });
+        buffer.write(&commands);
+        let instances = instances.per_instance().expect("Instancing isn't supported");
+
+        frame.draw((vb, instances), buffer.with_index_buffer(ib), state.program, uniforms, &state.parameters).unwrap();
+    }
+}
+
+impl<V: glium::Vertex + 'static> MeshArena for BufferArena<MeshHandle, V>{
+    fn remove(&mut self, mesh: &MeshHandle) -> bool{
+        BufferArena::remove(self, mesh)
+    }
+
+    fn draw(&mut self, frame: &mut glium::Frame, display: &glium::Display, draws: &[MeshDraw], state: &ArenaDraw){
+        match state.texture{
+            Some(texture) => {
+                let texture = texture.sampled()
+                    .magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest)
+                    .wrap_function(glium::uniforms::SamplerWrapFunction::Repeat);
+                let uniforms = uniform!{
+                    v: state.uniforms.view,
+                    p: state.uniforms.projection,
+                    t: texture
+                };
+                self.draw_with(frame, display, draws, state, &uniforms);
+            },
+            None => {
+                let uniforms = uniform!{
+                    v: state.uniforms.view,
+                    p: state.uniforms.projection
+                };
+                self.draw_with(frame, display, draws, state, &uniforms);
+            }
+        }
+    }
+
+    fn maintain(&mut self, display: &glium::Display){
+        BufferArena::maintain(self, display)
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any{
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// Allocator with every other span of `size` elements freed
+    fn checkered(spans: usize, size: usize) -> Allocator{
+        let mut allocator = Allocator::new(spans * size * 2);
+        let allocations: Vec<Allocation> = (0..spans * 2).map(|_| allocator.allocate(size).unwrap()).collect();
+        for allocation in allocations.iter().step_by(2){
+            allocator.free(*allocation);
+        }
+        allocator
+    }
+
+    #[test]
+    fn compaction_needs_waste(){
+        // fragmented, but only a few elements are lost
+        let small = checkered(8, 16);
+        assert!(small.fragmentation() > MAX_FRAGMENTATION);
+        assert!(!needs_compaction(&small));
+
+        let large = checkered(8, MIN_WASTE / 4);
+        assert!(needs_compaction(&large));
+
+        // a lot of free space in one span is just unused
+        let mut unused = Allocator::new(MIN_CAPACITY * 4);
+        unused.allocate(10).unwrap();
+        assert!(!needs_compaction(&unused));
+    }
+}
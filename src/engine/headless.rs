@@ -0,0 +1,198 @@
+use crate::engine::mesh::MeshData;
+use crate::engine::pass::RenderPass;
+use crate::engine::renderer::{FrameUniforms, MeshDraw, MeshHandle, Renderer};
+use crate::engine::input::InputEvent;
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Size of an uploaded mesh
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MeshRecord{
+    pub vertices: usize,
+    pub indices: usize
+}
+
+/// One `draw_meshes` call, with the meshes it drew in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct DrawRecord{
+    pub pass: String,
+    pub meshes: Vec<MeshHandle>,
+    pub uniforms: FrameUniforms
+}
+
+/// Renderer without a window nor a GPU, which only records what it's asked to do
+/// so the game can run and be checked anywhere.
+///
+/// Events to play are queued with `push_event` and handed out by the next `poll_events`.
+/// Like with a window, drawing with a pass or program that wasn't added panics.
+pub struct HeadlessRenderer{
+    aspect_ratio: f64,
+    atlas: Option<PathBuf>,
+    /// Vertex and fragment shader files of each program
+    programs: HashMap<String, (String, String)>,
+    passes: HashMap<String, RenderPass>,
+    meshes: HashMap<MeshHandle, MeshRecord>,
+    /// Copy of each uploaded mesh's `MeshData`, of whichever vertex type
+    data: HashMap<MeshHandle, Box<dyn Any>>,
+    uploads: usize,
+    frames: usize,
+    /// Draws of the frame in progress
+    draws: Vec<DrawRecord>,
+    /// Draws of the last finished frame
+    last_frame: Vec<DrawRecord>,
+    clear_color: Option<[f32; 4]>,
+    events: Vec<InputEvent>,
+    mouse_grab: bool
+}
+
+#[allow(dead_code)]
+impl HeadlessRenderer{
+    pub fn new(aspect_ratio: f64) -> Self{
+        Self{
+            aspect_ratio,
+            atlas: None,
+            programs: HashMap::new(),
+            passes: HashMap::new(),
+            meshes: HashMap::new(),
+            data: HashMap::new(),
+            uploads: 0,
+            frames: 0,
+            draws: Vec::new(),
+            last_frame: Vec::new(),
+            clear_color: None,
+            events: Vec::new(),
+            mouse_grab: true
+        }
+    }
+
+    /// Queues an event for the next `poll_events`
+    pub fn push_event(&mut self, event: InputEvent){
+        self.events.push(event);
+    }
+
+    /// Meshes currently uploaded
+    pub fn meshes(&self) -> &HashMap<MeshHandle, MeshRecord>{
+        &self.meshes
+    }
+
+    pub fn mesh(&self, mesh: MeshHandle) -> Option<MeshRecord>{
+        self.meshes.get(&mesh).cloned()
+    }
+
+    /// What was uploaded as `mesh`, `None` if it was removed or has other vertices
+    pub fn mesh_data<V: glium::Vertex + 'static>(&self, mesh: MeshHandle) -> Option<&MeshData<V>>{
+        self.data.get(&mesh).and_then(|data| data.downcast_ref())
+    }
+
+    /// Non-empty meshes uploaded so far, replaced ones included
+    pub fn upload_count(&self) -> usize{
+        self.uploads
+    }
+
+    /// Frames finished with `end_frame`
+    pub fn frame_count(&self) -> usize{
+        self.frames
+    }
+
+    pub fn last_frame(&self) -> &[DrawRecord]{
+        &self.last_frame
+    }
+
+    /// Meshes drawn in the last finished frame, in every pass
+    pub fn drawn_meshes(&self) -> usize{
+        self.last_frame.iter().map(|draw| draw.meshes.len()).sum()
+    }
+
+    /// Color the last frame was cleared to
+    pub fn clear_color(&self) -> Option<[f32; 4]>{
+        self.clear_color
+    }
+
+    pub fn atlas(&self) -> Option<&Path>{
+        self.atlas.as_deref()
+    }
+
+    /// Vertex and fragment shader files a program was added with
+    pub fn program(&self, name: &str) -> Option<(&str, &str)>{
+        self.programs.get(name).map(|(vertex, fragment)| (vertex.as_str(), fragment.as_str()))
+    }
+
+    pub fn pass(&self, name: &str) -> Option<&RenderPass>{
+        self.passes.get(name)
+    }
+
+    pub fn is_mouse_grabbed(&self) -> bool{
+        self.mouse_grab
+    }
+}
+
+impl Renderer for HeadlessRenderer{
+    fn begin_frame(&mut self, color: [f32; 4]){
+        self.draws.clear();
+        self.clear_color = Some(color);
+    }
+
+    fn end_frame(&mut self){
+        self.last_frame = std::mem::take(&mut self.draws);
+        self.frames += 1;
+    }
+
+    fn aspect_ratio(&self) -> f64{
+        self.aspect_ratio
+    }
+
+    fn load_atlas(&mut self, path: &Path, _tile_size: u32){
+        self.atlas = Some(path.to_path_buf());
+    }
+
+    fn add_program(&mut self, name: &str, vertex: &str, fragment: &str){
+        self.programs.insert(name.to_string(), (vertex.to_string(), fragment.to_string()));
+    }
+
+    fn add_pass(&mut self, name: &str, pass: RenderPass){
+        self.passes.insert(name.to_string(), pass);
+    }
+
+    fn upload_mesh<V: glium::Vertex + 'static>(&mut self, data: &MeshData<V>) -> Option<MeshHandle>{
+        if data.vertices.is_empty() || data.indices.is_empty() { return None }
+
+        let mesh = MeshHandle::new(self.uploads as u64);
+        self.meshes.insert(mesh, MeshRecord{ vertices: data.vertices.len(), indices: data.indices.len() });
+        self.data.insert(mesh, Box::new(data.clone()));
+        self.uploads += 1;
+        Some(mesh)
+    }
+
+    fn remove_mesh(&mut self, mesh: MeshHandle){
+        self.meshes.remove(&mesh);
+        self.data.remove(&mesh);
+    }
+
+    fn draw_meshes(&mut self, pass: &str, draws: &[MeshDraw], uniforms: &FrameUniforms){
+        let program = self.passes.get(pass).expect("Unknown render pass").program();
+        assert!(self.programs.contains_key(program), "Unknown shader program");
+
+        let meshes: Vec<MeshHandle> = draws.iter()
+            .map(|draw| draw.mesh)
+            .filter(|mesh| self.meshes.contains_key(mesh))
+            .collect();
+
+        self.draws.push(DrawRecord{
+            pass: pass.to_string(),
+            meshes,
+            uniforms: *uniforms
+        });
+    }
+
+    fn poll_events(&mut self) -> Vec<InputEvent>{
+        std::mem::take(&mut self.events)
+    }
+
+    fn grab_mouse(&mut self){
+        self.mouse_grab = !self.mouse_grab;
+    }
+
+    fn reset_mouse_position(&mut self){}
+}
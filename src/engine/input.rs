@@ -0,0 +1,24 @@
+/// Keys the game reacts to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key{
+    W,
+    A,
+    S,
+    D,
+    Space,
+    LShift,
+    P,
+    Escape
+}
+
+/// Window and device input, whatever the renderer gets it from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputEvent{
+    CloseRequested,
+    Key{
+        key: Key,
+        pressed: bool
+    },
+    /// Raw mouse movement, unaffected by the cursor being grabbed
+    MouseMotion(f64, f64)
+}
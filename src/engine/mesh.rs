@@ -1,12 +1,16 @@
 use crate::engine::Vertex;
 
-#[derive(Clone)]
-pub struct MeshData{
-    pub vertices: Vec<Vertex>,
+use std::mem;
+
+/// Vertices and triangle indices of a mesh, generic over the vertex format so
+/// meshes that don't need full precision can use a packed one
+#[derive(Debug, Clone, PartialEq)]
+pub struct MeshData<V = Vertex>{
+    pub vertices: Vec<V>,
     pub indices: Vec<u32>
 }
 
-impl MeshData{
+impl<V: glium::Vertex> MeshData<V>{
     pub fn new() -> Self{
         Self{
             vertices: Vec::new(),
@@ -14,18 +18,21 @@ impl MeshData{
         }
     }
 
-    pub fn add(&mut self, vertices: Vec<Vertex>, mut indices: Vec<u32>){
+    pub fn add(&mut self, vertices: &[V], indices: &[u32]){
         let index_count = self.vertices.len() as u32;
 
-        for index in &mut indices {
-            *index += index_count
-        }
+        self.vertices.extend_from_slice(vertices);
+        self.indices.extend(indices.iter().map(|index| index + index_count));
+    }
 
-        self.vertices.extend_from_slice(&vertices);
-        self.indices.extend_from_slice(&indices);
+    /// Size of the vertex and index buffers once uploaded, in bytes
+    pub fn memory_usage(&self) -> usize{
+        self.vertices.len() * mem::size_of::<V>() + self.indices.len() * mem::size_of::<u32>()
     }
 
-    pub fn build(&self, display: &glium::Display) -> Mesh{
+    /// Uploads the mesh to buffers of its own
+    #[allow(dead_code)]
+    pub fn build(&self, display: &glium::Display) -> Mesh<V>{
         Mesh{
             vb: glium::vertex::VertexBuffer::immutable(display, &self.vertices[..]).expect("Couldn't create VB"),
             ib: glium::IndexBuffer::immutable(display, glium::index::PrimitiveType::TrianglesList, &self.indices[..]).expect("Couldn't create IB")
@@ -33,13 +40,16 @@ impl MeshData{
     }
 }
 
-pub struct Mesh{
-    vb: glium::vertex::VertexBuffer<Vertex>,
+/// Mesh with buffers of its own, chunks share theirs in a `BufferArena` instead
+#[allow(dead_code)]
+pub struct Mesh<V: Copy = Vertex>{
+    vb: glium::vertex::VertexBuffer<V>,
     ib: glium::index::IndexBuffer<u32>
 }
 
-impl Mesh{
-    pub fn get_vb(&self) -> &glium::vertex::VertexBuffer<Vertex>{
+#[allow(dead_code)]
+impl<V: Copy> Mesh<V>{
+    pub fn get_vb(&self) -> &glium::vertex::VertexBuffer<V>{
         &self.vb
     }
 
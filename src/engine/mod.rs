@@ -1,21 +1,21 @@
-#[derive(Copy, Clone, Debug)]
+/// Full-precision vertex, for meshes that don't fit the packed chunk format
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct Vertex {
     pub position: [f32; 3],
     pub uv: [f32; 2],
-    pub block: [u32; 2]
+    pub block: [u32; 2],
+    /// Sky and block light in front of the face, from 0 to 1
+    pub light: [f32; 2],
+    /// Ambient occlusion of the vertex, from 0 (fully occluded) to 1
+    pub ao: f32
 }
 
-impl Vertex{
-    pub const fn new(position: [f32; 3], uv: [f32; 2], block: [u32; 2]) -> Vertex{
-        Vertex{
-            position,
-            uv,
-            block
-        }
-    }
-}
-
-implement_vertex!(Vertex, position, uv, block);
+implement_vertex!(Vertex, position, uv, block, light, ao);
 
 pub mod renderer;
+pub mod headless;
+pub mod input;
+pub mod pass;
 pub mod mesh;
+pub mod allocator;
+pub mod arena;
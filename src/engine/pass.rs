@@ -0,0 +1,106 @@
+/// Program chunk meshes are drawn with
+pub const TERRAIN_PROGRAM: &str = "terrain";
+
+pub const OPAQUE_TERRAIN: &str = "opaque_terrain";
+/// Blended over the opaque terrain, drawn back to front
+pub const TRANSLUCENT_TERRAIN: &str = "translucent_terrain";
+
+/// How what's drawn is combined with what's already there
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Blend{
+    Replace,
+    /// Mixed by the drawn color's alpha
+    Alpha
+}
+
+/// Program and fixed-function state a kind of geometry is drawn with.
+///
+/// Renderers keep passes by name, new ones are added with `Renderer::add_pass`.
+/// Every pass tests against the depth buffer and culls back faces.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderPass{
+    program: String,
+    blend: Blend,
+    depth_write: bool
+}
+
+impl RenderPass{
+    pub fn new(program: &str) -> Self{
+        Self{
+            program: program.to_string(),
+            blend: Blend::Replace,
+            depth_write: true
+        }
+    }
+
+    pub fn blend(mut self, blend: Blend) -> Self{
+        self.blend = blend;
+        self
+    }
+
+    pub fn depth_write(mut self, depth_write: bool) -> Self{
+        self.depth_write = depth_write;
+        self
+    }
+
+    /// Solid geometry hiding what's behind it, like terrain and entities
+    pub fn opaque(program: &str) -> Self{
+        Self::new(program)
+    }
+
+    /// Blended over what's already drawn without hiding what's behind it, so it
+    /// should come after the opaque passes
+    pub fn translucent(program: &str) -> Self{
+        Self::new(program)
+            .blend(Blend::Alpha)
+            .depth_write(false)
+    }
+
+    pub fn program(&self) -> &str{
+        &self.program
+    }
+
+    /// The pass' state as glium draw parameters
+    pub fn draw_parameters(&self) -> glium::DrawParameters<'static>{
+        let blend = match self.blend{
+            Blend::Replace => glium::Blend::default(),
+            Blend::Alpha => glium::Blend::alpha_blending(),
+        };
+
+        glium::DrawParameters{
+            depth: glium::Depth{
+                test: glium::DepthTest::IfLess,
+                write: self.depth_write,
+                ..Default::default()
+            },
+            blend,
+            backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use glium::draw_parameters::BackfaceCullingMode;
+
+    #[test]
+    fn terrain_passes(){
+        let opaque = RenderPass::opaque("terrain");
+        assert_eq!(opaque, RenderPass::new("terrain"));
+        let parameters = opaque.draw_parameters();
+        assert!(parameters.depth.write);
+        assert_eq!(parameters.depth.test, glium::DepthTest::IfLess);
+        assert_eq!(parameters.backface_culling, BackfaceCullingMode::CullClockwise);
+        assert_eq!(parameters.blend, glium::Blend::default());
+
+        let translucent = RenderPass::translucent("terrain");
+        assert_eq!(translucent.program(), "terrain");
+        let parameters = translucent.draw_parameters();
+        assert!(!parameters.depth.write);
+        assert_eq!(parameters.depth.test, glium::DepthTest::IfLess);
+        assert_eq!(parameters.backface_culling, BackfaceCullingMode::CullClockwise);
+        assert_eq!(parameters.blend, glium::Blend::alpha_blending());
+    }
+}
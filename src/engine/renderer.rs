@@ -1,6 +1,11 @@
-use crate::engine::Vertex;
-use glium::uniforms::{AsUniformValue, Uniforms};
+use crate::engine::arena::{ArenaDraw, BufferArena, MeshArena};
+use crate::engine::input::{InputEvent, Key};
+use crate::engine::mesh::MeshData;
+use crate::engine::pass::RenderPass;
+use crate::utils::texture::TextureStorage;
 use glium::{glutin, Surface};
+use std::any::TypeId;
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 
@@ -8,19 +13,135 @@ use std::path::Path;
 pub const DEFAULT_WIDTH: u32 = 1024;
 pub const DEFAULT_HEIGHT: u32 = 768;
 
+/// Mesh uploaded to a renderer, only meaningful to the renderer that handed it out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MeshHandle(u64);
+
+impl MeshHandle {
+    pub(super) fn new(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+/// Mesh to draw, and the world position its vertices are offset by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MeshDraw {
+    pub mesh: MeshHandle,
+    pub offset: [f32; 3],
+}
+
+/// Uniforms shared by every draw of a frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameUniforms {
+    pub view: [[f32; 4]; 4],
+    pub projection: [[f32; 4]; 4],
+}
+
+/// What the game needs from whatever draws it, so it can run with a window
+/// (`Context`) or without (`HeadlessRenderer`)
+pub trait Renderer {
+    /// Starts a frame cleared to `color`
+    fn begin_frame(&mut self, color: [f32; 4]);
+
+    /// Presents the frame started by `begin_frame`
+    fn end_frame(&mut self);
+
+    /// Width over height of what's drawn to
+    fn aspect_ratio(&self) -> f64;
+
+    /// Loads the block texture atlas, cut into square tiles of `tile_size` pixels
+    fn load_atlas(&mut self, path: &Path, tile_size: u32);
+
+    /// Compiles a shader program from files of the `shaders` directory, replacing
+    /// the one with the same name
+    fn add_program(&mut self, name: &str, vertex: &str, fragment: &str);
+
+    /// Adds a pass to draw with, replacing the one with the same name
+    fn add_pass(&mut self, name: &str, pass: RenderPass);
+
+    /// Uploads a mesh of any vertex type, `None` if it's empty and there's
+    /// nothing to draw
+    fn upload_mesh<V: glium::Vertex + 'static>(&mut self, data: &MeshData<V>) -> Option<MeshHandle>;
+
+    fn remove_mesh(&mut self, mesh: MeshHandle);
+
+    /// Draws the uploaded meshes of `draws` with the pass named `pass`, in order
+    /// for meshes of the same vertex type. Draws whose mesh was removed are skipped.
+    fn draw_meshes(&mut self, pass: &str, draws: &[MeshDraw], uniforms: &FrameUniforms);
+
+    /// Window and device input since the last call
+    fn poll_events(&mut self) -> Vec<InputEvent>;
+
+    /// Toggles whether the cursor is grabbed and hidden
+    fn grab_mouse(&mut self);
+
+    /// Puts the cursor back at the center of the window while it's grabbed
+    fn reset_mouse_position(&mut self);
+}
+
 pub struct Context {
     pub events_loop: glium::glutin::EventsLoop,
     pub display: glium::Display,
-    pub shader_program: glium::Program,
+    programs: HashMap<String, glium::Program>,
+    passes: HashMap<String, RenderPass>,
     window_dimensions: (u32, u32),
     mouse_grab: bool,
-    render_params: glium::DrawParameters<'static>,
+    /// One arena for each vertex type, meshes of a type are drawn together
+    arenas: HashMap<TypeId, Box<dyn MeshArena>>,
+    /// Vertex type of each uploaded mesh
+    meshes: HashMap<MeshHandle, TypeId>,
+    next_mesh: u64,
+    /// Whether meshes can be drawn with a single multi-draw call, otherwise
+    /// they're drawn one by one
+    multi_draw: bool,
+    atlas: Option<TextureStorage>,
     pub frame: Option<glium::Frame>,
 }
 
+/// Indirect multi-draws and the base instance their commands pick offsets with
+/// are core since OpenGL 4.3, which macOS never got
+fn supports_multi_draw(display: &glium::Display) -> bool {
+    *display.get_opengl_version() >= glium::Version(glium::Api::Gl, 4, 3)
+}
+
+fn key(key: glutin::VirtualKeyCode) -> Option<Key> {
+    use glutin::VirtualKeyCode;
+
+    match key {
+        VirtualKeyCode::W => Some(Key::W),
+        VirtualKeyCode::A => Some(Key::A),
+        VirtualKeyCode::S => Some(Key::S),
+        VirtualKeyCode::D => Some(Key::D),
+        VirtualKeyCode::Space => Some(Key::Space),
+        VirtualKeyCode::LShift => Some(Key::LShift),
+        VirtualKeyCode::P => Some(Key::P),
+        VirtualKeyCode::Escape => Some(Key::Escape),
+        _ => None,
+    }
+}
+
+/// The game's input for a glutin event, `None` for the events it ignores
+fn input_event(event: &glutin::Event) -> Option<InputEvent> {
+    match event {
+        glutin::Event::DeviceEvent {
+            event: glutin::DeviceEvent::MouseMotion { delta },
+            ..
+        } => Some(InputEvent::MouseMotion(delta.0, delta.1)),
+        glutin::Event::WindowEvent { event, .. } => match event {
+            glutin::WindowEvent::CloseRequested => Some(InputEvent::CloseRequested),
+            glutin::WindowEvent::KeyboardInput { input, .. } => Some(InputEvent::Key {
+                key: key(input.virtual_keycode?)?,
+                pressed: input.state == glutin::ElementState::Pressed,
+            }),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 #[allow(dead_code)]
 impl Context {
-    pub fn new(title: &str, vert: &str, frag: &str) -> Self {
+    pub fn new(title: &str) -> Self {
         let window_dimensions = (DEFAULT_WIDTH, DEFAULT_HEIGHT);
 
         let events_loop = glutin::EventsLoop::new();
@@ -42,31 +163,15 @@ impl Context {
             .window()
             .set_position(glium::glutin::dpi::LogicalPosition::new(0., 0.));
 
-        let cargo_dir = env!("CARGO_MANIFEST_DIR");
-
-        // NORMAL SHADER
-        let vertex_shader_src =
-            fs::read_to_string(&Path::new(cargo_dir).join("shaders").join(vert))
-                .expect("Something went wrong reading the file");
-        let fragment_shader_src =
-            fs::read_to_string(&Path::new(cargo_dir).join("shaders").join(frag))
-                .expect("Something went wrong reading the file");
-        let shader_program =
-            glium::Program::from_source(&display, &vertex_shader_src, &fragment_shader_src, None)
-                .unwrap();
-
-        let render_params = glium::DrawParameters {
-            // polygon_mode: glium::draw_parameters::PolygonMode::Line,
-            // line_width: Some(10f32),
-            depth: glium::Depth {
-                test: glium::DepthTest::IfLess,
-                write: true,
-                ..Default::default()
-            },
-            backface_culling: glium::draw_parameters::BackfaceCullingMode::CullClockwise,
-            ..Default::default()
-        };
-
+        let programs = HashMap::new();
+        let passes = HashMap::new();
+        let arenas = HashMap::new();
+        let meshes = HashMap::new();
+        let multi_draw = supports_multi_draw(&display);
+        if !multi_draw {
+            println!("Multi-draw isn't supported, meshes are drawn one by one");
+        }
+        let atlas = None;
         let frame = None;
         let mouse_grab = true;
         display.gl_window().window().grab_cursor(mouse_grab).expect("Couldn't grab the cursor!");
@@ -78,8 +183,13 @@ impl Context {
             // gui,
             window_dimensions,
             mouse_grab,
-            render_params,
-            shader_program,
+            programs,
+            passes,
+            arenas,
+            meshes,
+            next_mesh: 0,
+            multi_draw,
+            atlas,
             frame,
         }
     }
@@ -112,9 +222,10 @@ impl Context {
         &self.display
     }
 
-    pub fn poll_events(&mut self) -> Vec<glutin::Event> {
+    /// Input since the last call, leaving out the events the game doesn't handle
+    pub fn poll_events(&mut self) -> Vec<InputEvent> {
         let mut events = Vec::new();
-        self.events_loop.poll_events(|e| events.push(e));
+        self.events_loop.poll_events(|e| events.extend(input_event(&e)));
         events
     }
 
@@ -139,21 +250,12 @@ impl Context {
             .clear_color_and_depth((color[0], color[1], color[2], color[3]), 1.0);
     }
 
-    pub fn draw<T: AsUniformValue, R: Uniforms>(
-        &mut self,
-        vb: &glium::VertexBuffer<Vertex>,
-        ib: &glium::IndexBuffer<u32>,
-        u: &glium::uniforms::UniformsStorage<T, R>,
-    ) {
-        self.frame
-            .as_mut()
-            .unwrap()
-            .draw(vb, ib, &self.shader_program, u, &self.render_params)
-            .unwrap();
+    pub fn get_program(&self, name: &str) -> Option<&glium::Program> {
+        self.programs.get(name)
     }
 
-    pub fn get_program(&self) -> &glium::Program {
-        &self.shader_program
+    pub fn get_pass(&self, name: &str) -> Option<&RenderPass> {
+        self.passes.get(name)
     }
 
     pub fn new_frame(&mut self) {
@@ -165,3 +267,115 @@ impl Context {
         self.frame.take().unwrap().finish().expect("Couldn't finish frame!");
     }
 }
+
+impl Renderer for Context {
+    fn begin_frame(&mut self, color: [f32; 4]) {
+        self.new_frame();
+        self.clear_color(color);
+    }
+
+    fn end_frame(&mut self) {
+        self.finish_frame();
+        for arena in self.arenas.values_mut() {
+            arena.maintain(&self.display);
+        }
+    }
+
+    fn aspect_ratio(&self) -> f64 {
+        self.get_aspect_ratio()
+    }
+
+    fn load_atlas(&mut self, path: &Path, tile_size: u32) {
+        self.atlas = Some(TextureStorage::new(&self.display, path, image::ImageFormat::Png, tile_size));
+    }
+
+    fn add_program(&mut self, name: &str, vertex: &str, fragment: &str) {
+        let shaders = Path::new(env!("CARGO_MANIFEST_DIR")).join("shaders");
+        let vertex_shader_src =
+            fs::read_to_string(shaders.join(vertex)).expect("Something went wrong reading the file");
+        let fragment_shader_src =
+            fs::read_to_string(shaders.join(fragment)).expect("Something went wrong reading the file");
+        let program =
+            glium::Program::from_source(&self.display, &vertex_shader_src, &fragment_shader_src, None)
+                .expect("Couldn't compile the shader program");
+
+        self.programs.insert(name.to_string(), program);
+    }
+
+    fn add_pass(&mut self, name: &str, pass: RenderPass) {
+        self.passes.insert(name.to_string(), pass);
+    }
+
+    fn upload_mesh<V: glium::Vertex + 'static>(&mut self, data: &MeshData<V>) -> Option<MeshHandle> {
+        if data.vertices.is_empty() || data.indices.is_empty() {
+            return None;
+        }
+
+        let mesh = MeshHandle::new(self.next_mesh);
+        self.next_mesh += 1;
+        let vertex_type = TypeId::of::<V>();
+        self.arenas
+            .entry(vertex_type)
+            .or_insert_with(|| Box::new(BufferArena::<MeshHandle, V>::new()))
+            .as_any_mut()
+            .downcast_mut::<BufferArena<MeshHandle, V>>()
+            .expect("Arena of another vertex type")
+            .insert(&self.display, mesh, data);
+        self.meshes.insert(mesh, vertex_type);
+
+        Some(mesh)
+    }
+
+    fn remove_mesh(&mut self, mesh: MeshHandle) {
+        if let Some(vertex_type) = self.meshes.remove(&mesh) {
+            if let Some(arena) = self.arenas.get_mut(&vertex_type) {
+                arena.remove(&mesh);
+            }
+        }
+    }
+
+    /// Meshes of each vertex type share their arena's buffers, so they're drawn
+    /// with a single call where multi-draw is supported
+    fn draw_meshes(&mut self, pass: &str, draws: &[MeshDraw], uniforms: &FrameUniforms) {
+        let pass = self.passes.get(pass).expect("Unknown render pass");
+        let program = self.programs.get(pass.program()).expect("Unknown shader program");
+
+        // grouped by arena, in the order each type is first drawn
+        let mut groups: Vec<(TypeId, Vec<MeshDraw>)> = Vec::new();
+        for draw in draws {
+            let vertex_type = match self.meshes.get(&draw.mesh) {
+                Some(vertex_type) => *vertex_type,
+                None => continue,
+            };
+            match groups.iter_mut().find(|(group, _)| *group == vertex_type) {
+                Some((_, group)) => group.push(*draw),
+                None => groups.push((vertex_type, vec![*draw])),
+            }
+        }
+
+        let state = ArenaDraw {
+            program,
+            parameters: pass.draw_parameters(),
+            uniforms,
+            texture: self.atlas.as_ref().map(|atlas| atlas.get_array()),
+            multi_draw: self.multi_draw,
+        };
+        let frame = self.frame.as_mut().unwrap();
+        for (vertex_type, draws) in &groups {
+            let arena = self.arenas.get_mut(vertex_type).expect("Mesh of a vertex type without arena");
+            arena.draw(frame, &self.display, draws, &state);
+        }
+    }
+
+    fn poll_events(&mut self) -> Vec<InputEvent> {
+        Context::poll_events(self)
+    }
+
+    fn grab_mouse(&mut self) {
+        Context::grab_mouse(self)
+    }
+
+    fn reset_mouse_position(&mut self) {
+        Context::reset_mouse_position(self)
+    }
+}
@@ -1,38 +1,211 @@
-use crate::game::terrain::chunk::CHUNKSIZE;
-use crate::utils::texture::TextureStorage;
-use crate::engine::renderer::{Context};
+use crate::game::terrain::chunk::{CHUNKSIZE, ChunkPosition};
+use crate::game::terrain::block::{RenderLayer, RENDER_LAYERS};
+use crate::engine::renderer::{Context, FrameUniforms, MeshDraw, Renderer};
+use crate::engine::input::{InputEvent, Key};
+use crate::engine::pass::{RenderPass, OPAQUE_TERRAIN, TERRAIN_PROGRAM, TRANSLUCENT_TERRAIN};
 use crate::utils::timer::*;
 use crate::utils::camera::Camera;
+use crate::utils::frustum::{CullingStats, Frustum};
 use crate::game::ecs::ECSManager;
 use crate::game::terrain::manager::TerrainManager;
+use crate::game::terrain::streaming::chunk_at;
+use crate::game::terrain::visibility::visible_chunks;
+use crate::game::terrain::generation::{DefaultGenerator, WorldGenerator};
 
 use crate::game::registry::{Registry, BlockDataBuilder};
 
-use cgmath::{Vector3, Zero};
+use cgmath::{MetricSpace, Point3, Vector3, Zero};
 use specs::prelude::*;
 use crate::game::ecs::components;
 use crate::game::ecs::systems::*;
 
+use std::cmp::Ordering;
 use std::path::Path;
 use std::time::Instant;
 use std::sync::Arc;
 
+/// Every block in the game, block and state ids follow the registration order so new blocks go last
+pub fn create_registry() -> Registry{
+    use crate::game::terrain::block::Direction;
+    use crate::game::terrain::state::Property;
+    use crate::game::terrain::model::{BlockModel, ModelBox};
+
+    let mut registry = Registry::new();
+    let air = BlockDataBuilder::default()
+        .all_faces([0, 1])
+        .build();
+    registry.block_registry_mut().add("air", air);
+
+    let missing = BlockDataBuilder::default()
+        .all_faces([0, 1])
+        .build();
+    registry.block_registry_mut().add("missing", missing);
+
+    let grass = BlockDataBuilder::default()
+        .all_faces([3, 15])
+        .face(Direction::Top, [0, 15])
+        .face(Direction::Bottom, [2, 15])
+        .build();
+    registry.block_registry_mut().add("grass", grass);
+
+    let dirt = BlockDataBuilder::default()
+        .all_faces([2, 15])
+        .build();
+    registry.block_registry_mut().add("dirt", dirt);
+
+    let stone = BlockDataBuilder::default()
+        .all_faces([1, 15])
+        .build();
+    registry.block_registry_mut().add("stone", stone);
+
+    let bedrock = BlockDataBuilder::default()
+        .all_faces([1, 14])
+        .breakable(false)
+        .build();
+    registry.block_registry_mut().add("bedrock", bedrock);
+
+    let sand = BlockDataBuilder::default()
+        .all_faces([4, 15])
+        .build();
+    registry.block_registry_mut().add("sand", sand);
+
+    let snow = BlockDataBuilder::default()
+        .all_faces([5, 15])
+        .build();
+    registry.block_registry_mut().add("snow", snow);
+
+    let gravel = BlockDataBuilder::default()
+        .all_faces([6, 15])
+        .build();
+    registry.block_registry_mut().add("gravel", gravel);
+
+    let coal_ore = BlockDataBuilder::default()
+        .all_faces([7, 15])
+        .build();
+    registry.block_registry_mut().add("coal_ore", coal_ore);
+
+    let iron_ore = BlockDataBuilder::default()
+        .all_faces([8, 15])
+        .build();
+    registry.block_registry_mut().add("iron_ore", iron_ore);
+
+    let gold_ore = BlockDataBuilder::default()
+        .all_faces([9, 15])
+        .build();
+    registry.block_registry_mut().add("gold_ore", gold_ore);
+
+    let log = BlockDataBuilder::default()
+        .all_faces([10, 15])
+        .face(Direction::Top, [11, 15])
+        .face(Direction::Bottom, [11, 15])
+        .property(Property::Axis)
+        .build();
+    registry.block_registry_mut().add("log", log);
+
+    let leaves = BlockDataBuilder::default()
+        .all_faces([12, 15])
+        .layer(RenderLayer::Cutout)
+        .build();
+    registry.block_registry_mut().add("leaves", leaves);
+
+    let glowstone = BlockDataBuilder::default()
+        .all_faces([13, 15])
+        .emission(15)
+        .build();
+    registry.block_registry_mut().add("glowstone", glowstone);
+
+    let water = BlockDataBuilder::default()
+        .all_faces([14, 15])
+        .layer(RenderLayer::Translucent)
+        .build();
+    registry.block_registry_mut().add("water", water);
+
+    let furnace = BlockDataBuilder::default()
+        .all_faces([2, 14])
+        .face(Direction::North, [3, 14])
+        .face(Direction::Top, [4, 14])
+        .face(Direction::Bottom, [4, 14])
+        .property(Property::Facing)
+        .build();
+    registry.block_registry_mut().add("furnace", furnace);
+
+    let tall_grass = BlockDataBuilder::default()
+        .all_faces([5, 14])
+        .layer(RenderLayer::Cutout)
+        .model(BlockModel::Cross)
+        .build();
+    registry.block_registry_mut().add("tall_grass", tall_grass);
+
+    let flower = BlockDataBuilder::default()
+        .all_faces([6, 14])
+        .layer(RenderLayer::Cutout)
+        .model(BlockModel::Cross)
+        .build();
+    registry.block_registry_mut().add("flower", flower);
+
+    let planks = BlockDataBuilder::default()
+        .all_faces([7, 14])
+        .build();
+    registry.block_registry_mut().add("planks", planks);
+
+    let planks_slab = BlockDataBuilder::default()
+        .all_faces([7, 14])
+        .model(BlockModel::Slab)
+        .build();
+    registry.block_registry_mut().add("planks_slab", planks_slab);
+
+    let planks_stairs = BlockDataBuilder::default()
+        .all_faces([7, 14])
+        .property(Property::Facing)
+        .model(BlockModel::Stairs)
+        .build();
+    registry.block_registry_mut().add("planks_stairs", planks_stairs);
+
+    let mut torch_faces = [[8, 14]; 6];
+    torch_faces[Direction::Top as usize] = [9, 14];
+    let torch = BlockDataBuilder::default()
+        .all_faces([8, 14])
+        .emission(14)
+        .model(BlockModel::Boxes(vec![ModelBox::new([0.4375, 0., 0.4375], [0.5625, 0.625, 0.5625]).with_faces(torch_faces)]))
+        .build();
+    registry.block_registry_mut().add("torch", torch);
+
+    registry
+}
+
+/// Pass a layer of chunk meshes is drawn with, cutout holes are discarded by the shader
+fn terrain_pass(layer: RenderLayer) -> &'static str{
+    match layer{
+        RenderLayer::Translucent => TRANSLUCENT_TERRAIN,
+        _ => OPAQUE_TERRAIN
+    }
+}
+
+/// The game and everything it runs on, drawn by a window's `Context` or by any
+/// other `Renderer`, like a `HeadlessRenderer` for tests
 #[allow(dead_code)]
-pub struct Game{
-    context: Context,
+pub struct Game<R: Renderer = Context>{
+    renderer: R,
     ecs_manager: ECSManager,
     registry: Arc<Registry>,
     terrain_manager: TerrainManager,
-    texture_storage: TextureStorage,
     player: Entity,
     camera: Camera,
+    culling: CullingStats,
     timer: UpdateTimer,
     running: bool
 }
 
-impl Game{
-    pub fn new(title: &str) -> Self{
-        let context = Context::new(title, "vertex.glsl", "fragment.glsl");
+impl<R: Renderer> Game<R>{
+    /// Starts a game saved to `world_path`, drawn by `renderer`
+    pub fn new(renderer: R, world_path: &Path) -> Self{
+        let registry = Arc::new(create_registry());
+        let generator = Arc::new(DefaultGenerator::new(10291302, &registry));
+        Self::with_generator(renderer, world_path, registry, generator)
+    }
+
+    /// Starts a game whose new chunks come from `generator`
+    pub fn with_generator(mut renderer: R, world_path: &Path, registry: Arc<Registry>, generator: Arc<dyn WorldGenerator>) -> Self{
         let timer = UpdateTimer::new(16);
         let running = true;
 
@@ -40,7 +213,10 @@ impl Game{
         let mut ecs_manager = ECSManager::new();
 
         let texture_path = Path::new("res").join("img").join("texture").join("atlas.png");
-        let texture_storage = TextureStorage::new(context.get_display(), &texture_path, image::ImageFormat::Png, 16);
+        renderer.load_atlas(&texture_path, 16);
+        renderer.add_program(TERRAIN_PROGRAM, "vertex.glsl", "fragment.glsl");
+        renderer.add_pass(OPAQUE_TERRAIN, RenderPass::opaque(TERRAIN_PROGRAM));
+        renderer.add_pass(TRANSLUCENT_TERRAIN, RenderPass::translucent(TERRAIN_PROGRAM));
 
         let player_pos = components::Position(camera.get_position());
         let player_vel = components::Velocity(cgmath::Vector3::zero());
@@ -49,9 +225,6 @@ impl Game{
         };
         let player_controller = components::Controller::new();
 
-        // let perspective = cgmath::perspective(cgmath::Rad::from(cgmath::Deg(40f64)), context.get_aspect_ratio(), 0.1f64, 1024f64);
-        // let frustum_culler = FrustumCuller::from_matrix(perspective);
-
         let world = ecs_manager.get_mut_world();
         let player = world
                         .create_entity()
@@ -61,52 +234,16 @@ impl Game{
                         .with(player_controller)
                         .build();
 
-        let mut registry = Registry::new();
-        {
-            use crate::game::terrain::block::Direction;
-            let air = BlockDataBuilder::default()
-                .all_faces([0, 1])
-                .build();
-            registry.block_registry_mut().add("air", air);
-
-            let missing = BlockDataBuilder::default()
-                .all_faces([0, 1])
-                .build();
-            registry.block_registry_mut().add("missing", missing);
-
-            let grass = BlockDataBuilder::default()
-                .all_faces([3, 15])
-                .face(Direction::Top, [0, 15])
-                .face(Direction::Bottom, [2, 15])
-                .build();
-            registry.block_registry_mut().add("grass", grass);
-
-            let dirt = BlockDataBuilder::default()
-                .all_faces([2, 15])
-                .build();
-            registry.block_registry_mut().add("dirt", dirt);
-
-            let stone = BlockDataBuilder::default()
-                .all_faces([1, 15])
-                .build();
-            registry.block_registry_mut().add("stone", stone);
-
-            let bedrock = BlockDataBuilder::default()
-                .all_faces([1, 14])
-                .breakable(false)
-                .build();
-            registry.block_registry_mut().add("bedrock", bedrock);
-        }
-        let registry = Arc::new(registry);
-        let terrain_manager = TerrainManager::new(&registry);
+        let terrain_manager = TerrainManager::new(&registry, world_path, generator);
+        let culling = CullingStats::default();
 
         Self{
-            context,
+            renderer,
             ecs_manager,
             terrain_manager,
-            texture_storage,
             player,
             camera,
+            culling,
             registry,
             timer,
             running
@@ -119,6 +256,16 @@ impl Game{
         while self.running{
             self.tick();
         }
+
+        self.save();
+    }
+
+    /// Writes the chunks changed since they were loaded to the world directory
+    pub fn save(&mut self){
+        match self.terrain_manager.save(){
+            Ok(saved) => println!("Saved {} chunks", saved),
+            Err(e) => println!("Couldn't save the world: {}", e),
+        }
     }
 
     pub fn tick(&mut self){
@@ -142,7 +289,7 @@ impl Game{
             *dt = DeltaTime(to_secs(self.timer.max_ups) as f64 / 1e3);
         }
 
-        self.terrain_manager.setup(self.context.get_display());
+        self.terrain_manager.update_streaming(self.camera.get_position());
     }
 
 
@@ -161,94 +308,50 @@ impl Game{
         let position = position_storage.get(self.player).expect("Failed to get Player Position");
         self.camera.set_positon(position.0);
         self.camera.update();
+
+        self.terrain_manager.update_streaming(position.0);
     }
 
     pub fn handle_input(&mut self){
-        let events = self.context.poll_events();
+        let events = self.renderer.poll_events();
         for event in &events{
             match event{
-                glium::glutin::Event::DeviceEvent{ event, ..} => match event{
-                    glium::glutin::DeviceEvent::MouseMotion{ delta } => {
-                        self.camera.handle_mouse(delta.0, delta.1);
-                        self.context.reset_mouse_position();
-                    },
-                    _ => (),
+                InputEvent::CloseRequested => self.running = false,
+                InputEvent::MouseMotion(dx, dy) => {
+                    self.camera.handle_mouse(*dx, *dy);
+                    self.renderer.reset_mouse_position();
+                },
+                InputEvent::Key{ key: Key::P, pressed } => {
+                    if *pressed{
+                        self.renderer.grab_mouse();
+                    }
+                },
+                InputEvent::Key{ key: Key::Escape, .. } => self.running = false,
+                InputEvent::Key{ key, pressed } => {
+                    let world = self.ecs_manager.get_mut_world();
+                    let mut controller_storage = world.write_storage::<components::Controller>();
+                    let controller = controller_storage.get_mut(self.player).expect("Failed to get Player Controller");
+                    match key{
+                        Key::W => controller.forward = *pressed,
+                        Key::S => controller.backward = *pressed,
+                        Key::A => controller.left = *pressed,
+                        Key::D => controller.right = *pressed,
+                        Key::Space => controller.up = *pressed,
+                        Key::LShift => controller.down = *pressed,
+                        _ => (),
+                    }
                 },
-                glium::glutin::Event::WindowEvent { event, .. } => match event{
-                    glium::glutin::WindowEvent::CloseRequested => self.running = false,
-                    glium::glutin::WindowEvent::KeyboardInput{input, ..} => {
-                        let pressed = match input.state{
-                            glium::glutin::ElementState::Pressed => true,
-                            _ => false,
-                        };
-                        match input.virtual_keycode{
-                            Some(key) => {
-                                match key{
-                                    glium::glutin::VirtualKeyCode::P => {
-                                        if pressed{
-                                            self.context.grab_mouse();
-                                            // self.context.reset_mouse_position();
-                                        }
-                                    },
-                                    glium::glutin::VirtualKeyCode::Escape => {
-                                        self.running = false;
-                                    },
-                                    glium::glutin::VirtualKeyCode::W => {
-                                        let world = self.ecs_manager.get_mut_world();
-                                        let mut controller_storage = world.write_storage::<components::Controller>();
-                                        let mut controller = controller_storage.get_mut(self.player).expect("Failed to get Player Controller");
-                                        controller.forward = pressed;
-                                    },
-                                    glium::glutin::VirtualKeyCode::S => {
-                                        let world = self.ecs_manager.get_mut_world();
-                                        let mut controller_storage = world.write_storage::<components::Controller>();
-                                        let mut controller = controller_storage.get_mut(self.player).expect("Failed to get Player Controller");
-                                        controller.backward = pressed;
-                                    },
-                                    glium::glutin::VirtualKeyCode::A => {
-                                        let world = self.ecs_manager.get_mut_world();
-                                        let mut controller_storage = world.write_storage::<components::Controller>();
-                                        let mut controller = controller_storage.get_mut(self.player).expect("Failed to get Player Controller");
-                                        controller.left = pressed;
-                                    },
-                                    glium::glutin::VirtualKeyCode::D => {
-                                        let world = self.ecs_manager.get_mut_world();
-                                        let mut controller_storage = world.write_storage::<components::Controller>();
-                                        let mut controller = controller_storage.get_mut(self.player).expect("Failed to get Player Controller");
-                                        controller.right = pressed;
-                                    },
-                                    glium::glutin::VirtualKeyCode::Space => {
-                                        let world = self.ecs_manager.get_mut_world();
-                                        let mut controller_storage = world.write_storage::<components::Controller>();
-                                        let mut controller = controller_storage.get_mut(self.player).expect("Failed to get Player Controller");
-                                        controller.up = pressed;
-                                    },
-                                    glium::glutin::VirtualKeyCode::LShift => {
-                                        let world = self.ecs_manager.get_mut_world();
-                                        let mut controller_storage = world.write_storage::<components::Controller>();
-                                        let mut controller = controller_storage.get_mut(self.player).expect("Failed to get Player Controller");
-                                        controller.down = pressed;
-                                    },
-                                    _ => (),
-                                }
-                            },
-                            None => (),
-                        }
-                    },
-                    _ => ()
-                }
-                _ => (),
             }
         }
     }
 
 
     pub fn render(&mut self, _timer: Instant){
-        self.context.new_frame();
-        self.context.clear_color([0.3, 0.45, 0.65, 1.0]);
+        self.renderer.begin_frame([0.3, 0.45, 0.65, 1.0]);
 
-        let texture = self.texture_storage.get_array().sampled().magnify_filter(glium::uniforms::MagnifySamplerFilter::Nearest).wrap_function(glium::uniforms::SamplerWrapFunction::Repeat);
-        let perspective: [[f32; 4]; 4] = cgmath::perspective(cgmath::Rad::from(cgmath::Deg(90f64)), self.context.get_aspect_ratio(), 0.1f64, 1024f64)
+        let projection = cgmath::perspective(cgmath::Rad::from(cgmath::Deg(90f64)), self.renderer.aspect_ratio(), 0.1f64, 1024f64);
+        let frustum = Frustum::from_matrix(projection * self.camera.get_view());
+        let perspective: [[f32; 4]; 4] = projection
             .cast::<f32>() // Casts internal f64 to f32, since 'double' support in video grahics card is fairly recent...
             .expect("Couldn't cast Perspective f64 to f32")
             .into();
@@ -257,19 +360,235 @@ impl Game{
             .expect("Couldn't cast View f64 to f32")
             .into();
 
-        self.terrain_manager.update_meshes(self.context.get_display());
-        for mesh_ref in self.terrain_manager.get_meshes().iter(){
-            let (position, mesh) = mesh_ref.pair();
-            let model: [[f32; 4]; 4] = cgmath::Matrix4::from_translation(Vector3::new(position.x as f32, position.y as f32, position.z as f32) * CHUNKSIZE as f32).into();
-            let uniforms = uniform!{
-                m: model,
-                v: view,
-                p: perspective,
-                t: texture
-            };
-
-            self.context.draw(mesh.get_vb(), mesh.get_ib(), &uniforms);
+        self.terrain_manager.update_meshes(&mut self.renderer);
+
+        let visible = |position: &ChunkPosition|{
+            let min = Point3::new(position.x as f64, position.y as f64, position.z as f64) * CHUNKSIZE as f64;
+            frustum.intersects_aabb(min, min + Vector3::new(1., 1., 1.) * CHUNKSIZE as f64)
+        };
+
+        // chunks hidden behind cave walls and solid ground aren't reachable from the camera's
+        let terrain = &self.terrain_manager;
+        let reachable = visible_chunks(chunk_at(self.camera.get_position()), |position| terrain.connectivity(position), visible);
+
+        let mut culling = CullingStats::default();
+        let mut drawn = Vec::new();
+        for position in terrain.meshed_chunks(){
+            if !visible(position){
+                culling.culled += 1;
+                continue;
+            }
+            if !reachable.contains(position){
+                culling.occluded += 1;
+                continue;
+            }
+            culling.drawn += 1;
+            drawn.push(*position);
+        }
+
+        // nearest first, so the depth test rejects what's hidden before it's shaded
+        let camera = self.camera.get_position();
+        let distance = |position: &ChunkPosition|{
+            let center = (position.cast::<f64>().expect("Couldn't cast chunk position") + Vector3::new(0.5, 0.5, 0.5)) * CHUNKSIZE as f64;
+            center.distance2(camera)
+        };
+        drawn.sort_by(|a, b| distance(a).partial_cmp(&distance(b)).unwrap_or(Ordering::Equal));
+
+        let uniforms = FrameUniforms{
+            view,
+            projection: perspective
+        };
+        // translucent is the last layer, drawn over everything else
+        for layer in &RENDER_LAYERS{
+            let mut draws: Vec<MeshDraw> = drawn.iter()
+                .filter_map(|position| Some(MeshDraw{
+                    mesh: terrain.chunk_mesh(position, *layer)?,
+                    offset: (position.cast::<f32>().expect("Couldn't cast chunk position") * CHUNKSIZE as f32).into()
+                }))
+                .collect();
+            // farthest first for blending, the nearer chunks go over the farther ones
+            if *layer == RenderLayer::Translucent{
+                draws.reverse();
+            }
+            self.renderer.draw_meshes(terrain_pass(*layer), &draws, &uniforms);
+        }
+        self.renderer.end_frame();
+        self.culling = culling;
+    }
+
+    /// Chunks drawn and culled by the view frustum in the last frame
+    #[allow(dead_code)]
+    pub fn culling_stats(&self) -> CullingStats{
+        self.culling
+    }
+
+    #[allow(dead_code)]
+    pub fn renderer(&self) -> &R{
+        &self.renderer
+    }
+
+    #[allow(dead_code)]
+    pub fn renderer_mut(&mut self) -> &mut R{
+        &mut self.renderer
+    }
+
+    #[allow(dead_code)]
+    pub fn terrain(&self) -> &TerrainManager{
+        &self.terrain_manager
+    }
+
+    #[allow(dead_code)]
+    pub fn terrain_mut(&mut self) -> &mut TerrainManager{
+        &mut self.terrain_manager
+    }
+
+    #[allow(dead_code)]
+    pub fn camera(&self) -> &Camera{
+        &self.camera
+    }
+
+    #[allow(dead_code)]
+    pub fn is_running(&self) -> bool{
+        self.running
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::engine::headless::HeadlessRenderer;
+    use crate::engine::renderer::MeshHandle;
+    use crate::game::terrain::chunk::Chunk;
+    use crate::game::terrain::generation::GeneratedChunk;
+    use crate::game::terrain::jobs::Inline;
+    use crate::game::terrain::vertex::ChunkVertex;
+
+    /// Stone under a pond with flowers floating on it, so every layer has meshes
+    struct Flat{
+        stone: usize,
+        water: usize,
+        flower: usize
+    }
+
+    impl WorldGenerator for Flat{
+        fn generate(&self, position: ChunkPosition) -> GeneratedChunk{
+            let mut chunk = Chunk::new(0);
+            if position.y == -1{
+                for x in 0..CHUNKSIZE{
+                    for z in 0..CHUNKSIZE{
+                        for y in 0..28{
+                            chunk.set_block(x, y, z, self.stone);
+                        }
+                        chunk.set_block(x, 28, z, self.water);
+                        if (x * 3 + z) % 5 == 0{
+                            chunk.set_block(x, 29, z, self.flower);
+                        }
+                    }
+                }
+            }else if position.y < -1{
+                chunk = Chunk::new(self.stone);
+            }
+            GeneratedChunk{
+                chunk,
+                pending: Vec::new()
+            }
+        }
+    }
+
+    /// A game of a flat world whose chunk jobs run inline
+    fn flat_game(world_path: &Path) -> Game<HeadlessRenderer>{
+        let registry = Arc::new(create_registry());
+        let state = |name: &str| registry.block_registry().default_state(registry.block_registry().id_of(name).unwrap());
+        let generator = Arc::new(Flat{
+            stone: state("stone"),
+            water: state("water"),
+            flower: state("flower")
+        });
+        let mut game = Game::with_generator(HeadlessRenderer::new(4. / 3.), world_path, registry.clone(), generator);
+        game.terrain_mut().set_executor(Box::new(Inline));
+        game.setup();
+        game
+    }
+
+    /// Updates and renders until every chunk around the player is loaded and meshed
+    fn play_until_meshed(game: &mut Game<HeadlessRenderer>){
+        let done = (0..1000).any(|_| {
+            game.update();
+            game.render(Instant::now());
+            let stats = game.terrain().streaming_stats();
+            stats.pending == 0 && stats.meshing == 0
+        });
+        assert!(done, "chunks still loading or meshing");
+    }
+
+    #[test]
+    fn terrain_is_uploaded_and_drawn(){
+        let dir = tempfile::tempdir().unwrap();
+        let mut game = flat_game(dir.path());
+        play_until_meshed(&mut game);
+        let renderer = game.renderer();
+        let terrain = game.terrain();
+        assert!(renderer.drawn_meshes() >= 20, "{} meshes drawn", renderer.drawn_meshes());
+        assert_eq!(renderer.clear_color(), Some([0.3, 0.45, 0.65, 1.0]));
+        assert_eq!(renderer.program(TERRAIN_PROGRAM), Some(("vertex.glsl", "fragment.glsl")));
+
+        // replaced and unloaded meshes are removed, only the chunks' current ones are left
+        let meshes: Vec<MeshHandle> = terrain.meshed_chunks()
+            .flat_map(|position| RENDER_LAYERS.iter().filter_map(move |layer| terrain.chunk_mesh(position, *layer)))
+            .collect();
+        assert_eq!(renderer.meshes().len(), meshes.len());
+        assert!(renderer.upload_count() >= meshes.len());
+        assert!(meshes.iter().all(|mesh| renderer.mesh_data::<ChunkVertex>(*mesh).is_some()));
+
+        // one draw per layer, the translucent one last
+        let frame = renderer.last_frame();
+        let passes: Vec<&str> = frame.iter().map(|draw| draw.pass.as_str()).collect();
+        assert_eq!(passes, vec![OPAQUE_TERRAIN, OPAQUE_TERRAIN, TRANSLUCENT_TERRAIN]);
+        assert!(frame.iter().all(|draw| draw.uniforms == frame[0].uniforms));
+
+        let camera = game.camera().get_position();
+        for (draw, layer) in frame.iter().zip(RENDER_LAYERS.iter()){
+            let distances: Vec<f64> = draw.meshes.iter()
+                .map(|mesh| {
+                    let position = terrain.meshed_chunks()
+                        .find(|position| terrain.chunk_mesh(position, *layer) == Some(*mesh))
+                        .expect("Drew a mesh that isn't one of the layer's");
+                    let center = (position.cast::<f64>().unwrap() + Vector3::new(0.5, 0.5, 0.5)) * CHUNKSIZE as f64;
+                    center.distance2(camera)
+                })
+                .collect();
+            assert!(distances.len() > 1, "{:?} layer", layer);
+            // nearest first, but farthest first when blending
+            match layer{
+                RenderLayer::Translucent => assert!(distances.windows(2).all(|pair| pair[0] >= pair[1])),
+                _ => assert!(distances.windows(2).all(|pair| pair[0] <= pair[1]))
+            }
         }
-        self.context.finish_frame();
+        assert!(frame.iter().all(|draw| draw.meshes.len() <= game.culling_stats().drawn));
+    }
+
+    #[test]
+    fn input_moves_and_stops_the_game(){
+        let dir = tempfile::tempdir().unwrap();
+        let mut game = flat_game(dir.path());
+        let start = game.camera().get_position();
+
+        game.renderer_mut().push_event(InputEvent::Key{ key: Key::W, pressed: true });
+        game.handle_input();
+        game.update();
+        let moved = game.camera().get_position();
+        assert!(moved.z > start.z, "{:?} to {:?}", start, moved);
+
+        game.renderer_mut().push_event(InputEvent::Key{ key: Key::W, pressed: false });
+        game.handle_input();
+        game.update();
+        let stopped = game.camera().get_position();
+        game.update();
+        assert_eq!(game.camera().get_position(), stopped);
+
+        assert!(game.is_running());
+        game.renderer_mut().push_event(InputEvent::CloseRequested);
+        game.handle_input();
+        assert!(!game.is_running());
     }
 }
@@ -1,5 +1,7 @@
 use dashmap::DashMap;
-use crate::game::terrain::block::{Direction, BlockData};
+use crate::game::terrain::block::{Direction, BlockData, RenderLayer, Rotation};
+use crate::game::terrain::state::{BlockState, Property};
+use crate::game::terrain::model::BlockModel;
 
 // pub struct ItemData{
 //     name: String,
@@ -8,16 +10,26 @@ use crate::game::terrain::block::{Direction, BlockData};
 
 pub struct BlockDataBuilder{
     faces: Option<[[u32; 2]; 6]>,
+    rotations: Option<[Rotation; 6]>,
     breakable: Option<bool>,
-    transparent: Option<bool>
+    transparent: Option<bool>,
+    layer: Option<RenderLayer>,
+    emission: Option<u8>,
+    properties: Option<Vec<Property>>,
+    model: Option<BlockModel>
 }
 
 impl Default for BlockDataBuilder{
     fn default() -> Self{
         Self{
             faces: Some([[0, 0]; 6]),
+            rotations: Some([Rotation::R0; 6]),
             breakable: Some(true),
-            transparent: Some(false)
+            transparent: Some(false),
+            layer: Some(RenderLayer::Opaque),
+            emission: Some(0),
+            properties: Some(Vec::new()),
+            model: Some(BlockModel::Cube)
         }
     }
 }
@@ -44,6 +56,15 @@ impl BlockDataBuilder{
         self
     }
 
+    /// Rotates the texture of a single face
+    #[allow(dead_code)]
+    pub fn rotation(mut self, dir: Direction, rotation: Rotation) -> Self{
+        let mut rotations = self.rotations.unwrap_or([Rotation::R0; 6]);
+        rotations[dir as usize] = rotation;
+        self.rotations = Some(rotations);
+        self
+    }
+
     pub fn breakable(mut self, breakable: bool) -> Self{
         self.breakable = Some(breakable);
         self
@@ -55,30 +76,83 @@ impl BlockDataBuilder{
         self
     }
 
+    /// Sets the pass the block is drawn in, blocks outside the opaque layer are transparent
+    pub fn layer(mut self, layer: RenderLayer) -> Self{
+        self.layer = Some(layer);
+        self.transparent = Some(layer != RenderLayer::Opaque);
+        self
+    }
+
+    pub fn emission(mut self, emission: u8) -> Self{
+        self.emission = Some(emission.min(15));
+        self
+    }
+
+    /// Declares a property, giving the block a state for each of its values
+    pub fn property(mut self, property: Property) -> Self{
+        let mut properties = self.properties.unwrap_or_default();
+        if !properties.contains(&property){
+            properties.push(property);
+        }
+        self.properties = Some(properties);
+        self
+    }
+
+    pub fn model(mut self, model: BlockModel) -> Self{
+        self.model = Some(model);
+        self
+    }
+
+    /// Builds the block, light goes through blocks that aren't full cubes
     pub fn build(self) -> BlockData{
-        BlockData::new(self.faces.expect("Missing faces"), self.breakable.expect("Missing breakable"), self.transparent.expect("Missing transparent"))
+        let model = self.model.expect("Missing model");
+        let transparent = self.transparent.expect("Missing transparent") || !model.is_cube();
+        BlockData::new(self.faces.expect("Missing faces"), self.rotations.expect("Missing rotations"), self.breakable.expect("Missing breakable"), transparent, self.layer.expect("Missing layer"), self.emission.expect("Missing emission"), self.properties.expect("Missing properties"), model)
     }
 }
 
+/// Blocks by name and id, and the states of every block.
+///
+/// Each block gets a contiguous range of state ids, in registration order, and
+/// chunks store state ids: a block without properties has a single state.
 pub struct BlockRegistry{
     ids: DashMap<String, usize>,
     blocks: Vec<BlockData>,
+    /// First state id of each block
+    first_states: Vec<usize>,
+    states: Vec<BlockState>,
 }
 
 impl BlockRegistry{
     pub fn new() -> Self{
         let ids = DashMap::default();
         let blocks = Vec::new();
+        let first_states = Vec::new();
+        let states = Vec::new();
 
         Self{
             ids,
-            blocks
+            blocks,
+            first_states,
+            states
         }
     }
 
     pub fn add(&mut self, name: &str, data: BlockData){
+        let id = self.blocks.len();
+        self.first_states.push(self.states.len());
+        for index in 0..data.state_count(){
+            // the first property varies the fastest
+            let mut rest = index;
+            let values = data.properties().iter().map(|property| {
+                let value = rest % property.values();
+                rest /= property.values();
+                value
+            }).collect();
+            self.states.push(BlockState::new(id, &data, values));
+        }
+
         self.blocks.push(data);
-        let id = self.blocks.len() - 1;
         self.ids.insert(String::from(name), id);
     }
 
@@ -102,6 +176,65 @@ impl BlockRegistry{
 
         return None;
     }
+
+    /// Block data of the block a state belongs to
+    pub fn by_state(&self, state: usize) -> Option<&BlockData>{
+        self.state(state).and_then(|state| self.by_id(state.block()))
+    }
+
+    pub fn state(&self, state: usize) -> Option<&BlockState>{
+        self.states.get(state)
+    }
+
+    /// Block a state belongs to, `missing` for unknown states
+    pub fn block_of(&self, state: usize) -> usize{
+        self.state(state).map(|state| state.block()).unwrap_or(1)
+    }
+
+    /// State of a block with every property at its default value
+    pub fn default_state(&self, block: usize) -> usize{
+        self.state_of(block, &[]).unwrap_or(1)
+    }
+
+    /// State of a block with the given property values, the others keeping their
+    /// default. `None` if the block doesn't have one of the properties or a value
+    /// is out of range.
+    pub fn state_of(&self, block: usize, values: &[(Property, usize)]) -> Option<usize>{
+        let data = self.by_id(block)?;
+        if values.iter().any(|(property, value)| !data.properties().contains(property) || *value >= property.values()){
+            return None;
+        }
+
+        let mut stride = 1;
+        let mut state = self.first_states[block];
+        for property in data.properties(){
+            let value = values.iter().rev()
+                .find(|(p, _)| p == property)
+                .map(|(_, value)| *value)
+                .unwrap_or_else(|| property.default_value());
+            state += value * stride;
+            stride *= property.values();
+        }
+        Some(state)
+    }
+
+    /// Value of one of a state's properties
+    #[allow(dead_code)]
+    pub fn property(&self, state: usize, property: Property) -> Option<usize>{
+        let state = self.state(state)?;
+        let index = self.by_id(state.block())?.properties().iter().position(|p| *p == property)?;
+        Some(state.values()[index])
+    }
+
+    /// The same block in the state with one property changed
+    #[allow(dead_code)]
+    pub fn with_property(&self, state: usize, property: Property, value: usize) -> Option<usize>{
+        let block_state = self.state(state)?;
+        let data = self.by_id(block_state.block())?;
+        let mut values: Vec<(Property, usize)> = data.properties().iter().copied().zip(block_state.values().iter().copied()).collect();
+        values.push((property, value));
+        self.state_of(block_state.block(), &values)
+    }
 }
 
 pub struct Registry{
@@ -1,4 +1,7 @@
+use super::model::BlockModel;
+use super::state::Property;
 use num_enum::TryFromPrimitive;
+use std::convert::TryFrom;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
 #[repr(usize)]
@@ -11,23 +14,160 @@ pub enum Direction{
     South = 5
 }
 
+impl Direction{
+    /// Direction a face points to, from the axis it's perpendicular to and
+    /// whether it faces the positive side of that axis
+    pub fn from_axis(axis: usize, positive: bool) -> Self{
+        let direction = axis * 2 + if positive {0} else {1};
+        Self::try_from(direction).expect("Unknown axis")
+    }
+
+    /// Unit vector pointing out of the face
+    pub fn normal(&self) -> [isize; 3]{
+        let axis = *self as usize / 2;
+        let mut normal = [0; 3];
+        normal[axis] = match self{
+            Direction::East | Direction::Top | Direction::North => 1,
+            _ => -1
+        };
+        normal
+    }
+
+    pub fn opposite(&self) -> Self{
+        let normal = self.normal();
+        Self::from_normal([-normal[0], -normal[1], -normal[2]])
+    }
+
+    /// Direction of an axis-aligned unit vector
+    pub fn from_normal(normal: [isize; 3]) -> Self{
+        let axis = normal.iter().position(|c| *c != 0).expect("Zero normal");
+        Self::from_axis(axis, normal[axis] > 0)
+    }
+
+    /// Texture axes of a face seen from outside of the block: `u` to the right
+    /// and `v` upwards, as world unit vectors.
+    ///
+    /// Side faces have `v` pointing up, top and bottom faces have it pointing north.
+    pub fn texture_axes(&self) -> ([f32; 3], [f32; 3]){
+        match self{
+            Direction::East => ([0., 0., -1.], [0., 1., 0.]),
+            Direction::West => ([0., 0., 1.], [0., 1., 0.]),
+            Direction::Top => ([-1., 0., 0.], [0., 0., 1.]),
+            Direction::Bottom => ([1., 0., 0.], [0., 0., 1.]),
+            Direction::North => ([1., 0., 0.], [0., 1., 0.]),
+            Direction::South => ([-1., 0., 0.], [0., 1., 0.]),
+        }
+    }
+}
+
+/// Clockwise rotation of a face's texture
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation{
+    R0,
+    R90,
+    R180,
+    R270
+}
+
+impl Rotation{
+    /// Rotation of a number of clockwise quarter turns
+    pub fn from_quarters(quarters: usize) -> Self{
+        match quarters % 4{
+            0 => Rotation::R0,
+            1 => Rotation::R90,
+            2 => Rotation::R180,
+            _ => Rotation::R270,
+        }
+    }
+
+    pub fn quarters(&self) -> usize{
+        *self as usize
+    }
+
+    /// This rotation followed by another one
+    pub fn then(&self, other: Rotation) -> Self{
+        Self::from_quarters(self.quarters() + other.quarters())
+    }
+}
+
+/// Pass a block's faces are drawn in
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RenderLayer{
+    Opaque = 0,
+    /// Fully opaque or fully transparent texels, like leaves
+    Cutout = 1,
+    /// Blended with what's behind, drawn last and back-to-front, like water
+    Translucent = 2
+}
+
+pub const RENDER_LAYERS: [RenderLayer; 3] = [RenderLayer::Opaque, RenderLayer::Cutout, RenderLayer::Translucent];
+
+/// Faces and their rotations are given for the block's default orientation,
+/// `state::BlockState` turns them for the other states
 #[allow(dead_code)]
 pub struct BlockData{
     faces: [[u32; 2]; 6],
+    rotations: [Rotation; 6],
     breakable: bool,
-    transparent: bool
+    transparent: bool,
+    layer: RenderLayer,
+    emission: u8,
+    properties: Vec<Property>,
+    model: BlockModel
 }
 
 impl BlockData{
-    pub fn new(faces: [[u32; 2]; 6], breakable: bool, transparent: bool) -> Self{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(faces: [[u32; 2]; 6], rotations: [Rotation; 6], breakable: bool, transparent: bool, layer: RenderLayer, emission: u8, properties: Vec<Property>, model: BlockModel) -> Self{
         Self{
             faces,
+            rotations,
             breakable,
-            transparent
+            transparent,
+            layer,
+            emission,
+            properties,
+            model
         }
     }
 
     pub fn get_face(&self, dir: Direction) -> [u32; 2]{
         self.faces[dir as usize]
     }
+
+    pub fn get_rotation(&self, dir: Direction) -> Rotation{
+        self.rotations[dir as usize]
+    }
+
+    pub fn is_breakable(&self) -> bool{
+        self.breakable
+    }
+
+    pub fn is_transparent(&self) -> bool{
+        self.transparent
+    }
+
+    pub fn layer(&self) -> RenderLayer{
+        self.layer
+    }
+
+    /// Block light level emitted, from 0 to 15
+    pub fn emission(&self) -> u8{
+        self.emission
+    }
+
+    /// Properties each state of the block has a value for, in declaration order
+    pub fn properties(&self) -> &[Property]{
+        &self.properties
+    }
+
+    pub fn model(&self) -> &BlockModel{
+        &self.model
+    }
+
+    /// Amount of states the block has, one per combination of property values
+    pub fn state_count(&self) -> usize{
+        self.properties.iter().map(|property| property.values()).product()
+    }
 }
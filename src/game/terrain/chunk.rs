@@ -1,72 +1,285 @@
+use super::palette::PalettedStorage;
 use cgmath::Point3;
 use std::sync::Arc;
+use std::mem;
 
 pub type ChunkPosition = Point3<isize>;
+pub type BlockPosition = Point3<isize>;
 
 pub const CHUNKSIZE: usize = 32;
+pub const CHUNK_VOLUME: usize = CHUNKSIZE * CHUNKSIZE * CHUNKSIZE;
+
+/// Offsets of the 26 chunks around a chunk, in the order neighbour lists are passed
+/// to `Chunk::check_block`: the six sharing a face in `Direction` order, then the
+/// twelve sharing an edge and the eight sharing a corner
+pub const NEIGHBOR_OFFSETS: [[isize; 3]; 26] = [
+    [1, 0, 0], [-1, 0, 0], [0, 1, 0], [0, -1, 0], [0, 0, 1], [0, 0, -1],
+    [1, 1, 0], [1, -1, 0], [-1, 1, 0], [-1, -1, 0],
+    [1, 0, 1], [1, 0, -1], [-1, 0, 1], [-1, 0, -1],
+    [0, 1, 1], [0, 1, -1], [0, -1, 1], [0, -1, -1],
+    [1, 1, 1], [1, 1, -1], [1, -1, 1], [1, -1, -1],
+    [-1, 1, 1], [-1, 1, -1], [-1, -1, 1], [-1, -1, -1]
+];
+
+/// Splits a world block position into its chunk and the block's coordinates inside it
+pub fn world_to_local(position: BlockPosition) -> (ChunkPosition, [usize; 3]){
+    let size = CHUNKSIZE as isize;
+    let chunk = ChunkPosition::new(position.x.div_euclid(size), position.y.div_euclid(size), position.z.div_euclid(size));
+    let local = [position.x.rem_euclid(size) as usize, position.y.rem_euclid(size) as usize, position.z.rem_euclid(size) as usize];
+    (chunk, local)
+}
+
+/// Block state ids, see `BlockRegistry`
+#[derive(Clone)]
+enum BlockStorage{
+    /// Every block in the chunk is the same (e.g. pure air or pure stone)
+    Uniform(usize),
+    Paletted(PalettedStorage),
+    Full(Vec<usize>)
+}
+
+/// Light levels, sky light in the high nibble and block light in the low one
+#[derive(Clone)]
+enum LightStorage{
+    Uniform(u8),
+    Full(Vec<u8>)
+}
+
 // #[derive(Debug)]
+#[derive(Clone)]
 pub struct Chunk{
-    blocks: [[[usize; CHUNKSIZE]; CHUNKSIZE]; CHUNKSIZE],
+    blocks: BlockStorage,
+    light: LightStorage,
+    /// Neighbours whose spilled feature blocks were written into this chunk, see `spill::source_bit`
+    received_spills: u32,
 }
 
 impl Chunk{
     pub fn new(filler: usize) -> Self{
         Self{
-            blocks: [[[filler; CHUNKSIZE]; CHUNKSIZE]; CHUNKSIZE],
+            blocks: BlockStorage::Uniform(filler),
+            light: LightStorage::Uniform(0),
+            received_spills: 0,
         }
     }
 
+    fn index(x: usize, y: usize, z: usize) -> usize{
+        if x >= CHUNKSIZE { panic!("Error: {:?}", [x, y, z])}
+        if y >= CHUNKSIZE { panic!("Error: {:?}", [x, y, z])}
+        if z >= CHUNKSIZE { panic!("Error: {:?}", [x, y, z])}
+        (x * CHUNKSIZE + y) * CHUNKSIZE + z
+    }
+
     pub fn set_block(&mut self, x: usize, y: usize, z: usize, block: usize){
-        self.blocks[x][y][z] = block;
+        let i = Self::index(x, y, z);
+        match &mut self.blocks{
+            BlockStorage::Uniform(value) => {
+                if *value != block{
+                    let mut storage = PalettedStorage::new(CHUNK_VOLUME, *value);
+                    storage.set(i, block);
+                    self.blocks = BlockStorage::Paletted(storage);
+                }
+            },
+            BlockStorage::Paletted(storage) => {
+                if !storage.set(i, block){
+                    let mut values = storage.to_values();
+                    values[i] = block;
+                    self.blocks = BlockStorage::Full(values);
+                }
+            },
+            BlockStorage::Full(values) => values[i] = block,
+        }
     }
 
     pub fn get_block(&self, x: usize, y: usize, z: usize) -> usize{
-        if x >= CHUNKSIZE { panic!("Error: {:?}", [x, y, z])}
-        if y >= CHUNKSIZE { panic!("Error: {:?}", [x, y, z])}
-        if z >= CHUNKSIZE { panic!("Error: {:?}", [x, y, z])}
-        self.blocks[x][y][z]
+        let i = Self::index(x, y, z);
+        match &self.blocks{
+            BlockStorage::Uniform(value) => *value,
+            BlockStorage::Paletted(storage) => storage.get(i),
+            BlockStorage::Full(values) => values[i],
+        }
     }
 
-    pub fn check_block(&self, x: isize, y: isize, z: isize, neighbors: Vec<Option<Arc<Chunk>>>) -> usize{
-        if x >= CHUNKSIZE as isize {
-            // println!("Block: {:?}", (x, y, z));
-            match &neighbors[0]{
-                Some(chunk) => return chunk.get_block(0, y as usize, z as usize),
-                None => return 0,
-            }
+    /// Packed light of a block, see `light::sky_light` and `light::block_light`
+    pub fn get_light(&self, x: usize, y: usize, z: usize) -> u8{
+        let i = Self::index(x, y, z);
+        match &self.light{
+            LightStorage::Uniform(value) => *value,
+            LightStorage::Full(values) => values[i],
         }
-        if x < 0{
-            match &neighbors[1]{
-                Some(chunk) => return chunk.get_block(CHUNKSIZE-1, y as usize, z as usize),
-                None => return 0,
-            }
+    }
+
+    pub fn set_light(&mut self, x: usize, y: usize, z: usize, light: u8){
+        let i = Self::index(x, y, z);
+        match &mut self.light{
+            LightStorage::Uniform(value) => {
+                if *value != light{
+                    let mut values = vec![*value; CHUNK_VOLUME];
+                    values[i] = light;
+                    self.light = LightStorage::Full(values);
+                }
+            },
+            LightStorage::Full(values) => values[i] = light,
         }
+    }
 
-        if y >= CHUNKSIZE as isize {
-            match &neighbors[2]{
-                Some(chunk) => return chunk.get_block(x as usize, 0, z as usize),
-                None => return 0,
-            }
+    /// Returns the block filling the whole chunk, if it's uniform
+    pub fn uniform_block(&self) -> Option<usize>{
+        match &self.blocks{
+            BlockStorage::Uniform(value) => Some(*value),
+            BlockStorage::Paletted(storage) => storage.single_value(),
+            BlockStorage::Full(_) => None,
         }
-        if y < 0{
-            match &neighbors[3]{
-                Some(chunk) => return chunk.get_block(x as usize, CHUNKSIZE-1, z as usize),
-                None => return 0,
+    }
+
+    /// Re-encodes the blocks using the smallest representation that fits them,
+    /// should be called after bulk edits such as generation
+    pub fn compact(&mut self){
+        let values = match &self.blocks{
+            BlockStorage::Uniform(_) => return,
+            BlockStorage::Paletted(storage) => storage.to_values(),
+            BlockStorage::Full(values) => values.clone(),
+        };
+
+        self.blocks = if values.iter().all(|v| *v == values[0]){
+            BlockStorage::Uniform(values[0])
+        }else if let Some(storage) = PalettedStorage::from_values(&values){
+            BlockStorage::Paletted(storage)
+        }else{
+            BlockStorage::Full(values)
+        };
+    }
+
+    /// Collapses the light back to a single value when the whole chunk has the same light
+    pub fn compact_light(&mut self){
+        if let LightStorage::Full(values) = &self.light{
+            if values.iter().all(|v| *v == values[0]){
+                self.light = LightStorage::Uniform(values[0]);
             }
         }
+    }
 
-        if z >= CHUNKSIZE as isize {
-            match &neighbors[4]{
-                Some(chunk) => return chunk.get_block(x as usize, y as usize, 0),
-                None => return 0,
-            }
+    pub fn received_spills(&self) -> u32{
+        self.received_spills
+    }
+
+    pub fn set_received_spills(&mut self, sources: u32){
+        self.received_spills = sources;
+    }
+
+    /// Approximate memory used by this chunk in bytes, including heap allocations
+    pub fn memory_usage(&self) -> usize{
+        let blocks = match &self.blocks{
+            BlockStorage::Uniform(_) => 0,
+            BlockStorage::Paletted(storage) => storage.heap_size(),
+            BlockStorage::Full(values) => values.capacity() * mem::size_of::<usize>(),
+        };
+        let light = match &self.light{
+            LightStorage::Uniform(_) => 0,
+            LightStorage::Full(values) => values.capacity(),
+        };
+
+        mem::size_of::<Self>() + blocks + light
+    }
+
+    /// Finds the chunk and local coordinates of a position that may be up to one
+    /// block outside of this chunk, `None` if that neighbour isn't loaded.
+    ///
+    /// `neighbors` are in `NEIGHBOR_OFFSETS` order, a list of only the first six
+    /// leaves the edges and corners without a neighbour.
+    fn locate<'a>(&'a self, x: isize, y: isize, z: isize, neighbors: &'a [Option<Arc<Chunk>>]) -> Option<(&'a Chunk, usize, usize, usize)>{
+        let size = CHUNKSIZE as isize;
+        let mut offset = [0isize; 3];
+        for (o, c) in offset.iter_mut().zip([x, y, z].iter()){
+            *o = if *c < 0 { -1 } else if *c >= size { 1 } else { 0 };
         }
-        if z < 0{
-            match &neighbors[5]{
-                Some(chunk) => return chunk.get_block(x as usize, y as usize, CHUNKSIZE-1),
-                None => return 0,
-            }
+        if offset == [0, 0, 0]{
+            return Some((self, x as usize, y as usize, z as usize));
+        }
+
+        let neighbor = NEIGHBOR_OFFSETS.iter().position(|o| *o == offset)?;
+        neighbors.get(neighbor)?.as_deref().map(|chunk| (chunk, x.rem_euclid(size) as usize, y.rem_euclid(size) as usize, z.rem_euclid(size) as usize))
+    }
+
+    pub fn check_block(&self, x: isize, y: isize, z: isize, neighbors: &[Option<Arc<Chunk>>]) -> usize{
+        match self.locate(x, y, z, neighbors){
+            Some((chunk, x, y, z)) => chunk.get_block(x, y, z),
+            None => 0,
+        }
+    }
+
+    /// Light of a block that may be in a neighbour, missing neighbours count as open sky
+    pub fn check_light(&self, x: isize, y: isize, z: isize, neighbors: &[Option<Arc<Chunk>>]) -> u8{
+        match self.locate(x, y, z, neighbors){
+            Some((chunk, x, y, z)) => chunk.get_light(x, y, z),
+            None => 0xF0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    /// Sets the first `count` blocks along x then z to distinct values
+    fn fill_distinct(chunk: &mut Chunk, count: usize){
+        for i in 0..count{
+            chunk.set_block(i % CHUNKSIZE, 0, i / CHUNKSIZE, i + 1);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_full_storage(){
+        let mut chunk = Chunk::new(0);
+        fill_distinct(&mut chunk, 255);
+        assert!(matches!(chunk.blocks, BlockStorage::Paletted(_)));
+        // air and 255 others fill the palette, one more doesn't fit
+        chunk.set_block(0, 1, 0, 1000);
+        assert!(matches!(chunk.blocks, BlockStorage::Full(_)));
+        assert_eq!(chunk.get_block(0, 1, 0), 1000);
+        assert_eq!(chunk.get_block(30, 0, 7), 255);
+        assert_eq!(chunk.get_block(0, 2, 0), 0);
+    }
+
+    #[test]
+    fn compact_goes_back_to_uniform(){
+        let mut chunk = Chunk::new(0);
+        fill_distinct(&mut chunk, 300);
+        assert!(matches!(chunk.blocks, BlockStorage::Full(_)));
+
+        for i in 0..300{
+            chunk.set_block(i % CHUNKSIZE, 0, i / CHUNKSIZE, if i < 2 {5} else {0});
         }
-        self.get_block(x as usize, y as usize, z as usize)
+        chunk.compact();
+        assert!(matches!(chunk.blocks, BlockStorage::Paletted(_)));
+        assert_eq!(chunk.get_block(1, 0, 0), 5);
+
+        chunk.set_block(0, 0, 0, 0);
+        chunk.set_block(1, 0, 0, 0);
+        assert_eq!(chunk.uniform_block(), Some(0));
+        chunk.compact();
+        assert!(matches!(chunk.blocks, BlockStorage::Uniform(0)));
+    }
+
+    #[test]
+    fn memory_usage_per_storage(){
+        let base = mem::size_of::<Chunk>();
+        let mut chunk = Chunk::new(0);
+        assert_eq!(chunk.memory_usage(), base);
+
+        // 1 bit per block plus the palette
+        chunk.set_block(0, 0, 0, 1);
+        let one_bit = chunk.memory_usage() - base;
+        assert!((CHUNK_VOLUME / 8..CHUNK_VOLUME / 8 + 256).contains(&one_bit), "{}", one_bit);
+
+        fill_distinct(&mut chunk, 200);
+        let eight_bits = chunk.memory_usage() - base;
+        assert!((CHUNK_VOLUME..CHUNK_VOLUME + 8192).contains(&eight_bits), "{}", eight_bits);
+
+        fill_distinct(&mut chunk, 300);
+        assert_eq!(chunk.memory_usage(), base + CHUNK_VOLUME * mem::size_of::<usize>());
+
+        chunk.set_light(0, 0, 0, 0xF0);
+        assert_eq!(chunk.memory_usage(), base + CHUNK_VOLUME * mem::size_of::<usize>() + CHUNK_VOLUME);
     }
 }
@@ -0,0 +1,183 @@
+use super::stages::default_state;
+use crate::game::registry::Registry;
+
+use noise::{Fbm, MultiFractal, NoiseFn, Seedable};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Biome{
+    Ocean,
+    Plains,
+    Desert,
+    Forest,
+    Mountains,
+    Tundra
+}
+
+pub const BIOMES: [Biome; 6] = [Biome::Ocean, Biome::Plains, Biome::Desert, Biome::Forest, Biome::Mountains, Biome::Tundra];
+
+/// Shape and blocks of a biome
+pub struct BiomeData{
+    /// Climate the biome is centered on, as `[temperature, humidity]` in `[-1, 1]`
+    pub climate: [f64; 2],
+    /// Surface height when the terrain noise is zero
+    pub base_height: f64,
+    /// How far the surface moves up or down with the terrain noise
+    pub height_variation: f64,
+    pub top: usize,
+    pub filler: usize
+}
+
+impl BiomeData{
+    pub fn height(&self, noise: f64) -> f64{
+        self.base_height + self.height_variation * noise
+    }
+}
+
+/// Picks biomes from temperature and humidity noise maps and blends their heights
+pub struct BiomeMap{
+    temperature: Fbm,
+    humidity: Fbm,
+    data: Vec<BiomeData>,
+    /// Climate distance over which neighbouring biomes' heights are blended
+    pub blend: f64
+}
+
+impl BiomeMap{
+    pub fn new(seed: u32, registry: &Registry) -> Self{
+        let temperature = Fbm::new().set_seed(seed.wrapping_add(1)).set_octaves(3);
+        let humidity = Fbm::new().set_seed(seed.wrapping_add(2)).set_octaves(3);
+
+        let biome = |climate, base_height, height_variation, top, filler| BiomeData{
+            climate,
+            base_height,
+            height_variation,
+            top: default_state(registry, top),
+            filler: default_state(registry, filler)
+        };
+
+        // indexed by `Biome as usize`, same order as `BIOMES`
+        let data = vec![
+            biome([0.1, 0.7], -20., 4., "sand", "sand"),
+            biome([0.1, -0.1], -8., 6., "grass", "dirt"),
+            biome([0.7, -0.6], -6., 3., "sand", "sand"),
+            biome([0.2, 0.35], -8., 10., "grass", "dirt"),
+            biome([-0.2, -0.5], 4., 24., "gravel", "stone"),
+            biome([-0.7, 0.1], -6., 6., "snow", "dirt"),
+        ];
+
+        Self{
+            temperature,
+            humidity,
+            data,
+            blend: 0.15
+        }
+    }
+
+    /// Temperature and humidity of a column
+    pub fn climate(&self, x: isize, z: isize) -> [f64; 2]{
+        let point = [x as f64 * 0.002, z as f64 * 0.002];
+        // the raw noise rarely reaches its extremes, stretch it so every biome shows up
+        [(self.temperature.get(point) * 1.6).clamp(-1., 1.), (self.humidity.get(point) * 1.6).clamp(-1., 1.)]
+    }
+
+    pub fn data(&self, biome: Biome) -> &BiomeData{
+        &self.data[biome as usize]
+    }
+
+    fn distance_squared(a: [f64; 2], b: [f64; 2]) -> f64{
+        (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)
+    }
+
+    pub fn biome_from_climate(&self, climate: [f64; 2]) -> Biome{
+        let mut nearest = BIOMES[0];
+        for biome in &BIOMES[1..]{
+            if Self::distance_squared(climate, self.data(*biome).climate) < Self::distance_squared(climate, self.data(nearest).climate){
+                nearest = *biome;
+            }
+        }
+        nearest
+    }
+
+    pub fn biome_at(&self, x: isize, z: isize) -> Biome{
+        self.biome_from_climate(self.climate(x, z))
+    }
+
+    /// Surface height for a column, mixing the height curves of every biome
+    /// weighted by how close the column's climate is to theirs.
+    ///
+    /// Since the climate changes smoothly, so does the height across biome borders.
+    pub fn height(&self, climate: [f64; 2], noise: f64) -> f64{
+        let nearest = Self::distance_squared(climate, self.data(self.biome_from_climate(climate)).climate);
+        let (mut height, mut total) = (0., 0.);
+        for data in &self.data{
+            // relative to the nearest biome, so the weights can't all underflow
+            let weight = (-(Self::distance_squared(climate, data.climate) - nearest) / (self.blend * self.blend)).exp();
+            height += data.height(noise) * weight;
+            total += weight;
+        }
+        height / total
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::game::game::create_registry;
+
+    #[test]
+    fn biomes_are_deterministic(){
+        let registry = create_registry();
+        let map = BiomeMap::new(7, &registry);
+        let same = BiomeMap::new(7, &registry);
+        let reseeded = BiomeMap::new(8, &registry);
+
+        let columns: Vec<(isize, isize)> = (-20..20).flat_map(|x| (-20..20).map(move |z| (x * 97, z * 89))).collect();
+        assert!(columns.iter().all(|(x, z)| map.biome_at(*x, *z) == same.biome_at(*x, *z)));
+        assert!(columns.iter().any(|(x, z)| map.biome_at(*x, *z) != reseeded.biome_at(*x, *z)));
+    }
+
+    #[test]
+    fn every_biome_can_be_picked(){
+        let registry = create_registry();
+        let map = BiomeMap::new(7, &registry);
+        for biome in &BIOMES{
+            assert_eq!(map.biome_from_climate(map.data(*biome).climate), *biome);
+        }
+
+        // and shows up in the world
+        let mut found = Vec::new();
+        for x in -100..100{
+            for z in -100..100{
+                let biome = map.biome_at(x * 50, z * 50);
+                if !found.contains(&biome){
+                    found.push(biome);
+                }
+            }
+        }
+        assert_eq!(found.len(), BIOMES.len(), "only found {:?}", found);
+    }
+
+    #[test]
+    fn heights_blend_across_borders(){
+        let registry = create_registry();
+        let map = BiomeMap::new(7, &registry);
+        let (mut borders, mut largest, mut largest_unblended) = (0, 0f64, 0f64);
+        for z in -5..5{
+            let z = z * 1000;
+            for x in -5000..5000{
+                let (here, there) = (map.climate(x, z), map.climate(x + 1, z));
+                let (biome, next) = (map.biome_from_climate(here), map.biome_from_climate(there));
+                if biome == next { continue }
+                borders += 1;
+                largest = largest.max((map.height(here, 0.) - map.height(there, 0.)).abs());
+                largest_unblended = largest_unblended.max((map.data(biome).height(0.) - map.data(next).height(0.)).abs());
+            }
+        }
+
+        assert!(borders > 10, "only {} borders crossed", borders);
+        // without blending, some borders would be cliffs of 10 blocks or more, blended
+        // they're slopes of a few blocks per column where the climate changes fastest
+        assert!(largest_unblended >= 10., "{}", largest_unblended);
+        assert!(largest < 4., "{} blocks between neighbouring columns", largest);
+    }
+}
@@ -0,0 +1,210 @@
+use super::{GenerationContext, GenerationStage, Stage};
+use crate::game::registry::Registry;
+use crate::game::terrain::chunk::CHUNKSIZE;
+
+use noise::{NoiseFn, Perlin, Seedable};
+
+/// Shape of the caves carved by `CaveStage`
+#[derive(Debug, Clone, Copy)]
+pub struct CaveConfig{
+    /// Whether anything is carved at all
+    pub enabled: bool,
+    /// Frequency of the two noise fields whose zero crossings form the tunnels
+    pub tunnel_frequency: f64,
+    /// Tunnel thickness, in noise units
+    pub tunnel_radius: f64,
+    pub cavern_frequency: f64,
+    /// Noise value above which large caverns are carved, higher means fewer caverns
+    pub cavern_threshold: f64,
+    /// Caverns stay at least this many blocks below the surface
+    pub cavern_depth: isize,
+    pub overhang_frequency: f64,
+    pub overhang_threshold: f64,
+    /// How far below the surface overhangs may be undercut
+    pub overhang_depth: isize,
+    /// Nothing is carved at or below this height
+    pub floor: isize
+}
+
+impl Default for CaveConfig{
+    fn default() -> Self{
+        Self{
+            enabled: true,
+            tunnel_frequency: 0.02,
+            tunnel_radius: 0.08,
+            cavern_frequency: 0.012,
+            cavern_threshold: 0.55,
+            cavern_depth: 12,
+            overhang_frequency: 0.06,
+            overhang_threshold: 0.45,
+            overhang_depth: 5,
+            floor: -(CHUNKSIZE as isize)
+        }
+    }
+}
+
+/// Carves tunnels, caverns and overhangs out of the terrain using 3D noise.
+///
+/// Unbreakable blocks such as bedrock are never removed.
+pub struct CaveStage{
+    tunnels: [Perlin; 2],
+    caverns: Perlin,
+    overhangs: Perlin,
+    breakable: Vec<bool>,
+    pub config: CaveConfig
+}
+
+impl CaveStage{
+    pub fn new(seed: u32, registry: &Registry, config: CaveConfig) -> Self{
+        let perlin = |offset: u32| Perlin::new().set_seed(seed.wrapping_add(offset));
+
+        let mut breakable = Vec::new();
+        while let Some(data) = registry.block_registry().by_state(breakable.len()){
+            breakable.push(data.is_breakable());
+        }
+
+        Self{
+            tunnels: [perlin(10), perlin(11)],
+            caverns: perlin(12),
+            overhangs: perlin(13),
+            breakable,
+            config
+        }
+    }
+
+    fn is_cave(&self, x: f64, y: f64, z: f64, depth: isize) -> bool{
+        let config = &self.config;
+
+        // tunnels are squashed vertically so they wind horizontally more than they dive
+        let f = config.tunnel_frequency;
+        let a = self.tunnels[0].get([x * f, y * f * 1.5, z * f]);
+        let b = self.tunnels[1].get([x * f, y * f * 1.5, z * f]);
+        if a * a + b * b < config.tunnel_radius * config.tunnel_radius{
+            return true;
+        }
+
+        if depth >= config.cavern_depth{
+            let f = config.cavern_frequency;
+            if self.caverns.get([x * f, y * f * 2., z * f]) > config.cavern_threshold{
+                return true;
+            }
+        }
+
+        if depth > 0 && depth <= config.overhang_depth{
+            let f = config.overhang_frequency;
+            if self.overhangs.get([x * f, y * f * 2.5, z * f]) > config.overhang_threshold{
+                return true;
+            }
+        }
+
+        false
+    }
+}
+
+impl GenerationStage for CaveStage{
+    fn stage(&self) -> Stage{
+        Stage::Carver
+    }
+
+    fn apply(&self, context: &mut GenerationContext){
+        if !self.config.enabled { return }
+        for x in 0..CHUNKSIZE{
+            for z in 0..CHUNKSIZE{
+                let surface = context.heightmap[x][z];
+                for y in 0..CHUNKSIZE{
+                    let world = context.world_position(x, y, z);
+                    // only the terrain is carved, not the water above it
+                    if world.y <= self.config.floor || world.y > surface { continue }
+
+                    let block = context.chunk.get_block(x, y, z);
+                    if block == 0 || !self.breakable.get(block).cloned().unwrap_or(true) { continue }
+
+                    if self.is_cave(world.x as f64, world.y as f64, world.z as f64, surface - world.y){
+                        context.chunk.set_block(x, y, z, 0);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::game::game::create_registry;
+    use crate::game::terrain::chunk::{Chunk, ChunkPosition};
+
+    fn state(registry: &Registry, name: &str) -> usize{
+        let blocks = registry.block_registry();
+        blocks.default_state(blocks.id_of(name).unwrap())
+    }
+
+    /// Carves a chunk of stone just under the surface, with a bedrock layer at `y = 4`
+    fn carve(stage: &CaveStage, registry: &Registry, position: ChunkPosition) -> Chunk{
+        let mut context = GenerationContext::new(position, 0);
+        context.chunk = Chunk::new(state(registry, "stone"));
+        for x in 0..CHUNKSIZE{
+            for z in 0..CHUNKSIZE{
+                context.chunk.set_block(x, 4, z, state(registry, "bedrock"));
+            }
+        }
+        context.heightmap = [[(position.y + 1) * CHUNKSIZE as isize - 1; CHUNKSIZE]; CHUNKSIZE];
+        stage.apply(&mut context);
+        context.chunk
+    }
+
+    fn blocks(chunk: &Chunk) -> Vec<usize>{
+        (0..CHUNKSIZE * CHUNKSIZE * CHUNKSIZE)
+            .map(|i| chunk.get_block(i / (CHUNKSIZE * CHUNKSIZE), i / CHUNKSIZE % CHUNKSIZE, i % CHUNKSIZE))
+            .collect()
+    }
+
+    #[test]
+    fn bedrock_is_never_carved(){
+        let registry = create_registry();
+        // every breakable block is in a tunnel
+        let config = CaveConfig{
+            tunnel_radius: 10.,
+            ..CaveConfig::default()
+        };
+        let (stone, bedrock) = (state(&registry, "stone"), state(&registry, "bedrock"));
+        let chunk = carve(&CaveStage::new(1, &registry, config), &registry, ChunkPosition::new(0, 0, 0));
+        for x in 0..CHUNKSIZE{
+            for y in 0..CHUNKSIZE{
+                for z in 0..CHUNKSIZE{
+                    assert_eq!(chunk.get_block(x, y, z), if y == 4 {bedrock} else {0});
+                }
+            }
+        }
+
+        // nor anything at or below the floor
+        let chunk = carve(&CaveStage::new(1, &registry, config), &registry, ChunkPosition::new(0, -1, 0));
+        assert_eq!(chunk.get_block(0, 0, 0), stone);
+        assert_eq!(chunk.get_block(0, 1, 0), 0);
+    }
+
+    #[test]
+    fn caves_follow_the_seed(){
+        let registry = create_registry();
+        let position = ChunkPosition::new(2, -1, -3);
+        let carved = blocks(&carve(&CaveStage::new(5, &registry, CaveConfig::default()), &registry, position));
+        let again = blocks(&carve(&CaveStage::new(5, &registry, CaveConfig::default()), &registry, position));
+        let reseeded = blocks(&carve(&CaveStage::new(6, &registry, CaveConfig::default()), &registry, position));
+
+        assert!(carved.contains(&0), "nothing carved");
+        assert!(carved == again);
+        assert!(carved != reseeded);
+    }
+
+    #[test]
+    fn disabled_caves_carve_nothing(){
+        let registry = create_registry();
+        let config = CaveConfig{
+            enabled: false,
+            tunnel_radius: 10.,
+            ..CaveConfig::default()
+        };
+        let chunk = carve(&CaveStage::new(5, &registry, config), &registry, ChunkPosition::new(2, -1, -3));
+        assert!(blocks(&chunk).iter().all(|block| *block != 0));
+    }
+}
@@ -0,0 +1,59 @@
+use super::{ChunkPosition, GeneratedChunk, StagedGenerator, WorldGenerator};
+use super::stages::{HeightmapStage, SurfaceStage, BedrockStage, WaterStage};
+use super::biome::{Biome, BiomeMap};
+use super::caves::{CaveConfig, CaveStage};
+use super::ores::{OreConfig, OreStage};
+use super::features::TreeStage;
+use crate::game::registry::Registry;
+use crate::game::terrain::chunk::CHUNKSIZE;
+
+use std::sync::Arc;
+
+/// Height below which the terrain is flooded
+pub const SEA_LEVEL: isize = -15;
+
+/// Biome-shaped terrain over stone riddled with caves, with a bedrock floor one chunk below zero
+pub struct DefaultGenerator{
+    biomes: Arc<BiomeMap>,
+    pipeline: StagedGenerator
+}
+
+impl DefaultGenerator{
+    pub fn new(seed: u32, registry: &Registry) -> Self{
+        Self::with_caves(seed, registry, CaveConfig::default())
+    }
+
+    pub fn with_caves(seed: u32, registry: &Registry, caves: CaveConfig) -> Self{
+        let biomes = Arc::new(BiomeMap::new(seed, registry));
+        let ores = vec![
+            OreConfig::new("coal_ore", [-31, 16], 14, 16),
+            OreConfig::new("iron_ore", [-31, -6], 8, 10),
+            OreConfig::new("gold_ore", [-31, -20], 6, 4),
+            OreConfig::new("glowstone", [-31, -16], 5, 2),
+        ];
+
+        let pipeline = StagedGenerator::new(seed)
+            .with(HeightmapStage::new(seed, &biomes, registry, "stone"))
+            .with(SurfaceStage::new(&biomes))
+            .with(BedrockStage::new(registry, "bedrock", -(CHUNKSIZE as isize)))
+            .with(WaterStage::new(registry, "water", SEA_LEVEL))
+            .with(CaveStage::new(seed, registry, caves))
+            .with(OreStage::new(registry, "stone", ores))
+            .with(TreeStage::new(registry, "log", "leaves"));
+
+        Self{
+            biomes,
+            pipeline
+        }
+    }
+}
+
+impl WorldGenerator for DefaultGenerator{
+    fn generate(&self, position: ChunkPosition) -> GeneratedChunk{
+        self.pipeline.generate(position)
+    }
+
+    fn biome_at(&self, x: isize, z: isize) -> Option<Biome>{
+        Some(self.biomes.biome_at(x, z))
+    }
+}
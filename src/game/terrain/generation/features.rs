@@ -0,0 +1,77 @@
+use super::{GenerationContext, GenerationStage, Stage};
+use super::biome::{Biome, BIOMES};
+use super::stages::default_state;
+use crate::game::registry::Registry;
+use crate::game::terrain::chunk::{BlockPosition, CHUNKSIZE};
+
+/// Grows trees on grass, features near the border spill into the neighbouring chunks
+pub struct TreeStage{
+    grass: usize,
+    log: usize,
+    leaves: usize,
+    /// Tree attempts per chunk for each biome, indexed by `Biome as usize`
+    pub density: [usize; BIOMES.len()]
+}
+
+impl TreeStage{
+    pub fn new(registry: &Registry, log: &str, leaves: &str) -> Self{
+        let mut density = [0; BIOMES.len()];
+        density[Biome::Forest as usize] = 8;
+        density[Biome::Plains as usize] = 1;
+        density[Biome::Tundra as usize] = 1;
+
+        Self{
+            grass: default_state(registry, "grass"),
+            log: default_state(registry, log),
+            leaves: default_state(registry, leaves),
+            density
+        }
+    }
+
+    fn grow(&self, context: &mut GenerationContext, base: BlockPosition, height: isize){
+        for y in 0..height{
+            context.place_block(BlockPosition::new(base.x, base.y + y, base.z), self.log);
+        }
+
+        // two wide layers around the top of the trunk and two narrow ones above
+        let top = base.y + height;
+        for (y, radius) in [(top - 2, 2isize), (top - 1, 2), (top, 1), (top + 1, 1)].iter(){
+            for dx in -radius..=*radius{
+                for dz in -radius..=*radius{
+                    // cut the corners of the wide layers to round the canopy
+                    if *radius == 2 && dx.abs() == 2 && dz.abs() == 2 { continue }
+                    context.place_block(BlockPosition::new(base.x + dx, *y, base.z + dz), self.leaves);
+                }
+            }
+        }
+    }
+}
+
+impl GenerationStage for TreeStage{
+    fn stage(&self) -> Stage{
+        Stage::Decoration
+    }
+
+    fn apply(&self, context: &mut GenerationContext){
+        let size = CHUNKSIZE as isize;
+        let mut random = context.random(0x7ee);
+        let attempts = self.density.iter().max().cloned().unwrap_or(0);
+        for _ in 0..attempts{
+            let (x, z) = (random.range(0, size) as usize, random.range(0, size) as usize);
+            let height = random.range(4, 7);
+            // rarer biomes skip some of the attempts
+            let chance = random.range(0, attempts as isize) as usize;
+            if chance >= self.density[context.biomes[x][z] as usize] { continue }
+
+            // only the chunk holding the ground block grows the tree
+            let ground = context.heightmap[x][z] - context.position.y * size;
+            if ground < 0 || ground >= size { continue }
+            if context.chunk.get_block(x, ground as usize, z) != self.grass { continue }
+            // no trees under water
+            if ground + 1 < size && context.chunk.get_block(x, ground as usize + 1, z) != 0 { continue }
+
+            let base = context.world_position(x, ground as usize + 1, z);
+            self.grow(context, base, height);
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use crate::game::terrain::chunk::{Chunk, ChunkPosition, BlockPosition, CHUNKSIZE, world_to_local};
+use crate::utils::random::Random;
+
+pub mod stages;
+pub mod biome;
+pub mod caves;
+pub mod ores;
+pub mod features;
+pub mod default;
+
+pub use default::DefaultGenerator;
+use biome::Biome;
+
+/// A block placed by a feature outside of the chunk being generated
+pub type PendingWrite = (BlockPosition, usize);
+
+/// Result of generating a chunk
+pub struct GeneratedChunk{
+    pub chunk: Chunk,
+    /// Blocks of features that spilled into neighbouring chunks, to be written
+    /// once those chunks exist
+    pub pending: Vec<PendingWrite>
+}
+
+/// Produces the initial blocks of a chunk.
+///
+/// Generation must only depend on the generator's seed and the chunk position,
+/// so the same chunk can be regenerated identically.
+pub trait WorldGenerator: Send + Sync{
+    fn generate(&self, position: ChunkPosition) -> GeneratedChunk;
+
+    /// Biome of a world column, for generators that have biomes
+    fn biome_at(&self, _x: isize, _z: isize) -> Option<Biome>{
+        None
+    }
+}
+
+/// Order in which stages run inside a `StagedGenerator`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage{
+    /// Base terrain shape (density or heightmap) filled with the base block
+    Base,
+    /// Replaces the top layers of the terrain (grass, dirt, sand...)
+    Surface,
+    /// Removes blocks from the terrain (caves, ravines...)
+    Carver,
+    /// Places ores, plants and other features on the finished terrain
+    Decoration
+}
+
+/// State shared by the stages while a chunk is generated
+pub struct GenerationContext{
+    pub position: ChunkPosition,
+    pub seed: u32,
+    pub chunk: Chunk,
+    /// World height of the topmost terrain block of each column, indexed `[x][z]`
+    pub heightmap: [[isize; CHUNKSIZE]; CHUNKSIZE],
+    /// Biome of each column, indexed `[x][z]`
+    pub biomes: [[Biome; CHUNKSIZE]; CHUNKSIZE],
+    pub pending: Vec<PendingWrite>
+}
+
+impl GenerationContext{
+    pub fn new(position: ChunkPosition, seed: u32) -> Self{
+        Self{
+            position,
+            seed,
+            chunk: Chunk::new(0),
+            heightmap: [[isize::MIN; CHUNKSIZE]; CHUNKSIZE],
+            biomes: [[Biome::Plains; CHUNKSIZE]; CHUNKSIZE],
+            pending: Vec::new()
+        }
+    }
+
+    /// World position of a block inside the chunk being generated
+    pub fn world_position(&self, x: usize, y: usize, z: usize) -> BlockPosition{
+        let size = CHUNKSIZE as isize;
+        BlockPosition::new(self.position.x * size + x as isize, self.position.y * size + y as isize, self.position.z * size + z as isize)
+    }
+
+    /// Places a block of a feature at a world position, only replacing air.
+    ///
+    /// Blocks outside this chunk are queued in `pending`.
+    pub fn place_block(&mut self, position: BlockPosition, block: usize){
+        let (chunk, local) = world_to_local(position);
+        if chunk != self.position{
+            self.pending.push((position, block));
+        }else if self.chunk.get_block(local[0], local[1], local[2]) == 0{
+            self.chunk.set_block(local[0], local[1], local[2], block);
+        }
+    }
+
+    /// Random stream unique to this chunk and `salt`, so stages don't share numbers
+    pub fn random(&self, salt: u64) -> Random{
+        Random::new(Random::hash(self.seed as u64, [self.position.x, self.position.y, self.position.z], salt))
+    }
+}
+
+pub trait GenerationStage: Send + Sync{
+    fn stage(&self) -> Stage;
+    fn apply(&self, context: &mut GenerationContext);
+}
+
+/// Generator running a list of stages, ordered by their `Stage`
+pub struct StagedGenerator{
+    seed: u32,
+    stages: Vec<Box<dyn GenerationStage>>
+}
+
+impl StagedGenerator{
+    pub fn new(seed: u32) -> Self{
+        Self{
+            seed,
+            stages: Vec::new()
+        }
+    }
+
+    /// Adds a stage, stages of the same kind run in insertion order
+    pub fn with<S: GenerationStage + 'static>(mut self, stage: S) -> Self{
+        self.stages.push(Box::new(stage));
+        self.stages.sort_by_key(|stage| stage.stage());
+        self
+    }
+}
+
+impl WorldGenerator for StagedGenerator{
+    fn generate(&self, position: ChunkPosition) -> GeneratedChunk{
+        let mut context = GenerationContext::new(position, self.seed);
+        for stage in &self.stages{
+            stage.apply(&mut context);
+        }
+
+        let mut chunk = context.chunk;
+        chunk.compact();
+        GeneratedChunk{
+            chunk,
+            pending: context.pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::game::game::create_registry;
+    use std::sync::{Arc, Mutex};
+
+    /// Records when it ran, and fills or clears the chunk's bottom layer
+    struct Recorder{
+        stage: Stage,
+        name: &'static str,
+        block: usize,
+        log: Arc<Mutex<Vec<&'static str>>>
+    }
+
+    impl GenerationStage for Recorder{
+        fn stage(&self) -> Stage{
+            self.stage
+        }
+
+        fn apply(&self, context: &mut GenerationContext){
+            self.log.lock().unwrap().push(self.name);
+            context.chunk.set_block(0, 0, 0, self.block);
+        }
+    }
+
+    #[test]
+    fn stages_run_in_order(){
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let recorder = |stage, name, block| Recorder{ stage, name, block, log: log.clone() };
+        let generator = StagedGenerator::new(1)
+            .with(recorder(Stage::Decoration, "decoration", 4))
+            .with(recorder(Stage::Surface, "surface 1", 2))
+            .with(recorder(Stage::Carver, "carver", 0))
+            .with(recorder(Stage::Base, "base", 1))
+            .with(recorder(Stage::Surface, "surface 2", 3));
+
+        let generated = generator.generate(ChunkPosition::new(0, 0, 0));
+        assert_eq!(*log.lock().unwrap(), vec!["base", "surface 1", "surface 2", "carver", "decoration"]);
+        // every stage works on the chunk left by the previous ones
+        assert_eq!(generated.chunk.get_block(0, 0, 0), 4);
+    }
+
+    #[test]
+    fn generation_is_deterministic(){
+        let registry = create_registry();
+        let generator = DefaultGenerator::new(10291302, &registry);
+        let other = DefaultGenerator::new(10291302, &registry);
+        let reseeded = DefaultGenerator::new(42, &registry);
+
+        let mut differs = false;
+        for position in &[ChunkPosition::new(0, -1, 0), ChunkPosition::new(3, -1, -2), ChunkPosition::new(-2, -2, 5)]{
+            let a = generator.generate(*position);
+            // other positions in between don't change the result
+            generator.generate(ChunkPosition::new(7, 0, 7));
+            let b = other.generate(*position);
+            let c = reseeded.generate(*position);
+
+            assert_eq!(a.pending, b.pending);
+            for x in 0..CHUNKSIZE{
+                for y in 0..CHUNKSIZE{
+                    for z in 0..CHUNKSIZE{
+                        assert_eq!(a.chunk.get_block(x, y, z), b.chunk.get_block(x, y, z));
+                        differs |= a.chunk.get_block(x, y, z) != c.chunk.get_block(x, y, z);
+                    }
+                }
+            }
+        }
+        assert!(differs, "the seed changes nothing");
+    }
+}
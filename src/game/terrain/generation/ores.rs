@@ -0,0 +1,172 @@
+use super::{GenerationContext, GenerationStage, Stage};
+use super::stages::default_state;
+use crate::game::registry::Registry;
+use crate::game::terrain::chunk::CHUNKSIZE;
+
+/// Where and how often an ore appears
+#[derive(Debug, Clone)]
+pub struct OreConfig{
+    pub block: String,
+    /// Lowest and highest world height veins can start at, inclusive
+    pub height: [isize; 2],
+    /// Amount of blocks visited by each vein
+    pub vein_size: usize,
+    /// Vein attempts per chunk, attempts outside the height range are skipped
+    pub veins_per_chunk: usize
+}
+
+impl OreConfig{
+    pub fn new(block: &str, height: [isize; 2], vein_size: usize, veins_per_chunk: usize) -> Self{
+        Self{
+            block: block.to_string(),
+            height,
+            vein_size,
+            veins_per_chunk
+        }
+    }
+}
+
+struct Ore{
+    block: usize,
+    config: OreConfig
+}
+
+/// Scatters ore veins inside a host block, seeded per chunk so regeneration yields the same veins
+pub struct OreStage{
+    host: usize,
+    ores: Vec<Ore>
+}
+
+const DIRECTIONS: [[isize; 3]; 6] = [[1, 0, 0], [-1, 0, 0], [0, 1, 0], [0, -1, 0], [0, 0, 1], [0, 0, -1]];
+
+impl OreStage{
+    pub fn new(registry: &Registry, host: &str, ores: Vec<OreConfig>) -> Self{
+        let ores = ores.into_iter().map(|config| Ore{
+            block: default_state(registry, &config.block),
+            config
+        }).collect();
+
+        Self{
+            host: default_state(registry, host),
+            ores
+        }
+    }
+}
+
+impl GenerationStage for OreStage{
+    fn stage(&self) -> Stage{
+        Stage::Decoration
+    }
+
+    fn apply(&self, context: &mut GenerationContext){
+        let size = CHUNKSIZE as isize;
+        for (i, ore) in self.ores.iter().enumerate(){
+            let mut random = context.random(0x0e5 + i as u64);
+            for _ in 0..ore.config.veins_per_chunk{
+                let mut current = [random.range(0, size), random.range(0, size), random.range(0, size)];
+                let start_y = context.position.y * size + current[1];
+                if start_y < ore.config.height[0] || start_y > ore.config.height[1]{
+                    continue;
+                }
+
+                // random walk from the starting block, only replacing the host block
+                for _ in 0..ore.config.vein_size{
+                    if current.iter().all(|c| *c >= 0 && *c < size){
+                        let (x, y, z) = (current[0] as usize, current[1] as usize, current[2] as usize);
+                        if context.chunk.get_block(x, y, z) == self.host{
+                            context.chunk.set_block(x, y, z, ore.block);
+                        }
+                    }
+
+                    let direction = DIRECTIONS[random.range(0, 6) as usize];
+                    for axis in 0..3{
+                        current[axis] += direction[axis];
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use super::super::{DefaultGenerator, StagedGenerator, WorldGenerator};
+    use crate::game::game::create_registry;
+    use crate::game::terrain::chunk::{Chunk, ChunkPosition};
+    use std::collections::HashMap;
+
+    /// Counts how many blocks of each id are generated in the chunks between `min` and `max`, inclusive
+    fn count_blocks(generator: &dyn WorldGenerator, min: ChunkPosition, max: ChunkPosition) -> HashMap<usize, usize>{
+        let mut counts = HashMap::new();
+        for cx in min.x..=max.x{
+            for cy in min.y..=max.y{
+                for cz in min.z..=max.z{
+                    let chunk = generator.generate(ChunkPosition::new(cx, cy, cz)).chunk;
+                    for x in 0..CHUNKSIZE{
+                        for y in 0..CHUNKSIZE{
+                            for z in 0..CHUNKSIZE{
+                                *counts.entry(chunk.get_block(x, y, z)).or_insert(0) += 1;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        counts
+    }
+
+    /// Chunks made only of the host block, so every vein is fully placed
+    struct Fill(usize);
+
+    impl GenerationStage for Fill{
+        fn stage(&self) -> Stage{
+            Stage::Base
+        }
+
+        fn apply(&self, context: &mut GenerationContext){
+            context.chunk = Chunk::new(self.0);
+        }
+    }
+
+    #[test]
+    fn veins_stay_in_their_depth_range(){
+        let registry = create_registry();
+        let stone = default_state(&registry, "stone");
+        let gold = default_state(&registry, "gold_ore");
+        let coal = default_state(&registry, "coal_ore");
+        let config = |block, height, size, veins| OreConfig::new(block, height, size, veins);
+        let generator = StagedGenerator::new(7)
+            .with(Fill(stone))
+            .with(OreStage::new(&registry, "stone", vec![config("gold_ore", [-31, -20], 4, 6), config("coal_ore", [-31, 16], 8, 12)]));
+
+        // veins start in the range and wander at most their size away from it
+        let above = count_blocks(&generator, ChunkPosition::new(-1, 1, -1), ChunkPosition::new(1, 1, 1));
+        let surface = count_blocks(&generator, ChunkPosition::new(-1, 0, -1), ChunkPosition::new(1, 0, 1));
+        let below = count_blocks(&generator, ChunkPosition::new(-1, -1, -1), ChunkPosition::new(1, -1, 1));
+        assert_eq!(above.get(&coal).copied().unwrap_or(0) + above.get(&gold).copied().unwrap_or(0), 0);
+        assert_eq!(surface.get(&gold).copied().unwrap_or(0), 0);
+        assert!(surface[&coal] > 0);
+
+        // a vein replaces at most one block per step
+        let chunks = 9;
+        assert!(below[&gold] > 0 && below[&gold] <= chunks * 6 * 4);
+        assert!(below[&coal] > below[&gold] && below[&coal] <= chunks * 12 * 8);
+        assert_eq!(below.values().sum::<usize>(), chunks * CHUNKSIZE * CHUNKSIZE * CHUNKSIZE);
+    }
+
+    #[test]
+    fn default_ore_distribution(){
+        let registry = create_registry();
+        let generator = DefaultGenerator::new(10291302, &registry);
+        let counts = count_blocks(&generator, ChunkPosition::new(-1, -1, -1), ChunkPosition::new(1, -1, 1));
+        assert_eq!(counts, count_blocks(&generator, ChunkPosition::new(-1, -1, -1), ChunkPosition::new(1, -1, 1)));
+
+        let count = |name| counts.get(&default_state(&registry, name)).copied().unwrap_or(0);
+        // rarer ores have fewer and smaller veins
+        assert!(count("coal_ore") > count("iron_ore"));
+        assert!(count("iron_ore") > count("gold_ore"));
+        assert!(count("gold_ore") > 0);
+    }
+}
@@ -0,0 +1,168 @@
+use super::{GenerationContext, GenerationStage, Stage};
+use super::biome::BiomeMap;
+use crate::game::registry::Registry;
+use crate::game::terrain::chunk::CHUNKSIZE;
+
+use noise::{Fbm, NoiseFn, Seedable};
+use std::sync::Arc;
+
+/// Default state of a block looked up by name, falling back to `missing`
+pub fn default_state(registry: &Registry, name: &str) -> usize{
+    let blocks = registry.block_registry();
+    blocks.id_of(name).map(|id| blocks.default_state(id)).unwrap_or_else(|| blocks.default_state(1))
+}
+
+/// Shapes the terrain from a 2D noise heightmap bent by each column's biome,
+/// filling everything below the surface
+pub struct HeightmapStage{
+    noise: Fbm,
+    biomes: Arc<BiomeMap>,
+    block: usize,
+    /// Horizontal noise frequency
+    pub scale: f64
+}
+
+impl HeightmapStage{
+    pub fn new(seed: u32, biomes: &Arc<BiomeMap>, registry: &Registry, block: &str) -> Self{
+        Self{
+            noise: Fbm::new().set_seed(seed),
+            biomes: biomes.clone(),
+            block: default_state(registry, block),
+            scale: 0.01
+        }
+    }
+}
+
+impl GenerationStage for HeightmapStage{
+    fn stage(&self) -> Stage{
+        Stage::Base
+    }
+
+    fn apply(&self, context: &mut GenerationContext){
+        for x in 0..CHUNKSIZE{
+            for z in 0..CHUNKSIZE{
+                let world = context.world_position(x, 0, z);
+                let (nx, nz) = (world.x as f64, world.z as f64);
+                let climate = self.biomes.climate(world.x, world.z);
+                let surface = self.biomes.height(climate, self.noise.get([nx*self.scale, nz*self.scale])).round() as isize;
+                context.heightmap[x][z] = surface;
+                context.biomes[x][z] = self.biomes.biome_from_climate(climate);
+
+                for y in 0..CHUNKSIZE{
+                    if context.world_position(x, y, z).y <= surface{
+                        context.chunk.set_block(x, y, z, self.block);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Covers the terrain with its biome's top block and a few layers of filler
+pub struct SurfaceStage{
+    biomes: Arc<BiomeMap>,
+    pub filler_depth: isize
+}
+
+impl SurfaceStage{
+    pub fn new(biomes: &Arc<BiomeMap>) -> Self{
+        Self{
+            biomes: biomes.clone(),
+            filler_depth: 3
+        }
+    }
+}
+
+impl GenerationStage for SurfaceStage{
+    fn stage(&self) -> Stage{
+        Stage::Surface
+    }
+
+    fn apply(&self, context: &mut GenerationContext){
+        for x in 0..CHUNKSIZE{
+            for z in 0..CHUNKSIZE{
+                let surface = context.heightmap[x][z];
+                let biome = self.biomes.data(context.biomes[x][z]);
+                for y in 0..CHUNKSIZE{
+                    let world_y = context.world_position(x, y, z).y;
+                    if world_y == surface{
+                        context.chunk.set_block(x, y, z, biome.top);
+                    }else if world_y < surface && world_y >= surface - self.filler_depth{
+                        context.chunk.set_block(x, y, z, biome.filler);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Places a single unbreakable layer at a fixed height
+pub struct BedrockStage{
+    block: usize,
+    pub height: isize
+}
+
+impl BedrockStage{
+    pub fn new(registry: &Registry, block: &str, height: isize) -> Self{
+        Self{
+            block: default_state(registry, block),
+            height
+        }
+    }
+}
+
+impl GenerationStage for BedrockStage{
+    fn stage(&self) -> Stage{
+        Stage::Surface
+    }
+
+    fn apply(&self, context: &mut GenerationContext){
+        let size = CHUNKSIZE as isize;
+        let local = self.height - context.position.y * size;
+        if local < 0 || local >= size { return }
+
+        for x in 0..CHUNKSIZE{
+            for z in 0..CHUNKSIZE{
+                // the surface always wins, like grass sitting right on top of the bedrock
+                if context.heightmap[x][z] != self.height{
+                    context.chunk.set_block(x, local as usize, z, self.block);
+                }
+            }
+        }
+    }
+}
+
+/// Fills the air between the terrain and the sea level with water
+pub struct WaterStage{
+    block: usize,
+    pub sea_level: isize
+}
+
+impl WaterStage{
+    pub fn new(registry: &Registry, block: &str, sea_level: isize) -> Self{
+        Self{
+            block: default_state(registry, block),
+            sea_level
+        }
+    }
+}
+
+impl GenerationStage for WaterStage{
+    fn stage(&self) -> Stage{
+        Stage::Surface
+    }
+
+    fn apply(&self, context: &mut GenerationContext){
+        for x in 0..CHUNKSIZE{
+            for z in 0..CHUNKSIZE{
+                let surface = context.heightmap[x][z];
+                for y in 0..CHUNKSIZE{
+                    let world_y = context.world_position(x, y, z).y;
+                    if world_y > surface && world_y <= self.sea_level{
+                        context.chunk.set_block(x, y, z, self.block);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,250 @@
+use super::chunk::ChunkPosition;
+
+use uvth::ThreadPool;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Handed to a chunk job, identifies it and tells it whether it was cancelled
+#[derive(Debug, Clone)]
+pub struct JobTicket{
+    pub generation: u64,
+    cancelled: Arc<AtomicBool>
+}
+
+impl JobTicket{
+    /// Jobs should check this before starting, their result is dropped anyway
+    pub fn is_cancelled(&self) -> bool{
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn cancel(&self){
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Runs chunk jobs, the game runs them on a thread pool
+pub trait JobExecutor{
+    fn execute(&self, job: Box<dyn FnOnce() + Send>);
+}
+
+impl JobExecutor for ThreadPool{
+    fn execute(&self, job: Box<dyn FnOnce() + Send>){
+        ThreadPool::execute(self, job);
+    }
+}
+
+/// Runs each job right away on the calling thread, so its result is there on the next receive
+#[cfg(test)]
+pub struct Inline;
+
+#[cfg(test)]
+impl JobExecutor for Inline{
+    fn execute(&self, job: Box<dyn FnOnce() + Send>){
+        job();
+    }
+}
+
+/// Chunk jobs of one kind (loading, meshing...) waiting for a thread or running.
+///
+/// A chunk has at most one waiting job, requests for a chunk already waiting are
+/// merged. Waiting jobs start nearest to the player first, and every started job
+/// gets a new generation: only the result of a chunk's latest job is kept, so an
+/// outdated result never replaces a newer one.
+pub struct JobQueue{
+    waiting: HashSet<ChunkPosition>,
+    running: HashMap<ChunkPosition, JobTicket>,
+    generation: u64,
+    /// Jobs allowed to run at once, the others wait so nearer chunks can overtake them
+    max_running: usize
+}
+
+fn distance_squared(a: &ChunkPosition, b: &ChunkPosition) -> isize{
+    let (dx, dy, dz) = (a.x - b.x, a.y - b.y, a.z - b.z);
+    dx * dx + dy * dy + dz * dz
+}
+
+impl JobQueue{
+    pub fn new(max_running: usize) -> Self{
+        Self{
+            waiting: HashSet::new(),
+            running: HashMap::new(),
+            generation: 0,
+            max_running
+        }
+    }
+
+    /// Queues a job for the chunk, a chunk already running gets another job once
+    /// a thread is free, whose result will replace the running one's
+    pub fn request(&mut self, position: ChunkPosition){
+        self.waiting.insert(position);
+    }
+
+    /// Whether the chunk has a job waiting or running
+    pub fn is_pending(&self, position: &ChunkPosition) -> bool{
+        self.waiting.contains(position) || self.running.contains_key(position)
+    }
+
+    /// Amount of chunks with a job waiting or running
+    pub fn pending(&self) -> usize{
+        self.waiting.len() + self.running.keys().filter(|position| !self.waiting.contains(position)).count()
+    }
+
+    /// Drops the chunk's waiting job and cancels its running one
+    pub fn cancel(&mut self, position: &ChunkPosition){
+        self.waiting.remove(position);
+        if let Some(ticket) = self.running.remove(position){
+            ticket.cancel();
+        }
+    }
+
+    /// Cancels the jobs of every chunk `keep` returns `false` for
+    pub fn retain<F: Fn(&ChunkPosition) -> bool>(&mut self, keep: F){
+        let cancelled: Vec<ChunkPosition> = self.waiting.iter()
+            .chain(self.running.keys())
+            .filter(|position| !keep(position))
+            .cloned()
+            .collect();
+        for position in &cancelled{
+            self.cancel(position);
+        }
+    }
+
+    /// Starts up to `limit` waiting jobs, nearest to `center` first, while there
+    /// are free slots. A chunk still running has its old job cancelled.
+    pub fn dispatch(&mut self, center: &ChunkPosition, limit: usize) -> Vec<(ChunkPosition, JobTicket)>{
+        let free = self.max_running.saturating_sub(self.running.len()).min(limit);
+        if free == 0 || self.waiting.is_empty() { return Vec::new() }
+
+        let mut waiting: Vec<ChunkPosition> = self.waiting.iter().cloned().collect();
+        waiting.sort_by_key(|position| distance_squared(position, center));
+
+        waiting.into_iter().take(free).map(|position| {
+            self.waiting.remove(&position);
+            self.generation += 1;
+            let ticket = JobTicket{
+                generation: self.generation,
+                cancelled: Arc::new(AtomicBool::new(false))
+            };
+            if let Some(old) = self.running.insert(position, ticket.clone()){
+                old.cancel();
+            }
+            (position, ticket)
+        }).collect()
+    }
+
+    /// Marks a job as done, returning whether it's the chunk's latest job and its
+    /// result should be used
+    pub fn finish(&mut self, position: &ChunkPosition, generation: u64) -> bool{
+        match self.running.get(position){
+            Some(ticket) if ticket.generation == generation => {
+                self.running.remove(position);
+                true
+            },
+            _ => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn position(x: isize) -> ChunkPosition{
+        ChunkPosition::new(x, 0, 0)
+    }
+
+    #[test]
+    fn requests_are_merged(){
+        let mut queue = JobQueue::new(4);
+        queue.request(position(1));
+        queue.request(position(1));
+        queue.request(position(2));
+        assert_eq!(queue.pending(), 2);
+        assert!(queue.is_pending(&position(1)));
+        assert!(!queue.is_pending(&position(3)));
+
+        let started = queue.dispatch(&position(0), usize::MAX);
+        assert_eq!(started.len(), 2);
+        // a request for a running chunk waits, without counting it twice
+        queue.request(position(1));
+        assert_eq!(queue.pending(), 2);
+    }
+
+    #[test]
+    fn nearest_first_within_limits(){
+        let mut queue = JobQueue::new(2);
+        for x in &[5, -1, 3, 8]{
+            queue.request(position(*x));
+        }
+
+        let center = position(0);
+        let started: Vec<ChunkPosition> = queue.dispatch(&center, usize::MAX).into_iter().map(|(position, _)| position).collect();
+        assert_eq!(started, vec![position(-1), position(3)]);
+        // every slot is taken
+        assert!(queue.dispatch(&center, usize::MAX).is_empty());
+
+        let mut queue = JobQueue::new(4);
+        for x in &[5, -1, 3, 8]{
+            queue.request(position(*x));
+        }
+        assert_eq!(queue.dispatch(&position(6), 1)[0].0, position(5));
+        assert_eq!(queue.dispatch(&position(6), 0).len(), 0);
+        assert_eq!(queue.pending(), 4);
+    }
+
+    #[test]
+    fn finish_frees_slots(){
+        let mut queue = JobQueue::new(1);
+        queue.request(position(1));
+        queue.request(position(2));
+        let (first, ticket) = queue.dispatch(&position(0), usize::MAX).remove(0);
+        assert!(queue.dispatch(&position(0), usize::MAX).is_empty());
+
+        assert!(queue.finish(&first, ticket.generation));
+        assert!(!queue.is_pending(&first));
+        assert_eq!(queue.dispatch(&position(0), usize::MAX)[0].0, position(2));
+    }
+
+    #[test]
+    fn outdated_generations_are_rejected(){
+        let mut queue = JobQueue::new(2);
+        queue.request(position(1));
+        let (_, old) = queue.dispatch(&position(0), usize::MAX).remove(0);
+
+        // requested again while running, the new job replaces the old one
+        queue.request(position(1));
+        let (_, new) = queue.dispatch(&position(0), usize::MAX).remove(0);
+        assert!(new.generation > old.generation);
+        assert!(old.is_cancelled());
+        assert!(!new.is_cancelled());
+        assert!(!queue.finish(&position(1), old.generation));
+        assert!(queue.is_pending(&position(1)));
+        assert!(queue.finish(&position(1), new.generation));
+        // finished already
+        assert!(!queue.finish(&position(1), new.generation));
+    }
+
+    #[test]
+    fn cancelled_jobs(){
+        let mut queue = JobQueue::new(2);
+        queue.request(position(1));
+        queue.request(position(2));
+        let (_, running) = queue.dispatch(&position(0), 1).remove(0);
+
+        queue.cancel(&position(1));
+        queue.cancel(&position(2));
+        assert!(running.is_cancelled());
+        assert_eq!(queue.pending(), 0);
+        assert!(!queue.finish(&position(1), running.generation));
+        assert!(queue.dispatch(&position(0), usize::MAX).is_empty());
+
+        for x in 0..6{
+            queue.request(position(x));
+        }
+        let started = queue.dispatch(&position(0), usize::MAX);
+        queue.retain(|position| position.x < 1 || position.x > 3);
+        assert!(started.iter().all(|(position, ticket)| ticket.is_cancelled() == (position.x == 1)));
+        assert_eq!(queue.pending(), 3);
+    }
+}
@@ -0,0 +1,384 @@
+use super::chunk::{Chunk, ChunkPosition, BlockPosition, CHUNKSIZE, world_to_local};
+use super::manager::ChunkMap;
+use crate::game::registry::Registry;
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+pub const MAX_LIGHT: u8 = 15;
+
+const NEIGHBORS: [[isize; 3]; 6] = [[1, 0, 0], [-1, 0, 0], [0, 1, 0], [0, -1, 0], [0, 0, 1], [0, 0, -1]];
+const DOWN: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LightChannel{
+    /// Light coming from the sky, travels straight down without fading
+    Sky,
+    /// Light emitted by blocks
+    Block
+}
+
+pub const CHANNELS: [LightChannel; 2] = [LightChannel::Sky, LightChannel::Block];
+
+pub fn sky_light(light: u8) -> u8{
+    light >> 4
+}
+
+pub fn block_light(light: u8) -> u8{
+    light & 0x0F
+}
+
+fn channel_light(light: u8, channel: LightChannel) -> u8{
+    match channel{
+        LightChannel::Sky => sky_light(light),
+        LightChannel::Block => block_light(light),
+    }
+}
+
+fn with_channel(light: u8, channel: LightChannel, level: u8) -> u8{
+    match channel{
+        LightChannel::Sky => (light & 0x0F) | (level << 4),
+        LightChannel::Block => (light & 0xF0) | level,
+    }
+}
+
+fn offset(position: BlockPosition, direction: [isize; 3]) -> BlockPosition{
+    BlockPosition::new(position.x + direction[0], position.y + direction[1], position.z + direction[2])
+}
+
+/// Light a neighbour receives from a block with `level`, in the given direction
+fn spread(level: u8, channel: LightChannel, direction: usize) -> u8{
+    if channel == LightChannel::Sky && direction == DOWN && level == MAX_LIGHT{
+        MAX_LIGHT
+    }else{
+        level.saturating_sub(1)
+    }
+}
+
+/// Copy-on-write view of the loaded chunks, in world coordinates.
+///
+/// Touched chunks are cloned on first write and put back by `commit`, so meshers
+/// holding the old `Arc<Chunk>` aren't affected.
+pub struct LightWorld<'a>{
+    chunks: &'a ChunkMap,
+    registry: &'a Registry,
+    edited: HashMap<ChunkPosition, Chunk>
+}
+
+impl<'a> LightWorld<'a>{
+    pub fn new(chunks: &'a ChunkMap, registry: &'a Registry) -> Self{
+        Self{
+            chunks,
+            registry,
+            edited: HashMap::new()
+        }
+    }
+
+    fn read<T, F: Fn(&Chunk, [usize; 3]) -> T>(&self, position: BlockPosition, f: F) -> Option<T>{
+        let (chunk_position, local) = world_to_local(position);
+        if let Some(chunk) = self.edited.get(&chunk_position){
+            return Some(f(chunk, local));
+        }
+        self.chunks.get(&chunk_position).map(|chunk| f(chunk.value(), local))
+    }
+
+    pub fn get_block(&self, position: BlockPosition) -> Option<usize>{
+        self.read(position, |chunk, l| chunk.get_block(l[0], l[1], l[2]))
+    }
+
+    pub fn get_light(&self, position: BlockPosition, channel: LightChannel) -> Option<u8>{
+        self.read(position, |chunk, l| channel_light(chunk.get_light(l[0], l[1], l[2]), channel))
+    }
+
+    pub fn set_light(&mut self, position: BlockPosition, channel: LightChannel, level: u8){
+        let (chunk_position, local) = world_to_local(position);
+        if !self.edited.contains_key(&chunk_position){
+            let chunk = match self.chunks.get(&chunk_position){
+                Some(chunk) => Chunk::clone(chunk.value()),
+                None => return,
+            };
+            self.edited.insert(chunk_position, chunk);
+        }
+
+        let chunk = self.edited.get_mut(&chunk_position).expect("Edited chunk missing");
+        let light = chunk.get_light(local[0], local[1], local[2]);
+        chunk.set_light(local[0], local[1], local[2], with_channel(light, channel, level));
+    }
+
+    /// Whether light goes through this block, unloaded blocks stop it
+    fn is_transparent(&self, position: BlockPosition) -> bool{
+        match self.get_block(position){
+            Some(0) => true,
+            Some(block) => self.registry.block_registry().by_state(block).map(|data| data.is_transparent()).unwrap_or(false),
+            None => false,
+        }
+    }
+
+    fn emission(&self, position: BlockPosition) -> u8{
+        match self.get_block(position){
+            Some(block) => self.registry.block_registry().by_state(block).map(|data| data.emission()).unwrap_or(0),
+            None => 0,
+        }
+    }
+
+    /// Flood fills light outwards from every queued block
+    fn propagate(&mut self, channel: LightChannel, mut queue: VecDeque<BlockPosition>){
+        while let Some(position) = queue.pop_front(){
+            let level = match self.get_light(position, channel){
+                Some(level) if level > 1 => level,
+                _ => continue,
+            };
+
+            for (direction, neighbor) in NEIGHBORS.iter().enumerate(){
+                let neighbor = offset(position, *neighbor);
+                let next = spread(level, channel, direction);
+                if !self.is_transparent(neighbor) { continue }
+                if self.get_light(neighbor, channel).map(|current| current < next).unwrap_or(false){
+                    self.set_light(neighbor, channel, next);
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+    }
+
+    /// Removes the light that came from `start`, then refills the darkened area
+    /// from the brighter blocks around it
+    fn remove(&mut self, channel: LightChannel, start: BlockPosition){
+        let level = self.get_light(start, channel).unwrap_or(0);
+        if level == 0 { return }
+
+        let mut queue = VecDeque::new();
+        let mut refill = VecDeque::new();
+        self.set_light(start, channel, 0);
+        queue.push_back((start, level));
+
+        while let Some((position, level)) = queue.pop_front(){
+            for (direction, neighbor) in NEIGHBORS.iter().enumerate(){
+                let neighbor = offset(position, *neighbor);
+                let current = match self.get_light(neighbor, channel){
+                    Some(current) if current > 0 => current,
+                    _ => continue,
+                };
+
+                // the neighbour was lit by this block, so it goes dark too
+                if current < level || spread(level, channel, direction) == current{
+                    self.set_light(neighbor, channel, 0);
+                    queue.push_back((neighbor, current));
+                }else{
+                    refill.push_back(neighbor);
+                }
+            }
+        }
+
+        self.propagate(channel, refill);
+    }
+
+    /// Updates the light around a block that was just changed
+    pub fn update_block(&mut self, position: BlockPosition){
+        for channel in &CHANNELS{
+            let channel = *channel;
+            self.remove(channel, position);
+
+            let mut queue = VecDeque::new();
+            if channel == LightChannel::Block{
+                let emission = self.emission(position);
+                if emission > 0{
+                    self.set_light(position, channel, emission);
+                    queue.push_back(position);
+                }
+            }
+
+            // let the surrounding light flow into the block
+            if self.is_transparent(position){
+                for neighbor in &NEIGHBORS{
+                    queue.push_back(offset(position, *neighbor));
+                }
+            }
+
+            self.propagate(channel, queue);
+        }
+    }
+
+    /// Lights a chunk that was just loaded: sky light falls down each column,
+    /// emissive blocks light up and the neighbours' light flows in
+    pub fn light_chunk(&mut self, position: ChunkPosition){
+        let size = CHUNKSIZE as isize;
+        let origin = BlockPosition::new(position.x * size, position.y * size, position.z * size);
+        let above = ChunkPosition::new(position.x, position.y + 1, position.z);
+        let sky_above = !self.chunks.contains_key(&above);
+
+        let mut sky = VecDeque::new();
+        let mut block = VecDeque::new();
+        for x in 0..size{
+            for z in 0..size{
+                // with nothing loaded above, assume the column is open to the sky
+                let top = offset(origin, [x, size, z]);
+                let mut level = if sky_above { MAX_LIGHT } else { self.get_light(top, LightChannel::Sky).unwrap_or(0) };
+                if level > 1{
+                    sky.push_back(top);
+                }
+
+                for y in (0..size).rev(){
+                    let current = offset(origin, [x, y, z]);
+                    if level == MAX_LIGHT && self.is_transparent(current){
+                        self.set_light(current, LightChannel::Sky, MAX_LIGHT);
+                        sky.push_back(current);
+                    }else{
+                        level = 0;
+                    }
+
+                    let emission = self.emission(current);
+                    if emission > 0{
+                        self.set_light(current, LightChannel::Block, emission);
+                        block.push_back(current);
+                    }
+                }
+            }
+        }
+
+        // light already on the other side of the borders
+        for i in 0..size{
+            for j in 0..size{
+                let border = [
+                    [-1, i, j], [size, i, j],
+                    [i, -1, j], [i, size, j],
+                    [i, j, -1], [i, j, size]
+                ];
+                for b in &border{
+                    let neighbor = offset(origin, *b);
+                    sky.push_back(neighbor);
+                    block.push_back(neighbor);
+                }
+            }
+        }
+
+        self.propagate(LightChannel::Sky, sky);
+        self.propagate(LightChannel::Block, block);
+
+        // the chunk below may have assumed open sky where this chunk now blocks it
+        let below = ChunkPosition::new(position.x, position.y - 1, position.z);
+        if self.chunks.contains_key(&below){
+            for x in 0..size{
+                for z in 0..size{
+                    let bottom = offset(origin, [x, 0, z]);
+                    let under = offset(origin, [x, -1, z]);
+                    let blocked = self.get_light(bottom, LightChannel::Sky) != Some(MAX_LIGHT) || !self.is_transparent(bottom);
+                    if blocked && self.get_light(under, LightChannel::Sky) == Some(MAX_LIGHT){
+                        self.remove(LightChannel::Sky, under);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Writes the relit chunks back, returning their positions
+    pub fn commit(self) -> HashSet<ChunkPosition>{
+        let mut changed = HashSet::new();
+        for (position, mut chunk) in self.edited{
+            if self.chunks.contains_key(&position){
+                chunk.compact_light();
+                self.chunks.insert(position, Arc::new(chunk));
+                changed.insert(position);
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::game::game::create_registry;
+
+    /// Air chunks lit in the given order, the first ones open to the sky
+    fn air_chunks(registry: &Registry, positions: &[ChunkPosition]) -> ChunkMap{
+        let chunks = ChunkMap::default();
+        for position in positions{
+            chunks.insert(*position, Arc::new(Chunk::new(0)));
+        }
+        for position in positions{
+            let mut world = LightWorld::new(&chunks, registry);
+            world.light_chunk(*position);
+            world.commit();
+        }
+        chunks
+    }
+
+    /// Places a block and updates the light around it, like `TerrainManager::set_block`
+    fn set_block(chunks: &ChunkMap, registry: &Registry, position: BlockPosition, block: usize){
+        let (chunk_position, local) = world_to_local(position);
+        Arc::make_mut(chunks.get_mut(&chunk_position).unwrap().value_mut()).set_block(local[0], local[1], local[2], block);
+
+        let mut world = LightWorld::new(chunks, registry);
+        world.update_block(position);
+        world.commit();
+    }
+
+    fn light(chunks: &ChunkMap, registry: &Registry, position: BlockPosition, channel: LightChannel) -> u8{
+        LightWorld::new(chunks, registry).get_light(position, channel).unwrap()
+    }
+
+    fn state(registry: &Registry, name: &str) -> usize{
+        registry.block_registry().default_state(registry.block_registry().id_of(name).unwrap())
+    }
+
+    #[test]
+    fn sky_light_reaches_the_bottom(){
+        let registry = create_registry();
+        let chunks = air_chunks(&registry, &[ChunkPosition::new(0, 0, 0), ChunkPosition::new(0, -1, 0)]);
+
+        for y in -32..32{
+            assert_eq!(light(&chunks, &registry, BlockPosition::new(5, y, 5), LightChannel::Sky), MAX_LIGHT);
+        }
+        assert_eq!(light(&chunks, &registry, BlockPosition::new(0, -32, 31), LightChannel::Sky), MAX_LIGHT);
+    }
+
+    #[test]
+    fn shadows_cross_into_the_chunk_below(){
+        let registry = create_registry();
+        let stone = state(&registry, "stone");
+        let chunks = air_chunks(&registry, &[ChunkPosition::new(0, 0, 0), ChunkPosition::new(0, -1, 0)]);
+        let roof = BlockPosition::new(5, 2, 5);
+
+        set_block(&chunks, &registry, roof, stone);
+        assert_eq!(light(&chunks, &registry, roof, LightChannel::Sky), 0);
+        assert_eq!(light(&chunks, &registry, BlockPosition::new(5, 3, 5), LightChannel::Sky), MAX_LIGHT);
+        // lit from the sides only, one level less
+        for y in &[1, 0, -1, -20, -32]{
+            assert_eq!(light(&chunks, &registry, BlockPosition::new(5, *y, 5), LightChannel::Sky), MAX_LIGHT - 1, "at y {}", y);
+        }
+        assert_eq!(light(&chunks, &registry, BlockPosition::new(6, -20, 5), LightChannel::Sky), MAX_LIGHT);
+
+        set_block(&chunks, &registry, roof, 0);
+        for y in -32..32{
+            assert_eq!(light(&chunks, &registry, BlockPosition::new(5, y, 5), LightChannel::Sky), MAX_LIGHT, "at y {}", y);
+        }
+    }
+
+    #[test]
+    fn block_light_crosses_borders(){
+        let registry = create_registry();
+        let torch = state(&registry, "torch");
+        let chunks = air_chunks(&registry, &[ChunkPosition::new(0, 0, 0), ChunkPosition::new(1, 0, 0)]);
+        let source = BlockPosition::new(30, 10, 5);
+
+        set_block(&chunks, &registry, source, torch);
+        assert_eq!(light(&chunks, &registry, source, LightChannel::Block), 14);
+        for distance in 1..16{
+            let position = BlockPosition::new(30 + distance, 10, 5);
+            let expected = 14u8.saturating_sub(distance as u8);
+            assert_eq!(light(&chunks, &registry, position, LightChannel::Block), expected, "{} blocks away", distance);
+        }
+        // around a corner, by taxicab distance
+        assert_eq!(light(&chunks, &registry, BlockPosition::new(35, 13, 7), LightChannel::Block), 4);
+        // the sky light above the torch still reaches below it
+        assert_eq!(light(&chunks, &registry, BlockPosition::new(30, 9, 5), LightChannel::Sky), MAX_LIGHT);
+
+        set_block(&chunks, &registry, source, 0);
+        for distance in 0..16{
+            let position = BlockPosition::new(30 + distance, 10, 5);
+            assert_eq!(light(&chunks, &registry, position, LightChannel::Block), 0, "{} blocks away", distance);
+        }
+        assert_eq!(light(&chunks, &registry, BlockPosition::new(35, 13, 7), LightChannel::Block), 0);
+    }
+}
@@ -0,0 +1,378 @@
+use crate::game::registry::Registry;
+use crate::game::terrain::block::Direction;
+use super::chunk::{Chunk, ChunkPosition, CHUNKSIZE};
+use super::mesher::{add_quad, is_cube, is_visible, mesh_padded, ChunkMeshData, FaceKey, PaddedChunk};
+
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// Blocks per side of a cell at each level of detail, level 0 being full resolution
+pub const LOD_SCALES: [usize; 4] = [1, 2, 4, 8];
+
+/// Level of detail of a chunk: the amount of `distances` (in chunks, measured
+/// along the farthest axis) it's at or past
+pub fn select_lod(position: &ChunkPosition, center: &ChunkPosition, distances: &[isize; 3]) -> usize{
+    let distance = (position.x - center.x).abs().max((position.y - center.y).abs()).max((position.z - center.z).abs());
+    distances.iter().filter(|d| distance >= **d).count()
+}
+
+/// Sides of a chunk facing a neighbour at another level of detail, in `Direction` order.
+///
+/// Neighbours sharing only an edge or a corner are left out: faces are only ever
+/// culled against the chunks sharing them, so those can't open a crack.
+pub fn select_seams(position: &ChunkPosition, center: &ChunkPosition, distances: &[isize; 3]) -> [bool; 6]{
+    let lod = select_lod(position, center, distances);
+    let mut seams = [false; 6];
+    for (side, seam) in seams.iter_mut().enumerate(){
+        let normal = Direction::try_from(side).expect("Unknown direction").normal();
+        let neighbor = ChunkPosition::new(position.x + normal[0], position.y + normal[1], position.z + normal[2]);
+        *seam = select_lod(&neighbor, center, distances) != lod;
+    }
+    seams
+}
+
+/// Brightest sky and block light of two packed light values
+fn max_light(a: u8, b: u8) -> u8{
+    (a & 0xF0).max(b & 0xF0) | (a & 0x0F).max(b & 0x0F)
+}
+
+/// A chunk downsampled into cubic cells of `scale` blocks, with a one cell border.
+///
+/// Cells are solid when at least half their blocks are full cubes and take the most
+/// common block among the top of their columns, so distant grass stays grass.
+/// Border cells only come from the one block padding of the chunk and are solid
+/// when all the padding blocks they touch are.
+pub struct LodGrid{
+    scale: usize,
+    size: usize,
+    blocks: Vec<usize>,
+    light: Vec<u8>
+}
+
+impl LodGrid{
+    pub fn new(padded: &PaddedChunk, scale: usize, registry: &Registry) -> Self{
+        let size = CHUNKSIZE / scale;
+        let volume = (size + 2) * (size + 2) * (size + 2);
+        let mut grid = Self{
+            scale,
+            size,
+            blocks: vec![0; volume],
+            light: vec![0; volume]
+        };
+
+        let (n, s) = (size as isize, scale as isize);
+        for x in -1..=n{
+            for y in -1..=n{
+                for z in -1..=n{
+                    let cell = [x, y, z];
+                    let border = cell.iter().filter(|c| **c < 0 || **c >= n).count();
+                    // only border cells sharing a face with the chunk are ever looked at
+                    if border > 1 { continue }
+
+                    let range = |c: isize| -> Range<isize>{
+                        if c < 0 { -1..0 } else if c >= n { CHUNKSIZE as isize..CHUNKSIZE as isize + 1 } else { c * s..c * s + s }
+                    };
+                    let (block, light) = Self::sample(padded, [range(x), range(y), range(z)], border == 0, registry);
+                    let i = grid.index(cell);
+                    grid.blocks[i] = block;
+                    grid.light[i] = light;
+                }
+            }
+        }
+
+        grid
+    }
+
+    /// Block and light of the cell covering `ranges` of the padded chunk
+    fn sample(padded: &PaddedChunk, ranges: [Range<isize>; 3], inside: bool, registry: &Registry) -> (usize, u8){
+        let (mut solid, mut total, mut light) = (0, 0, 0);
+        let mut tops: Vec<(usize, usize)> = Vec::new();
+        for x in ranges[0].clone(){
+            for z in ranges[2].clone(){
+                let mut top = None;
+                for y in ranges[1].clone().rev(){
+                    let block = padded.block([x, y, z]);
+                    light = max_light(light, padded.light([x, y, z]));
+                    total += 1;
+                    if block != 0 && is_cube(block, registry){
+                        solid += 1;
+                        top = top.or(Some(block));
+                    }
+                }
+
+                if let Some(top) = top{
+                    match tops.iter_mut().find(|(block, _)| *block == top){
+                        Some((_, count)) => *count += 1,
+                        None => tops.push((top, 1)),
+                    }
+                }
+            }
+        }
+
+        let filled = if inside { solid * 2 >= total } else { solid == total };
+        let block = match tops.iter().max_by_key(|(_, count)| *count){
+            Some((block, _)) if filled => *block,
+            _ => 0
+        };
+        (block, light)
+    }
+
+    /// Index of a cell, from -1 to `size` on each axis
+    fn index(&self, cell: [isize; 3]) -> usize{
+        let size = self.size as isize + 2;
+        (((cell[0] + 1) * size + cell[1] + 1) * size + cell[2] + 1) as usize
+    }
+
+    pub fn scale(&self) -> usize{
+        self.scale
+    }
+
+    /// Cells per axis, without the border
+    pub fn size(&self) -> usize{
+        self.size
+    }
+
+    pub fn block(&self, cell: [isize; 3]) -> usize{
+        self.blocks[self.index(cell)]
+    }
+
+    pub fn light(&self, cell: [isize; 3]) -> u8{
+        self.light[self.index(cell)]
+    }
+}
+
+/// Greedy meshing over the cells of a downsampled chunk, without ambient occlusion
+/// nor any block that isn't a full cube
+pub fn mesh_grid(grid: &LodGrid, registry: &Registry) -> ChunkMeshData{
+    let mut mesh = ChunkMeshData::new();
+    let (size, scale) = (grid.size(), grid.scale() as isize);
+
+    for backface in &[false, true]{
+        for dim in 0..3{
+            let u = (dim + 1) % 3;
+            let v = (dim + 2) % 3;
+            let mut dir = [0, 0, 0];
+            dir[dim] = if !*backface {-1} else{1};
+            let direction = Direction::from_axis(dim, *backface);
+
+            let face = |cell: [isize; 3]| -> Option<FaceKey>{
+                let block = grid.block(cell);
+                if block == 0 { return None }
+                let front = [cell[0] + dir[0], cell[1] + dir[1], cell[2] + dir[2]];
+                if !is_visible(block, grid.block(front), direction, registry) { return None }
+
+                Some((block, grid.light(front), [3; 4]))
+            };
+
+            let mut current = [0isize, 0, 0];
+            for layer in 0..size as isize{
+                current[dim] = layer;
+
+                let mut faces = vec![vec![None; size]; size];
+                for (d1, row) in faces.iter_mut().enumerate(){
+                    current[v] = d1 as isize;
+                    for (d2, cell) in row.iter_mut().enumerate(){
+                        current[u] = d2 as isize;
+                        *cell = face(current);
+                    }
+                }
+
+                let mut mask = vec![vec![false; size]; size];
+                for d1 in 0..size{
+                    for d2 in 0..size{
+                        let key = match faces[d1][d2]{
+                            Some(key) if !mask[d1][d2] => key,
+                            _ => continue
+                        };
+
+                        let mut w = 1;
+                        while d2 + w < size && faces[d1][d2 + w] == Some(key) && !mask[d1][d2 + w]{
+                            w += 1;
+                        }
+                        let mut h = 1;
+                        while d1 + h < size && (d2..d2 + w).all(|i| faces[d1 + h][i] == Some(key) && !mask[d1 + h][i]){
+                            h += 1;
+                        }
+
+                        for row in mask.iter_mut().skip(d1).take(h){
+                            for masked in row.iter_mut().skip(d2).take(w){
+                                *masked = true;
+                            }
+                        }
+
+                        // quads are placed from the cell's outermost block on the face's side
+                        let mut block = [0; 3];
+                        block[dim] = layer * scale + if *backface { scale - 1 } else { 0 };
+                        block[u] = d2 as isize * scale;
+                        block[v] = d1 as isize * scale;
+                        add_quad(&mut mesh, registry, dim, *backface, block, w * scale as usize, h * scale as usize, key);
+                    }
+                }
+            }
+        }
+    }
+
+    mesh
+}
+
+/// Meshes a chunk at a level of detail from `LOD_SCALES`.
+///
+/// `seams` are the sides facing a neighbour meshed at another level: faces on those
+/// sides are never culled, so the walls they leave cover the cracks between levels.
+pub fn mesh_lod(chunk: &Chunk, neighbors: &[Option<Arc<Chunk>>], registry: &Registry, lod: usize, seams: [bool; 6]) -> ChunkMeshData{
+    let mut padded = PaddedChunk::new(chunk, neighbors);
+    for (side, seam) in seams.iter().enumerate(){
+        if *seam{
+            padded.clear_side(Direction::try_from(side).expect("Unknown direction"));
+        }
+    }
+
+    match LOD_SCALES.get(lod){
+        Some(1) | None => mesh_padded(&padded, registry),
+        Some(scale) => mesh_grid(&LodGrid::new(&padded, *scale, registry), registry),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::game::game::create_registry;
+    use crate::game::terrain::block::RenderLayer;
+    use crate::game::terrain::chunk::NEIGHBOR_OFFSETS;
+
+    fn state(registry: &Registry, name: &str) -> usize{
+        let blocks = registry.block_registry();
+        blocks.default_state(blocks.id_of(name).unwrap())
+    }
+
+    #[test]
+    fn lod_thresholds(){
+        let center = ChunkPosition::new(3, -1, 7);
+        let distances = [2, 4, 8];
+        let lod = |x: isize, y: isize, z: isize| select_lod(&ChunkPosition::new(center.x + x, center.y + y, center.z + z), &center, &distances);
+        assert_eq!(lod(0, 0, 0), 0);
+        assert_eq!(lod(1, 1, -1), 0);
+        assert_eq!(lod(-2, 0, 0), 1);
+        assert_eq!(lod(1, -3, 2), 1);
+        assert_eq!(lod(0, 4, 0), 2);
+        assert_eq!(lod(7, 7, 7), 2);
+        assert_eq!(lod(0, 0, -8), 3);
+        assert_eq!(lod(100, 0, 0), 3);
+    }
+
+    #[test]
+    fn seams_between_levels(){
+        let center = ChunkPosition::new(0, 0, 0);
+        let distances = [2, 4, 8];
+        let seams = |x: isize, y: isize, z: isize| select_seams(&ChunkPosition::new(x, y, z), &center, &distances);
+        assert_eq!(seams(0, 0, 0), [false; 6]);
+        // only the east neighbour is farther
+        assert_eq!(seams(1, 0, 0), [true, false, false, false, false, false]);
+        assert_eq!(seams(2, 0, 0), [false, true, false, false, false, false]);
+        // a corner of the first ring, two of its sides face the next one
+        assert_eq!(seams(1, 1, 1), [true, false, true, false, true, false]);
+        assert_eq!(seams(3, 0, 0), [true, false, false, false, false, false]);
+    }
+
+    #[test]
+    fn edges_and_corners_are_not_seams(){
+        let center = ChunkPosition::new(0, 0, 0);
+        let distances = [3, 4, 5];
+        // its neighbours at (3, 3, 0) and (3, 3, 3) are a level further
+        assert_eq!(select_lod(&ChunkPosition::new(3, 3, 0), &center, &distances), 1);
+        assert_eq!(select_seams(&ChunkPosition::new(2, 2, 0), &center, &distances), [true, false, true, false, false, false]);
+        assert_eq!(select_seams(&ChunkPosition::new(2, 2, 2), &center, &distances), [true, false, true, false, true, false]);
+    }
+
+    #[test]
+    fn grid_solidity_and_top_block(){
+        let registry = create_registry();
+        let (stone, dirt, grass) = (state(&registry, "stone"), state(&registry, "dirt"), state(&registry, "grass"));
+        let mut chunk = Chunk::new(0);
+        // half of the first cell is solid, three blocks of the next one
+        for x in 0..2{
+            for z in 0..2{
+                chunk.set_block(x, 0, z, stone);
+            }
+        }
+        for (x, y, z) in [(2, 0, 0), (3, 0, 0), (2, 1, 1)].iter(){
+            chunk.set_block(*x, *y, *z, stone);
+        }
+        // a full cell topped with two grass blocks, a dirt one and a stone one
+        for x in 4..6{
+            for y in 0..2{
+                for z in 4..6{
+                    chunk.set_block(x, y, z, dirt);
+                }
+            }
+        }
+        chunk.set_block(4, 1, 4, grass);
+        chunk.set_block(5, 1, 4, grass);
+        chunk.set_block(4, 1, 5, stone);
+
+        let grid = LodGrid::new(&PaddedChunk::new(&chunk, &[]), 2, &registry);
+        assert_eq!(grid.size(), 16);
+        assert_eq!(grid.block([0, 0, 0]), stone);
+        assert_eq!(grid.block([1, 0, 0]), 0);
+        assert_eq!(grid.block([2, 0, 2]), grass);
+        assert_eq!(grid.block([5, 5, 5]), 0);
+        // the border of a missing neighbour is air
+        assert_eq!(grid.block([-1, 0, 0]), 0);
+    }
+
+    /// Faces on the chunk's east side, drawn against its east neighbour
+    fn east_faces(mesh: &ChunkMeshData) -> usize{
+        mesh.layer(RenderLayer::Opaque).vertices.iter()
+            .filter(|vertex| vertex.face & 0b111 == Direction::East as u32 && vertex.get_position()[0] == CHUNKSIZE as f32)
+            .count() / 4
+    }
+
+    /// Total area of the opaque quads
+    fn area(mesh: &ChunkMeshData) -> f32{
+        mesh.layer(RenderLayer::Opaque).vertices.chunks(4).map(|quad| {
+            let mut extents: Vec<f32> = (0..3).map(|axis| {
+                let values = quad.iter().map(|vertex| vertex.get_position()[axis]);
+                values.clone().fold(f32::MIN, f32::max) - values.fold(f32::MAX, f32::min)
+            }).collect();
+            extents.sort_by(|a, b| b.partial_cmp(a).unwrap());
+            extents[0] * extents[1]
+        }).sum()
+    }
+
+    #[test]
+    fn edge_neighbours_hide_no_faces(){
+        let registry = create_registry();
+        let stone = Arc::new(Chunk::new(state(&registry, "stone")));
+        let mut neighbors = vec![None; 26];
+        for offset in [[1, 1, 0], [1, 0, 1], [1, -1, -1]]{
+            neighbors[NEIGHBOR_OFFSETS.iter().position(|o| *o == offset).unwrap()] = Some(stone.clone());
+        }
+
+        for lod in 0..LOD_SCALES.len(){
+            let alone = mesh_lod(&stone, &[], &registry, lod, [false; 6]);
+            let mesh = mesh_lod(&stone, &neighbors, &registry, lod, [false; 6]);
+            // the edge neighbours change the occlusion, so quads may be split differently
+            assert_eq!(area(&mesh), area(&alone), "level {}", lod);
+            assert_eq!(area(&alone), 6. * (CHUNKSIZE * CHUNKSIZE) as f32);
+        }
+    }
+
+    #[test]
+    fn seam_sides_keep_their_faces(){
+        let registry = create_registry();
+        let stone = Arc::new(Chunk::new(state(&registry, "stone")));
+        let mut neighbors = vec![None; 6];
+        neighbors[Direction::East as usize] = Some(stone.clone());
+
+        for lod in 0..LOD_SCALES.len(){
+            assert_eq!(east_faces(&mesh_lod(&stone, &neighbors, &registry, lod, [false; 6])), 0, "level {}", lod);
+            let mut seams = [false; 6];
+            seams[Direction::East as usize] = true;
+            let mesh = mesh_lod(&stone, &neighbors, &registry, lod, seams);
+            assert!(east_faces(&mesh) > 0, "level {}", lod);
+            // nothing is drawn inside the chunk
+            assert!(mesh.layer(RenderLayer::Opaque).vertices.iter().all(|vertex| vertex.face & 0b111 != Direction::Top as u32 || vertex.get_position()[1] == CHUNKSIZE as f32));
+        }
+    }
+}
@@ -1,25 +1,44 @@
-use crate::game::terrain::block::Direction;
 use crate::game::registry::Registry;
+use crate::engine::renderer::{MeshHandle, Renderer};
 use crate::engine::Vertex;
-use crate::engine::mesh::{Mesh, MeshData};
-use super::chunk::{ChunkPosition, Chunk};
+use super::chunk::{ChunkPosition, Chunk, BlockPosition, NEIGHBOR_OFFSETS, world_to_local};
 use super::chunk::CHUNKSIZE;
+use super::region::RegionStorage;
+use super::spill::{SpillMap, source_bit};
+use super::generation::WorldGenerator;
+use super::generation::biome::Biome;
+use super::streaming::{StreamingConfig, StreamingStats, chunk_at, chunks_in_range, should_evict};
+use super::light::LightWorld;
+use super::mesher::ChunkMeshData;
+use super::vertex::ChunkVertex;
+use super::lod::{mesh_lod, select_lod, select_seams};
+use super::visibility::Connectivity;
+use super::jobs::{JobExecutor, JobQueue, JobTicket};
+use super::block::RenderLayer;
 
-use std::convert::TryFrom;
 use dashmap::{DashMap};
 use dashmap::mapref::one::Ref;
 use cgmath::Point3;
-use noise::{Fbm, NoiseFn, Seedable};
-use uvth::{ThreadPoolBuilder, ThreadPool};
+use uvth::ThreadPoolBuilder;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::mpsc;
 use std::sync::mpsc::{Sender, Receiver};
 
-pub fn range_map(s: f64, a: [f64; 2], b: [f64; 2]) -> f64{
-    b[0] + ((s - a[0])*(b[1] - b[0]))/(a[1] - a[0])
+/// Generates a chunk, queueing the blocks its features placed in other chunks
+fn generate(generator: &dyn WorldGenerator, spills: &SpillMap, position: ChunkPosition) -> Chunk{
+    let generated = generator.generate(position);
+    spills.queue(position, generated.pending);
+    generated.chunk
 }
 
-pub type MeshMessage = (ChunkPosition, MeshData);
+/// Sent by the workers once a chunk has been inserted, so the main thread can light it,
+/// along with the generation of the job that loaded it
+pub type ArrivalMessage = (ChunkPosition, u64);
+pub type MeshMessage = (ChunkPosition, u64, ChunkMeshData, Connectivity);
 pub struct ChunkMesher{
     sender: Sender<MeshMessage>,
     receiver: Receiver<MeshMessage>
@@ -36,249 +55,706 @@ impl ChunkMesher{
     }
 }
 
+/// What a chunk's mesh was built from, to tell when it's outdated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MeshDetail{
+    lod: usize,
+    seams: [bool; 6],
+    /// Neighbours that were loaded and lit, in `NEIGHBOR_OFFSETS` order. Missing ones
+    /// count as air, leaving faces along the border until they arrive.
+    neighbors: [bool; 26]
+}
+
+impl MeshDetail{
+    /// Whether a mesh built from `self` should be rebuilt now that the chunk has `current`.
+    ///
+    /// Neighbours unloaded since are ignored, the faces they left don't need to go.
+    fn is_outdated(&self, current: &MeshDetail) -> bool{
+        self.lod != current.lod || self.seams != current.seams ||
+            self.neighbors.iter().zip(current.neighbors.iter()).any(|(then, now)| *now && !*then)
+    }
+}
+
 pub type ChunkRef<'a> = Ref<'a, ChunkPosition, Arc<Chunk>>;
 pub type ChunkMap = DashMap<ChunkPosition, Arc<Chunk>>;
-pub type ChunkMeshMap = DashMap<ChunkPosition, Mesh>;
 pub struct TerrainManager{
     chunks: Arc<ChunkMap>,
     registry: Arc<Registry>,
-    threadpool: ThreadPool,
+    /// Runs the loading and meshing jobs
+    executor: Box<dyn JobExecutor>,
     mesher: ChunkMesher,
-    meshes: ChunkMeshMap,
-    noise: Arc<Fbm>
+    generator: Arc<dyn WorldGenerator>,
+    /// Feature blocks waiting for their chunk, saved with the world in `spill_path`
+    spills: Arc<SpillMap>,
+    spill_path: PathBuf,
+    storage: Arc<RegionStorage>,
+    arrivals: (Sender<ArrivalMessage>, Receiver<ArrivalMessage>),
+    lit: HashSet<ChunkPosition>,
+    /// What each chunk was last meshed with
+    details: HashMap<ChunkPosition, MeshDetail>,
+    /// Chunk the player was in on the last `update_streaming`
+    center: ChunkPosition,
+    /// Sides of each meshed chunk that see each other, for occlusion culling
+    connectivity: HashMap<ChunkPosition, Connectivity>,
+    /// Meshed chunks without any face, which have no mesh in the renderer
+    empty: HashSet<ChunkPosition>,
+    /// Chunks whose meshes are dropped from the renderer on the next `update_meshes`
+    removed: HashSet<ChunkPosition>,
+    /// Each chunk's meshes in the renderer, by render layer
+    meshes: HashMap<ChunkPosition, [Option<MeshHandle>; 3]>,
+    /// Vertices and indices of each chunk's meshes in the renderer
+    mesh_sizes: HashMap<ChunkPosition, (usize, usize)>,
+    modified: HashSet<ChunkPosition>,
+    dirty: HashSet<ChunkPosition>,
+    streaming: StreamingConfig,
+    loads: JobQueue,
+    meshing: JobQueue,
+    stats: StreamingStats
 }
 
 #[allow(dead_code)]
 impl TerrainManager{
-    pub fn new(registry: &Arc<Registry>) -> Self{
+    pub fn new(registry: &Arc<Registry>, world_path: &Path, generator: Arc<dyn WorldGenerator>) -> Self{
         let chunks = Arc::new(ChunkMap::default());
-        let meshes = ChunkMeshMap::default();
 
-        let threadpool = ThreadPoolBuilder::new()
+        // as many jobs of each kind as threads, so the nearest chunks never wait behind far ones
+        let threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4);
+        let executor = Box::new(ThreadPoolBuilder::new()
             .name("TerrainManager".to_string())
-            .build();
+            .num_threads(threads)
+            .build());
         let mesher = ChunkMesher::new();
 
-        let noise = Arc::new(Fbm::new().set_seed(10291302));
         let registry = registry.clone();
+        let spill_path = world_path.join("spills");
+        let spills = SpillMap::load(&spill_path).unwrap_or_else(|e| {
+            println!("Couldn't load the pending feature blocks: {}", e);
+            SpillMap::default()
+        });
+        let spills = Arc::new(spills);
+        let storage = Arc::new(RegionStorage::new(world_path).expect("Couldn't open the world directory"));
+        let arrivals = mpsc::channel();
+        let lit = HashSet::new();
+        let details = HashMap::new();
+        let center = ChunkPosition::new(0, 0, 0);
+        let connectivity = HashMap::new();
+        let empty = HashSet::new();
+        let removed = HashSet::new();
+        let meshes = HashMap::new();
+        let mesh_sizes = HashMap::new();
+        let modified = HashSet::new();
+        let dirty = HashSet::new();
+
+        let streaming = StreamingConfig::default();
+        let loads = JobQueue::new(threads);
+        let meshing = JobQueue::new(threads);
+        let stats = StreamingStats::default();
 
         Self{
             chunks,
-            threadpool,
+            executor,
             registry,
             mesher,
+            generator,
+            spills,
+            spill_path,
+            storage,
+            arrivals,
+            lit,
+            details,
+            center,
+            connectivity,
+            empty,
+            removed,
             meshes,
-            noise
+            mesh_sizes,
+            modified,
+            dirty,
+            streaming,
+            loads,
+            meshing,
+            stats
+        }
+    }
+
+    /// Requests every missing chunk around the player, nearest first, and evicts
+    /// the ones that moved out of the eviction radius
+    pub fn update_streaming(&mut self, player: Point3<f64>){
+        self.light_arrivals();
+        self.apply_pending_writes();
+        let center = chunk_at(player);
+        self.center = center;
+
+        let streaming = self.streaming;
+        self.loads.retain(|position| !should_evict(position, &center, &streaming));
+
+        let evicted: Vec<ChunkPosition> = self.chunks.iter()
+            .map(|c_ref| *c_ref.key())
+            .filter(|position| should_evict(position, &center, &self.streaming))
+            .collect();
+        for position in &evicted{
+            self.unload_chunk(position);
+        }
+
+        for position in chunks_in_range(&center, &self.streaming){
+            if !self.chunks.contains_key(&position) && !self.loads.is_pending(&position){
+                self.loads.request(position);
+            }
+        }
+
+        for (position, ticket) in self.loads.dispatch(&center, self.streaming.requests_per_tick){
+            self.load_chunk(position, ticket);
         }
     }
 
-    pub fn setup(&mut self, _display: &glium::Display){
-        let distance = 1;
-        for z in -distance..distance{
-            for x in -distance..distance{
-                self.generate_chunk(ChunkPosition::new(x, -1, z));
+    /// Lights the chunks the workers finished since the last tick.
+    ///
+    /// Chunks are only meshed once lit, and neighbours whose light changed are remeshed.
+    fn light_arrivals(&mut self){
+        let arrived: Vec<ArrivalMessage> = self.arrivals.1.try_iter().collect();
+        for (position, generation) in arrived{
+            if !self.loads.finish(&position, generation) || !self.chunks.contains_key(&position) { continue }
+
+            let mut world = LightWorld::new(&self.chunks, &self.registry);
+            world.light_chunk(position);
+            let changed = world.commit();
+
+            self.lit.insert(position);
+            for changed in changed{
+                if changed != position && self.lit.contains(&changed){
+                    self.dirty.insert(changed);
+                }
             }
         }
     }
 
-    pub fn update_meshes(&mut self, display: &glium::Display){
-        // self.pop_dirty();
+    /// Writes the queued feature blocks into the chunks that are now loaded.
+    ///
+    /// Like in generation, feature blocks only replace air. A chunk only receives the
+    /// spills of each neighbour once, so regenerating an unmodified neighbour doesn't
+    /// bring back blocks the player removed.
+    fn apply_pending_writes(&mut self){
+        if self.spills.is_empty() { return }
+        let ready: Vec<ChunkPosition> = self.spills.targets().into_iter()
+            .filter(|position| self.chunks.contains_key(position))
+            .collect();
+
+        for position in &ready{
+            for (source, writes) in self.spills.take(position){
+                let bit = source_bit(position, &source).expect("Spill from a chunk that isn't a neighbour");
+                let received = self.chunks.get(position).map(|chunk| chunk.received_spills()).unwrap_or(0);
+                if received & bit != 0 { continue }
+
+                for (block_position, block) in writes{
+                    if self.get_block(block_position) == Some(0){
+                        self.set_block(block_position, block);
+                    }
+                }
+                // saved even when no block was written, so the spills aren't applied again
+                if let Some(mut chunk) = self.chunks.get_mut(position){
+                    Arc::make_mut(chunk.value_mut()).set_received_spills(received | bit);
+                }
+                self.mark_modified(*position);
+            }
+        }
+    }
+
+    /// Removes a chunk and its mesh, saving it first if it was modified
+    fn unload_chunk(&mut self, position: &ChunkPosition){
+        if let Some((_, chunk)) = self.chunks.remove(position){
+            if self.modified.remove(position){
+                if let Err(e) = self.storage.save_chunk(position, &chunk){
+                    println!("Couldn't save chunk {:?}: {}", position, e);
+                }
+            }
+            self.stats.evicted += 1;
+        }
+        self.remove_mesh(position);
+        self.dirty.remove(position);
+        self.lit.remove(position);
+        self.details.remove(position);
+        self.connectivity.remove(position);
+        self.empty.remove(position);
+        self.loads.cancel(position);
+        self.meshing.cancel(position);
+    }
+
+    pub fn set_streaming_config(&mut self, config: StreamingConfig){
+        self.streaming = config;
+    }
+
+    /// Runs the jobs started from now on with `executor` instead of the thread pool
+    pub fn set_executor(&mut self, executor: Box<dyn JobExecutor>){
+        self.executor = executor;
+    }
+
+    pub fn streaming_stats(&self) -> StreamingStats{
+        StreamingStats{
+            loaded: self.chunks.len(),
+            pending: self.loads.pending(),
+            meshing: self.meshing.pending(),
+            empty: self.empty.len(),
+            evicted: self.stats.evicted,
+            spilled: self.spills.len(),
+            mesh_memory: self.mesh_memory(mem::size_of::<ChunkVertex>()),
+            unpacked_mesh_memory: self.mesh_memory(mem::size_of::<Vertex>())
+        }
+    }
+
+    /// Bytes of the uploaded meshes with vertices of `vertex_size` bytes
+    fn mesh_memory(&self, vertex_size: usize) -> usize{
+        self.mesh_sizes.values().map(|(vertices, indices)| vertices * vertex_size + indices * mem::size_of::<u32>()).sum()
+    }
+
+    /// Meshes the chunks that need it and hands the finished meshes to the renderer
+    pub fn update_meshes<R: Renderer>(&mut self, renderer: &mut R){
+        for position in self.removed.drain(){
+            self.mesh_sizes.remove(&position);
+            for mesh in self.meshes.remove(&position).iter().flatten().flatten(){
+                renderer.remove_mesh(*mesh);
+            }
+        }
+
+        self.pop_dirty();
+        // chunks are meshed once lit and their neighbours are in, then again whenever
+        // a neighbour arrives or the player moved far enough to change their level of
+        // detail or their neighbours'
         for c_ref in self.chunks.clone().iter(){
-            if !self.meshes.contains_key(c_ref.key()){
-                self.mesh(c_ref.key());
+            let position = c_ref.key();
+            if !self.lit.contains(position) || self.meshing.is_pending(position) || self.awaits_neighbors(position) { continue }
+
+            let outdated = match self.details.get(position){
+                Some(detail) => detail.is_outdated(&self.detail(position)),
+                None => true
+            };
+            if outdated{
+                self.meshing.request(*position);
             }
         }
 
+        for (position, ticket) in self.meshing.dispatch(&self.center, usize::MAX){
+            self.mesh(&position, ticket);
+        }
+
         let received: Vec<_> = self.mesher.receiver.try_iter().collect();
-        for (position, data) in &received{
-            let mesh = data.build(display);
-            self.meshes.insert(*position, mesh);
+        for (position, generation, data, connectivity) in &received{
+            // results of cancelled or outdated jobs are dropped
+            if !self.meshing.finish(position, *generation) { continue }
+
+            self.connectivity.insert(*position, *connectivity);
+            if data.is_empty(){
+                self.remove_mesh(position);
+                self.empty.insert(*position);
+                continue;
+            }
+            // the new meshes replace the old ones
+            let meshes = data.upload(renderer);
+            for mesh in self.meshes.insert(*position, meshes).iter().flatten().flatten(){
+                renderer.remove_mesh(*mesh);
+            }
+            self.mesh_sizes.insert(*position, (data.vertex_count(), data.index_count()));
+            self.empty.remove(position);
+            self.removed.remove(position);
         }
     }
 
+    /// Drops a chunk's meshes, on the next `update_meshes` since it's the one with the renderer
+    fn remove_mesh(&mut self, position: &ChunkPosition){
+        self.removed.insert(*position);
+    }
+
+    /// Re-queues meshing for every chunk edited since the last frame
+    fn pop_dirty(&mut self){
+        let lit = &self.lit;
+        let dirty: Vec<ChunkPosition> = self.dirty.drain().filter(|position| lit.contains(position)).collect();
+        for position in dirty{
+            self.meshing.request(position);
+        }
+    }
+
+    /// Block state at a world position, see `BlockRegistry::state`
+    pub fn get_block(&self, position: BlockPosition) -> Option<usize>{
+        let (chunk_position, local) = world_to_local(position);
+        self.chunks.get(&chunk_position).map(|chunk| chunk.get_block(local[0], local[1], local[2]))
+    }
+
+    /// Sets the block state at a world position, returning `false` if its chunk isn't loaded.
+    ///
+    /// The chunk is copied if a mesher still holds it, and both it and any neighbour
+    /// sharing the edited face are remeshed on the next `update_meshes`, along with
+    /// every chunk whose light changed.
+    pub fn set_block(&mut self, position: BlockPosition, block: usize) -> bool{
+        let (chunk_position, local) = world_to_local(position);
+        match self.chunks.get_mut(&chunk_position){
+            Some(mut chunk) => {
+                if chunk.get_block(local[0], local[1], local[2]) == block { return true }
+                Arc::make_mut(chunk.value_mut()).set_block(local[0], local[1], local[2], block);
+            },
+            None => return false,
+        }
+
+        self.mark_modified(chunk_position);
+        self.dirty.insert(chunk_position);
+
+        let mut world = LightWorld::new(&self.chunks, &self.registry);
+        world.update_block(position);
+        self.dirty.extend(world.commit());
+
+        // neighbours padded with the block, diagonal ones included for their ambient occlusion
+        for offset in &NEIGHBOR_OFFSETS{
+            let touches = (0..3).all(|axis| match offset[axis]{
+                -1 => local[axis] == 0,
+                1 => local[axis] == CHUNKSIZE - 1,
+                _ => true
+            });
+            if !touches { continue }
+
+            let neighbor = ChunkPosition::new(chunk_position.x + offset[0], chunk_position.y + offset[1], chunk_position.z + offset[2]);
+            if self.chunks.contains_key(&neighbor){
+                self.dirty.insert(neighbor);
+            }
+        }
+
+        true
+    }
+
     pub fn get_chunks(&self) -> &ChunkMap{
         &self.chunks
     }
 
-    pub fn get_meshes(&self) -> &ChunkMeshMap{
-        &self.meshes
+    /// Mesh of a chunk's render layer in the renderer, `None` if it has no faces there
+    pub fn chunk_mesh(&self, position: &ChunkPosition, layer: RenderLayer) -> Option<MeshHandle>{
+        self.meshes.get(position).and_then(|meshes| meshes[layer as usize])
     }
 
-    fn chunk_neighbors(&self, position: &ChunkPosition) -> [Option<ChunkRef>; 6]{
-        let east = self.chunks.get(&Point3::new(position.x+1, position.y, position.z));           // East
-        let west = self.chunks.get(&Point3::new(position.x-1, position.y, position.z));           // West
-        let top = self.chunks.get(&Point3::new(position.x, position.y + 1, position.z));            // Top
-        let bottom = self.chunks.get(&Point3::new(position.x, position.y - 1, position.z));         // Bottom
-        let north = self.chunks.get(&Point3::new(position.x, position.y, position.z + 1));      // North
-        let south = self.chunks.get(&Point3::new(position.x, position.y, position.z - 1));      // South
+    /// Chunks with a mesh in the renderer, in any layer
+    pub fn meshed_chunks(&self) -> impl Iterator<Item = &ChunkPosition>{
+        // every meshed chunk has its connectivity, the empty ones have no mesh
+        self.connectivity.keys().filter(move |position| !self.empty.contains(position))
+    }
 
-        [east, west, top, bottom, north, south]
+    /// Sides of a chunk that see each other, `None` past the eviction radius.
+    ///
+    /// Chunks that aren't loaded or meshed yet count as open, so they don't hide
+    /// what's behind them.
+    pub fn connectivity(&self, position: &ChunkPosition) -> Option<Connectivity>{
+        if should_evict(position, &self.center, &self.streaming) { return None }
+        Some(self.connectivity.get(position).cloned().unwrap_or(Connectivity::OPEN))
     }
 
-    fn generate_chunk(&mut self, position: ChunkPosition){
+    /// Biome of a world column, if the generator has biomes
+    pub fn biome_at(&self, x: isize, z: isize) -> Option<Biome>{
+        self.generator.biome_at(x, z)
+    }
+
+    /// Total memory used by the loaded chunks' block data, in bytes
+    pub fn memory_usage(&self) -> usize{
+        self.chunks.iter().map(|c_ref| c_ref.value().memory_usage()).sum()
+    }
+
+    /// The 26 chunks around a chunk, in `NEIGHBOR_OFFSETS` order
+    fn chunk_neighbors(&self, position: &ChunkPosition) -> Vec<Option<ChunkRef>>{
+        Self::neighbor_positions(position).iter().map(|neighbor| self.chunks.get(neighbor)).collect()
+    }
+
+    /// Loads the chunk from the world's region files, generating it when it was never saved.
+    ///
+    /// A chunk already loaded by an older job is kept rather than replaced.
+    fn load_chunk(&mut self, position: ChunkPosition, ticket: JobTicket){
         let chunks = self.chunks.clone();
-        let noise = self.noise.clone();
-        let registry = self.registry.clone();
-        self.threadpool.execute(move ||{
-            let mut chunk = Chunk::new(0);
-            for z in 0..CHUNKSIZE{
-                for y in 0..CHUNKSIZE{
-                    for x in 0..CHUNKSIZE{
-                        let nx = (position.x * CHUNKSIZE as isize + x as isize) as f64;
-                        let ny = (position.y * CHUNKSIZE as isize + y as isize) as f64;
-                        let nz = (position.z * CHUNKSIZE as isize + z as isize) as f64;
-
-                        let height = range_map(2. * noise.get([nx*0.01, nz*0.01]), [-1., 1.], [0., CHUNKSIZE as f64 / 2.]).round();
-
-                        if ny == -height{
-                            let block = registry.block_registry().id_of("grass").unwrap_or(1);
-                            chunk.set_block(x, y, z, block);
-                        }else if ny == -(CHUNKSIZE as f64){
-                            let block = registry.block_registry().id_of("bedrock").unwrap_or(1);
-                            chunk.set_block(x, y, z, block);
-                        }else if ny < -height - 3.{
-                            let block = registry.block_registry().id_of("stone").unwrap_or(1);
-                            chunk.set_block(x, y, z, block);
-                        }else if ny < -height{
-                            let block = registry.block_registry().id_of("dirt").unwrap_or(1);
-                            chunk.set_block(x, y, z, block);
-                        }
-                    }
+        let storage = self.storage.clone();
+        let generator = self.generator.clone();
+        let spills = self.spills.clone();
+        let arrivals = self.arrivals.0.clone();
+        self.executor.execute(Box::new(move ||{
+            if ticket.is_cancelled() { return }
+            let chunk = match storage.load_chunk(&position){
+                Ok(Some(chunk)) => chunk,
+                Ok(None) => generate(&*generator, &spills, position),
+                Err(e) => {
+                    println!("Couldn't load chunk {:?}, regenerating it: {}", position, e);
+                    generate(&*generator, &spills, position)
                 }
+            };
+            if ticket.is_cancelled() { return }
+            chunks.entry(position).or_insert_with(|| Arc::new(chunk));
+            arrivals.send((position, ticket.generation)).expect("Couldn't send chunk arrival to main thread!");
+        }));
+    }
+
+    /// Flags a chunk so it gets written on the next `save`
+    pub fn mark_modified(&mut self, position: ChunkPosition){
+        self.modified.insert(position);
+    }
+
+    /// Writes every modified chunk to the world's region files along with the feature
+    /// blocks still waiting for their chunk, returning how many chunks were saved
+    pub fn save(&mut self) -> io::Result<usize>{
+        let mut saved = 0;
+        let positions: Vec<ChunkPosition> = self.modified.iter().cloned().collect();
+        for position in positions{
+            if let Some(chunk) = self.chunks.get(&position){
+                self.storage.save_chunk(&position, chunk.value())?;
+                saved += 1;
             }
+            self.modified.remove(&position);
+        }
+        self.spills.save(&self.spill_path)?;
 
-            chunks.insert(position, Arc::new(chunk));
-        });
+        Ok(saved)
     }
 
-    fn mesh(&mut self, position: &ChunkPosition){
-        if let Some(chunk) = self.chunks.get(position){
-            let sender = self.mesher.sender.clone();
-            let registry = self.registry.clone();
-
-            let chunk = chunk.value().clone();
-            let neighbors: Vec<Option<Arc<Chunk>>> = self.chunk_neighbors(position).iter().map(|n_ref| n_ref.as_ref().and_then(|inner| Some(Arc::clone(inner)))).collect();
-            let position = position.clone();
-
-            self.threadpool.execute(move ||{
-                let mut mesh = MeshData::new();
-
-                for backface in &[false, true]{
-                    for dim in 0..3{
-                        let u = (dim + 1) % 3;
-                        let v = (dim + 2) % 3;
-                        let mut dir = [0, 0, 0];
-                        dir[dim] = if !*backface {-1} else{1};
-
-                        let mut current = [0isize, 0, 0];
-                        // goes through each 'layer' of blocks in that dim
-                        for layer in 0..CHUNKSIZE as isize{
-                            let mut mask = [[false; CHUNKSIZE]; CHUNKSIZE];
-                            current[dim] = layer; //sets the current layer
-                            for d1 in 0..CHUNKSIZE as isize{
-                                current[v] = d1;
-                                for d2 in 0..CHUNKSIZE as isize{
-                                    current[u] = d2;
-                                    let current_block = chunk.check_block(current[0], current[1], current[2], neighbors.clone());
-
-                                    let (mut w, mut h) = (1, 1);
-                                    // if not masked already, not air and facing air
-                                    if !mask[d1 as usize][d2 as usize] && current_block != 0 && chunk.check_block(current[0]+dir[0], current[1]+dir[1], current[2]+dir[2], neighbors.clone()) == 0{
-                                        mask[d1 as usize][d2 as usize] = true;
-                                        let mut next = current;
-                                        next[u] += 1;
-                                        // if next block is equal current block, start increasing mesh size and not meshed already too...
-                                        if ((d2+1) as usize) < CHUNKSIZE{
-                                            if current_block == chunk.check_block(next[0], next[1], next[2], neighbors.clone()) && !mask[d1 as usize][(d2+1) as usize]{
-                                                w += 1;
-                                                mask[d1 as usize][(d2+1) as usize] = true;
-                                                for i in d2+2..CHUNKSIZE as isize{ // for each remaining block in the current row
-                                                    let mut next2 = next;
-                                                    next2[u] = i;
-                                                    if chunk.check_block(next2[0], next2[1], next2[2], neighbors.clone()) == current_block && !mask[d1 as usize][i as usize]{ w += 1; mask[d1 as usize][i as usize] = true; /*println!("mask: {:?}", mask)*/} else { break }
-                                                }
-                                            }
-                                        }
-
-                                        'row: for j in d1+1..CHUNKSIZE as isize{ // for each row in the remaining rows
-                                            let mut next2 = next;
-                                            next2[v] = j;
-                                            for i in d2..d2+w as isize{ // for each remaining block in the current row
-                                                next2[u] = i;
-                                                if chunk.check_block(next2[0], next2[1], next2[2], neighbors.clone()) != current_block || mask[i as usize][j as usize]{ break 'row }
-                                            }
-                                            for i in d2..d2+w as isize{
-                                                mask[j as usize][i as usize] = true;
-                                            }
-                                            h += 1;
-                                        }
-
-                                        let get_uv = |w, h|{[
-                                            [0.,        0.],
-                                            [w as f32,  0.],
-                                            [0.,        h as f32],
-                                            [w as f32,  h as f32]
-                                        ]};
-
-                                        let (w, h) = (w as f32, h as f32);
-                                        let (ix, uvs) = match dim{
-                                            0 => { // east or west
-                                                if *backface{([0, 2, 1, 3], get_uv(h, w))} else {([2, 0, 3, 1], get_uv(h, w))}
-                                            },
-                                            1 => { // up or down
-                                                if *backface{([0, 2, 1, 3], get_uv(h, w))} else {([2, 0, 3, 1], get_uv(h, w))} //3, 1, 2, 0
-                                            },
-                                            2 => { //north or south
-                                                if *backface{([1, 0, 3, 2], get_uv(w, h))} else {([0, 1, 2, 3], get_uv(w, h))}
-                                            },
-                                            _ => panic!("Unknown dimension")
-                                        };
-
-                                        let mut x = [current[0] as f32, current[1] as f32, current[2]  as f32];
-                                        if *backface { x[dim] += 1.; }
-                                        let mut du = [0., 0., 0.];
-                                        du[u] = w;
-                                        let mut dv = [0., 0., 0.];
-                                        dv[v] = h;
-
-                                        let block = if let Some(block_data) = registry.block_registry().by_id(current_block as usize) { block_data.get_face(Direction::try_from(u).unwrap_or(Direction::East)) } else{ [0, 1] };
-                                        // let block = if current_block == BlockType::Dirt{
-                                        //     [2, 15]
-                                        // }else if current_block == BlockType::Cobblestone{
-                                        //     [0, 14]
-                                        // }else{
-                                        //     [0, 0]
-                                        // };
-
-                                        let v = [
-                                            x,
-                                            [x[0] + du[0],         x[1] + du[1],         x[2] + du[2]],
-                                            [x[0] + dv[0],         x[1] + dv[1],         x[2] + dv[2]],
-                                            [x[0] + du[0] + dv[0], x[1] + du[1] + dv[1], x[2] + du[2] + dv[2]]
-                                        ];
-
-                                        let vertices = vec![
-                                            Vertex::new(v[ix[0]], uvs[0], block),
-                                            Vertex::new(v[ix[1]], uvs[1], block),
-                                            Vertex::new(v[ix[2]], uvs[2], block),
-                                            Vertex::new(v[ix[3]], uvs[3], block)
-                                        ];
-
-                                        let indices = vec![2, 3, 1, 1, 0, 2];
-                                        mesh.add(vertices, indices);
-                                    }
-
-                                }
-                            }
+    /// Positions of a chunk's neighbours, in `NEIGHBOR_OFFSETS` order
+    fn neighbor_positions(position: &ChunkPosition) -> [ChunkPosition; 26]{
+        let mut neighbors = [*position; 26];
+        for (offset, neighbor) in NEIGHBOR_OFFSETS.iter().zip(neighbors.iter_mut()){
+            *neighbor = ChunkPosition::new(position.x + offset[0], position.y + offset[1], position.z + offset[2]);
+        }
+        neighbors
+    }
+
+    /// Whether a neighbour of the chunk is being loaded or lit, meshing it now
+    /// would only have to be done again once it's in
+    fn awaits_neighbors(&self, position: &ChunkPosition) -> bool{
+        Self::neighbor_positions(position).iter().any(|neighbor| {
+            self.loads.is_pending(neighbor) || (self.chunks.contains_key(neighbor) && !self.lit.contains(neighbor))
+        })
+    }
 
+    /// What a chunk should be meshed with around the current center
+    fn detail(&self, position: &ChunkPosition) -> MeshDetail{
+        let distances = &self.streaming.lod_distances;
+        let mut neighbors = [false; 26];
+        for (present, neighbor) in neighbors.iter_mut().zip(Self::neighbor_positions(position).iter()){
+            *present = self.lit.contains(neighbor);
+        }
+
+        MeshDetail{
+            lod: select_lod(position, &self.center, distances),
+            seams: select_seams(position, &self.center, distances),
+            neighbors
+        }
+    }
+
+    /// Meshes a chunk on the thread pool, or right away when there's nothing to mesh
+    fn mesh(&mut self, position: &ChunkPosition, ticket: JobTicket){
+        let detail = self.detail(position);
+        self.details.insert(*position, detail);
+        let chunk = match self.chunks.get(position){
+            Some(chunk) => chunk.value().clone(),
+            None => {
+                self.meshing.finish(position, ticket.generation);
+                return;
+            }
+        };
+
+        // nothing to mesh in a chunk made only of air
+        if chunk.uniform_block() == Some(0){
+            self.meshing.finish(position, ticket.generation);
+            self.remove_mesh(position);
+            self.empty.insert(*position);
+            self.connectivity.insert(*position, Connectivity::OPEN);
+            return;
+        }
+
+        let sender = self.mesher.sender.clone();
+        let registry = self.registry.clone();
+        // neighbours that aren't lit yet are left out like missing ones, their light is wrong
+        let neighbors: Vec<Option<Arc<Chunk>>> = self.chunk_neighbors(position).iter()
+            .zip(detail.neighbors.iter())
+            .map(|(n_ref, lit)| n_ref.as_ref().filter(|_| *lit).map(|inner| Arc::clone(inner)))
+            .collect();
+        let position = position.clone();
+
+        self.executor.execute(Box::new(move ||{
+            if ticket.is_cancelled() { return }
+            let mesh = mesh_lod(&chunk, &neighbors, &registry, detail.lod, detail.seams);
+            let connectivity = Connectivity::of_chunk(&chunk, &registry);
+            // empty meshes are sent too, so a chunk emptied by an edit loses its old mesh
+            sender.send((position, ticket.generation, mesh, connectivity)).expect("Couldn't send chunk to main thread!");
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::game::game::create_registry;
+    use crate::engine::headless::HeadlessRenderer;
+    use crate::engine::mesh::MeshData;
+    use crate::game::terrain::block::RENDER_LAYERS;
+    use crate::game::terrain::generation::GeneratedChunk;
+    use crate::game::terrain::jobs::Inline;
+
+    /// Air everywhere, the spills are queued by hand
+    struct Empty;
+
+    impl WorldGenerator for Empty{
+        fn generate(&self, _position: ChunkPosition) -> GeneratedChunk{
+            GeneratedChunk{
+                chunk: Chunk::new(0),
+                pending: Vec::new()
+            }
+        }
+    }
+
+    #[test]
+    fn spills_are_received_once(){
+        let dir = tempfile::tempdir().unwrap();
+        let registry = Arc::new(create_registry());
+        let leaves = registry.block_registry().default_state(registry.block_registry().id_of("leaves").unwrap());
+        let mut terrain = TerrainManager::new(&registry, dir.path(), Arc::new(Empty));
+
+        let source = ChunkPosition::new(0, 0, 0);
+        let target = ChunkPosition::new(1, 0, 0);
+        let leaf = BlockPosition::new(32, 4, 4);
+        terrain.spills.queue(source, vec![(leaf, leaves)]);
+        terrain.chunks.insert(target, Arc::new(Chunk::new(0)));
+        terrain.apply_pending_writes();
+        assert_eq!(terrain.get_block(leaf), Some(leaves));
+
+        // dug out, then the unmodified source is regenerated and spills again
+        terrain.set_block(leaf, 0);
+        terrain.spills.queue(source, vec![(leaf, leaves)]);
+        terrain.apply_pending_writes();
+        assert_eq!(terrain.get_block(leaf), Some(0));
+        assert!(terrain.spills.is_empty());
+
+        // the target remembers it once saved and reloaded
+        terrain.unload_chunk(&target);
+        terrain.spills.queue(source, vec![(leaf, leaves)]);
+        let reloaded = terrain.storage.load_chunk(&target).unwrap().unwrap();
+        terrain.chunks.insert(target, Arc::new(reloaded));
+        terrain.apply_pending_writes();
+        assert_eq!(terrain.get_block(leaf), Some(0));
+
+        // other neighbours still spill into it
+        let above = ChunkPosition::new(1, 1, 0);
+        let branch = BlockPosition::new(33, 31, 4);
+        terrain.spills.queue(above, vec![(branch, leaves)]);
+        terrain.apply_pending_writes();
+        assert_eq!(terrain.get_block(branch), Some(leaves));
+    }
+
+    #[test]
+    fn pending_spills_are_saved(){
+        let dir = tempfile::tempdir().unwrap();
+        let registry = Arc::new(create_registry());
+        let leaf = BlockPosition::new(-1, 4, 4);
+        {
+            let mut terrain = TerrainManager::new(&registry, dir.path(), Arc::new(Empty));
+            terrain.spills.queue(ChunkPosition::new(0, 0, 0), vec![(leaf, 7)]);
+            terrain.save().unwrap();
+        }
+
+        let mut terrain = TerrainManager::new(&registry, dir.path(), Arc::new(Empty));
+        terrain.chunks.insert(ChunkPosition::new(-1, 0, 0), Arc::new(Chunk::new(0)));
+        terrain.apply_pending_writes();
+        assert_eq!(terrain.get_block(leaf), Some(7));
+    }
+
+    /// Rolling hills of stone riddled with holes, so every chunk border has faces
+    /// that depend on the chunk across it
+    struct Hills{
+        stone: usize
+    }
+
+    impl WorldGenerator for Hills{
+        fn generate(&self, position: ChunkPosition) -> GeneratedChunk{
+            let mut chunk = Chunk::new(0);
+            for x in 0..CHUNKSIZE{
+                for z in 0..CHUNKSIZE{
+                    let (world_x, world_z) = (position.x * CHUNKSIZE as isize + x as isize, position.z * CHUNKSIZE as isize + z as isize);
+                    let height = ((world_x as f64 * 0.2).sin() * 6. + (world_z as f64 * 0.15).cos() * 6.) as isize + 8;
+                    for y in 0..CHUNKSIZE{
+                        let world_y = position.y * CHUNKSIZE as isize + y as isize;
+                        if world_y < height && (world_x * 7 + world_y * 13 + world_z * 3).rem_euclid(11) != 0{
+                            chunk.set_block(x, y, z, self.stone);
                         }
                     }
                 }
-                if mesh.indices.len() != 0 {
-                    // println!("Sending!");
-                    sender.send((position, mesh)).expect("Couldn't send chunk to main thread!");
-                }
+            }
+            GeneratedChunk{
+                chunk,
+                pending: Vec::new()
+            }
+        }
+    }
+
+    /// Streams the world around each point of `path` in turn until every job is
+    /// done, then returns the meshes of the chunks around the origin by layer.
+    ///
+    /// Jobs run inline, so each tick finishes the jobs it started and nothing
+    /// depends on how the threads are scheduled.
+    fn stream_meshes(path: &[Point3<f64>], requests_per_tick: usize, lod_distances: [isize; 3]) -> HashMap<ChunkPosition, Vec<Option<MeshData<ChunkVertex>>>>{
+        let dir = tempfile::tempdir().unwrap();
+        let registry = Arc::new(create_registry());
+        let stone = registry.block_registry().default_state(registry.block_registry().id_of("stone").unwrap());
+        let mut renderer = HeadlessRenderer::new(1.);
+        let mut terrain = TerrainManager::new(&registry, dir.path(), Arc::new(Hills{ stone }));
+        terrain.set_executor(Box::new(Inline));
+        terrain.set_streaming_config(StreamingConfig{
+            horizontal: 2,
+            vertical: 1,
+            hysteresis: 1,
+            requests_per_tick,
+            lod_distances
+        });
+
+        for point in path{
+            let done = (0..1000).any(|_| {
+                terrain.update_streaming(*point);
+                terrain.update_meshes(&mut renderer);
+                let stats = terrain.streaming_stats();
+                stats.pending == 0 && stats.meshing == 0
             });
+            assert!(done, "still streaming around {:?}", point);
         }
+
+        let mut meshes = HashMap::new();
+        for x in -1..=1{
+            for z in -1..=1{
+                let position = ChunkPosition::new(x, 0, z);
+                let layers = RENDER_LAYERS.iter()
+                    .map(|layer| terrain.chunk_mesh(&position, *layer).and_then(|mesh| renderer.mesh_data::<ChunkVertex>(mesh)).cloned())
+                    .collect();
+                meshes.insert(position, layers);
+            }
+        }
+        meshes
+    }
+
+    #[test]
+    fn meshes_do_not_depend_on_load_order(){
+        let origin = Point3::new(0., 0., 0.);
+        // every chunk at full resolution, then past the first ring at lower ones
+        for lod_distances in [[10, 11, 12], [1, 2, 3]]{
+            // all at once, one chunk at a time, and arriving from two directions
+            let all = stream_meshes(&[origin], 100, lod_distances);
+            let one_by_one = stream_meshes(&[origin], 1, lod_distances);
+            let from_the_east = stream_meshes(&[Point3::new(32. * 3., 0., 32. * 2.), origin], 3, lod_distances);
+            let from_above = stream_meshes(&[Point3::new(-32. * 2., 32., 0.), origin], 2, lod_distances);
+
+            assert!(all.values().all(|layers| layers[RenderLayer::Opaque as usize].is_some()));
+            assert!(all == one_by_one, "meshes differ when chunks are loaded one at a time, LOD at {:?}", lod_distances);
+            assert!(all == from_the_east, "meshes differ when chunks arrive from the east, LOD at {:?}", lod_distances);
+            assert!(all == from_above, "meshes differ when chunks arrive from above, LOD at {:?}", lod_distances);
+        }
+    }
+
+    #[test]
+    fn farther_chunks_are_coarser(){
+        let origin = Point3::new(0., 0., 0.);
+        let full = stream_meshes(&[origin], 100, [10, 11, 12]);
+        let lod = stream_meshes(&[origin], 100, [1, 2, 3]);
+        let vertices = |meshes: &HashMap<ChunkPosition, Vec<Option<MeshData<ChunkVertex>>>>, position: ChunkPosition| {
+            meshes[&position].iter().flatten().map(|mesh| mesh.vertices.len()).sum::<usize>()
+        };
+
+        let center = ChunkPosition::new(0, 0, 0);
+        // at full resolution, with walls along the seams with its coarser neighbours
+        assert!(vertices(&lod, center) > vertices(&full, center));
+        let ring = ChunkPosition::new(1, 0, -1);
+        assert!(vertices(&lod, ring) < vertices(&full, ring));
     }
 }
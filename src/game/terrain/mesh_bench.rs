@@ -0,0 +1,196 @@
+use crate::game::game::create_registry;
+use crate::game::registry::Registry;
+use crate::game::terrain::block::Direction;
+use super::chunk::{Chunk, ChunkPosition, CHUNKSIZE, NEIGHBOR_OFFSETS};
+use super::generation::{DefaultGenerator, WorldGenerator};
+use super::light::LightWorld;
+use super::manager::ChunkMap;
+use super::mesher::{greedy_mesh, add_quad, face_ao, is_cube, is_opaque, is_visible, mesh_models, ChunkMeshData, FaceKey};
+
+use crate::engine::Vertex;
+use super::vertex::ChunkVertex;
+
+use std::mem;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Greedy mesher that probes the chunk and its neighbours for every block it looks
+/// at, cloning the neighbour list each time like the mesher did before padding.
+///
+/// It shares the face rules, ambient occlusion, quads and models with `greedy_mesh`,
+/// so comparing the two only checks the padded copy and the merging loop. Kept to
+/// measure the speed-up of padding.
+#[allow(clippy::unnecessary_to_owned)]
+pub fn unpadded_mesh(chunk: &Chunk, neighbors: &[Option<Arc<Chunk>>], registry: &Registry) -> ChunkMeshData{
+    let mut mesh = ChunkMeshData::new();
+
+    for backface in &[false, true]{
+        for dim in 0..3{
+            let u = (dim + 1) % 3;
+            let v = (dim + 2) % 3;
+            let mut dir = [0, 0, 0];
+            dir[dim] = if !*backface {-1} else{1};
+
+            let direction = Direction::from_axis(dim, *backface);
+            let check_block = |p: [isize; 3]| chunk.check_block(p[0], p[1], p[2], &neighbors.to_vec());
+            let solid = |p: [isize; 3]| is_opaque(check_block(p), registry);
+            let face = |position: [isize; 3]| -> Option<FaceKey>{
+                let block = check_block(position);
+                if block == 0 || !is_cube(block, registry) { return None }
+                let front = [position[0] + dir[0], position[1] + dir[1], position[2] + dir[2]];
+                if !is_visible(block, check_block(front), direction, registry) { return None }
+
+                Some((block, chunk.check_light(front[0], front[1], front[2], &neighbors.to_vec()), face_ao(front, u, v, solid)))
+            };
+
+            let mut current = [0isize, 0, 0];
+            for layer in 0..CHUNKSIZE as isize{
+                let mut mask = [[false; CHUNKSIZE]; CHUNKSIZE];
+                current[dim] = layer;
+                for d1 in 0..CHUNKSIZE as isize{
+                    current[v] = d1;
+                    for d2 in 0..CHUNKSIZE as isize{
+                        current[u] = d2;
+                        let current_face = face(current);
+
+                        let (mut w, mut h) = (1, 1);
+                        if let Some(key) = current_face.filter(|_| !mask[d1 as usize][d2 as usize]){
+                            mask[d1 as usize][d2 as usize] = true;
+                            let mut next = current;
+                            for i in d2+1..CHUNKSIZE as isize{
+                                next[u] = i;
+                                if face(next) == current_face && !mask[d1 as usize][i as usize]{ w += 1; mask[d1 as usize][i as usize] = true; } else { break }
+                            }
+
+                            'row: for j in d1+1..CHUNKSIZE as isize{
+                                let mut next2 = current;
+                                next2[v] = j;
+                                for i in d2..d2+w as isize{
+                                    next2[u] = i;
+                                    if face(next2) != current_face || mask[j as usize][i as usize]{ break 'row }
+                                }
+                                for i in d2..d2+w as isize{
+                                    mask[j as usize][i as usize] = true;
+                                }
+                                h += 1;
+                            }
+
+                            add_quad(&mut mesh, registry, dim, *backface, current, w as usize, h, key);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    mesh_models(&mut mesh, registry, |p| chunk.check_block(p[0], p[1], p[2], neighbors), |p| chunk.check_light(p[0], p[1], p[2], neighbors));
+    mesh
+}
+
+/// Generates and lights every chunk between `min` and `max` included
+fn lit_chunks(registry: &Registry, min: [isize; 3], max: [isize; 3]) -> ChunkMap{
+    let generator = DefaultGenerator::new(10291302, registry);
+    let chunks = ChunkMap::default();
+    for x in min[0]..=max[0]{
+        for y in min[1]..=max[1]{
+            for z in min[2]..=max[2]{
+                let position = ChunkPosition::new(x, y, z);
+                chunks.insert(position, Arc::new(generator.generate(position).chunk));
+                let mut world = LightWorld::new(&chunks, registry);
+                world.light_chunk(position);
+                world.commit();
+            }
+        }
+    }
+    chunks
+}
+
+/// The chunks around a position in `NEIGHBOR_OFFSETS` order
+fn neighbors_of(chunks: &ChunkMap, position: &ChunkPosition) -> Vec<Option<Arc<Chunk>>>{
+    NEIGHBOR_OFFSETS.iter()
+        .map(|o| chunks.get(&ChunkPosition::new(position.x + o[0], position.y + o[1], position.z + o[2])).map(|c_ref| c_ref.value().clone()))
+        .collect()
+}
+
+/// Generates and lights a few chunks, then meshes them with and without padding
+/// and prints the timings and mesh sizes
+///
+/// Run with `cargo run --release -- --bench-mesher`.
+pub fn run(){
+    let registry = create_registry();
+    // a ring of extra chunks around the meshed ones, so their borders are filled
+    let (radius, vertical) = (3, 2);
+    let chunks = lit_chunks(&registry, [-radius - 1, -vertical - 1, -radius - 1], [radius + 1, vertical, radius + 1]);
+
+    let (mut unpadded_time, mut padded_time) = (Duration::default(), Duration::default());
+    let (mut meshed, mut quads) = (0, 0);
+    let (mut packed_memory, mut full_memory) = (0, 0);
+    for x in -radius..=radius{
+        for y in -vertical..vertical{
+            for z in -radius..=radius{
+                let position = ChunkPosition::new(x, y, z);
+                let chunk = chunks.get(&position).expect("Missing generated chunk").value().clone();
+                let neighbors = neighbors_of(&chunks, &position);
+
+                let start = Instant::now();
+                let expected = unpadded_mesh(&chunk, &neighbors, &registry);
+                unpadded_time += start.elapsed();
+
+                let start = Instant::now();
+                let mesh = greedy_mesh(&chunk, &neighbors, &registry);
+                padded_time += start.elapsed();
+                packed_memory += mesh.memory_usage();
+                full_memory += mesh.vertex_count() * mem::size_of::<Vertex>() + mesh.index_count() * mem::size_of::<u32>();
+
+                // both meshes are used so neither is optimized out
+                quads += mesh.index_count().max(expected.index_count()) / 6;
+                meshed += 1;
+            }
+        }
+    }
+
+    println!("Meshed {} chunks ({} quads)", meshed, quads);
+    println!("Unpadded mesher:  {:?} ({:?} per chunk)", unpadded_time, unpadded_time / meshed);
+    println!("Padded mesher:    {:?} ({:?} per chunk)", padded_time, padded_time / meshed);
+    println!("Speed-up: {:.1}x", unpadded_time.as_secs_f64() / padded_time.as_secs_f64());
+    println!("Mesh memory with {}-byte vertices: {} bytes per chunk", mem::size_of::<Vertex>(), full_memory / meshed as usize);
+    println!("Mesh memory with {}-byte packed vertices: {} bytes per chunk", mem::size_of::<ChunkVertex>(), packed_memory / meshed as usize);
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::game::terrain::block::RENDER_LAYERS;
+
+    fn assert_same_mesh(expected: &ChunkMeshData, mesh: &ChunkMeshData, what: &str){
+        for layer in &RENDER_LAYERS{
+            let (a, b) = (expected.layer(*layer), mesh.layer(*layer));
+            assert!(a.vertices == b.vertices && a.indices == b.indices,
+                "{} differs in the {:?} layer: {} quads expected, got {}", what, layer, a.indices.len() / 6, b.indices.len() / 6);
+        }
+    }
+
+    /// Blocks and light read from the padded copy are the ones the chunk and its
+    /// neighbours hold, with any set of neighbours
+    #[test]
+    fn padding_matches_neighbour_lookups(){
+        let registry = create_registry();
+        let chunks = lit_chunks(&registry, [-1, -2, -1], [1, 1, 1]);
+
+        let mut quads = 0;
+        for y in -1..=0{
+            let position = ChunkPosition::new(0, y, 0);
+            let chunk = chunks.get(&position).unwrap().value().clone();
+            let all = neighbors_of(&chunks, &position);
+            // every other neighbour missing, and the edges and corners left out
+            let some: Vec<Option<Arc<Chunk>>> = all.iter().enumerate().map(|(i, n)| n.clone().filter(|_| i % 2 == 0)).collect();
+            for (name, neighbors) in &[("all", &all[..]), ("some", &some[..]), ("faces", &all[..6]), ("none", &[][..])]{
+                let expected = unpadded_mesh(&chunk, neighbors, &registry);
+                let mesh = greedy_mesh(&chunk, neighbors, &registry);
+                assert_same_mesh(&expected, &mesh, &format!("Chunk {:?} with {} neighbours", position, name));
+                quads += mesh.index_count() / 6;
+            }
+        }
+        assert!(quads > 0);
+    }
+}
@@ -0,0 +1,600 @@
+use crate::game::terrain::block::{Direction, RenderLayer, Rotation, RENDER_LAYERS};
+use crate::game::registry::Registry;
+use crate::engine::mesh::MeshData;
+use crate::engine::renderer::{MeshHandle, Renderer};
+use super::chunk::{Chunk, CHUNKSIZE};
+use super::state::StateBox;
+use super::vertex::ChunkVertex;
+
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// Block state, light in front of the face and ambient occlusion of its four corners.
+///
+/// Only faces with the same key are merged together.
+pub type FaceKey = (usize, u8, [u8; 4]);
+
+/// Triangles of a quad, split along the diagonal between its 2nd and 3rd vertex
+const INDICES: [u32; 6] = [2, 3, 1, 1, 0, 2];
+/// Same quad split along the other diagonal
+const FLIPPED_INDICES: [u32; 6] = [0, 2, 3, 3, 1, 0];
+/// Triangles of a model quad whose vertices go (-u, -v), (+u, -v), (-u, +v), (+u, +v)
+/// along its face's texture axes
+const MODEL_INDICES: [u32; 6] = [0, 1, 3, 3, 2, 0];
+/// Same quad seen from both sides, for crosses
+const DOUBLE_SIDED_INDICES: [u32; 12] = [0, 1, 3, 3, 2, 0, 0, 2, 3, 3, 1, 0];
+
+/// Mesh data of a chunk, split by render layer
+pub struct ChunkMeshData{
+    layers: [MeshData<ChunkVertex>; 3]
+}
+
+impl ChunkMeshData{
+    pub fn new() -> Self{
+        Self{
+            layers: [MeshData::new(), MeshData::new(), MeshData::new()]
+        }
+    }
+
+    pub fn layer(&self, layer: RenderLayer) -> &MeshData<ChunkVertex>{
+        &self.layers[layer as usize]
+    }
+
+    pub fn layer_mut(&mut self, layer: RenderLayer) -> &mut MeshData<ChunkVertex>{
+        &mut self.layers[layer as usize]
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.layers.iter().all(|data| data.indices.is_empty())
+    }
+
+    pub fn vertex_count(&self) -> usize{
+        self.layers.iter().map(|data| data.vertices.len()).sum()
+    }
+
+    pub fn index_count(&self) -> usize{
+        self.layers.iter().map(|data| data.indices.len()).sum()
+    }
+
+    /// Size of every layer's buffers once uploaded, in bytes
+    pub fn memory_usage(&self) -> usize{
+        self.layers.iter().map(|data| data.memory_usage()).sum()
+    }
+
+    /// Uploads every layer of the chunk to the renderer, returning their meshes
+    /// by render layer. Layers without faces are left out.
+    pub fn upload<R: Renderer>(&self, renderer: &mut R) -> [Option<MeshHandle>; 3]{
+        let mut meshes = [None; 3];
+        for layer in &RENDER_LAYERS{
+            meshes[*layer as usize] = renderer.upload_mesh(self.layer(*layer));
+        }
+        meshes
+    }
+}
+
+/// Occlusion of a face corner from the two blocks next to it and the one diagonal
+/// to it, from 0 (fully occluded) to 3 (open)
+pub fn vertex_ao(side1: bool, side2: bool, corner: bool) -> u8{
+    if side1 && side2 { return 0 }
+    3 - (side1 as u8 + side2 as u8 + corner as u8)
+}
+
+/// Blocks per axis of a padded chunk: the chunk plus a one block border
+pub const PADDED_SIZE: usize = CHUNKSIZE + 2;
+
+/// Copy of a chunk's blocks and light with a one block border taken from its
+/// neighbours, so meshing never has to look outside of it.
+///
+/// The border's edges and corners come from the diagonal neighbours, so ambient
+/// occlusion matches across chunk borders. Missing neighbours leave their part air.
+pub struct PaddedChunk{
+    blocks: Vec<usize>,
+    light: Vec<u8>
+}
+
+impl PaddedChunk{
+    pub fn new(chunk: &Chunk, neighbors: &[Option<Arc<Chunk>>]) -> Self{
+        let volume = PADDED_SIZE * PADDED_SIZE * PADDED_SIZE;
+        let mut padded = Self{
+            blocks: vec![0; volume],
+            light: vec![0; volume]
+        };
+
+        let size = CHUNKSIZE as isize;
+        for x in -1..=size{
+            for y in -1..=size{
+                for z in -1..=size{
+                    let i = Self::index([x, y, z]);
+                    padded.blocks[i] = chunk.check_block(x, y, z, neighbors);
+                    padded.light[i] = chunk.check_light(x, y, z, neighbors);
+                }
+            }
+        }
+
+        padded
+    }
+
+    /// Index of a position in chunk coordinates, from -1 to `CHUNKSIZE` on each axis
+    fn index(position: [isize; 3]) -> usize{
+        let size = PADDED_SIZE as isize;
+        (((position[0] + 1) * size + position[1] + 1) * size + position[2] + 1) as usize
+    }
+
+    pub fn block(&self, position: [isize; 3]) -> usize{
+        self.blocks[Self::index(position)]
+    }
+
+    pub fn light(&self, position: [isize; 3]) -> u8{
+        self.light[Self::index(position)]
+    }
+
+    /// Turns the border on one side into air, keeping its light, so no face on
+    /// that side is culled
+    pub fn clear_side(&mut self, direction: Direction){
+        let axis = direction as usize / 2;
+        let layer = if direction.normal()[axis] > 0 { CHUNKSIZE as isize } else { -1 };
+        for a in -1..=CHUNKSIZE as isize{
+            for b in -1..=CHUNKSIZE as isize{
+                let mut position = [0; 3];
+                position[axis] = layer;
+                position[(axis + 1) % 3] = a;
+                position[(axis + 2) % 3] = b;
+                self.blocks[Self::index(position)] = 0;
+            }
+        }
+    }
+}
+
+/// Whether a block's face pointing towards `direction` is drawn against its neighbour.
+///
+/// Only neighbours whose facing side is full can hide the face, and only when
+/// they're in the opaque layer or of the same kind, like water against water.
+pub fn is_visible(block: usize, neighbor: usize, direction: Direction, registry: &Registry) -> bool{
+    if neighbor == 0 { return true }
+    let blocks = registry.block_registry();
+    if !blocks.state(neighbor).map(|state| state.is_full(direction.opposite())).unwrap_or(true) { return true }
+    if blocks.block_of(neighbor) == blocks.block_of(block) { return false }
+    blocks.by_state(neighbor).map(|data| data.layer() != RenderLayer::Opaque).unwrap_or(false)
+}
+
+/// Full cubes are greedy meshed, unknown states are drawn as cubes too
+pub fn is_cube(block: usize, registry: &Registry) -> bool{
+    registry.block_registry().state(block).map(|state| state.is_cube()).unwrap_or(true)
+}
+
+pub fn is_opaque(block: usize, registry: &Registry) -> bool{
+    block != 0 && !registry.block_registry().by_state(block).map(|data| data.is_transparent()).unwrap_or(false)
+}
+
+/// Ambient occlusion of a face's corners, in the same order as the quad's vertices:
+/// (-u, -v), (+u, -v), (-u, +v), (+u, +v)
+pub fn face_ao<F: Fn([isize; 3]) -> bool>(front: [isize; 3], u: usize, v: usize, solid: F) -> [u8; 4]{
+    let mut ao = [0; 4];
+    for (corner, value) in ao.iter_mut().enumerate(){
+        let mut side1 = front;
+        side1[u] += if corner & 1 == 0 {-1} else {1};
+        let mut side2 = front;
+        side2[v] += if corner & 2 == 0 {-1} else {1};
+        let mut diagonal = side1;
+        diagonal[v] = side2[v];
+        *value = vertex_ao(solid(side1), solid(side2), solid(diagonal));
+    }
+    ao
+}
+
+/// Appends a `w` by `h` quad starting at the block `current`, facing `dim` (or
+/// the opposite way for backfaces), to the mesh of its block's layer
+#[allow(clippy::too_many_arguments)]
+pub fn add_quad(mesh: &mut ChunkMeshData, registry: &Registry, dim: usize, backface: bool, current: [isize; 3], w: usize, h: usize, key: FaceKey){
+    let (current_state, light, ao) = key;
+    let u = (dim + 1) % 3;
+    let v = (dim + 2) % 3;
+
+    let (w, h) = (w as f32, h as f32);
+    // vertex order picked so every quad faces outwards
+    let ix = match dim{
+        0 => { // east or west
+            if backface{[0, 2, 1, 3]} else {[2, 0, 3, 1]}
+        },
+        1 => { // up or down
+            if backface{[0, 2, 1, 3]} else {[2, 0, 3, 1]}
+        },
+        2 => { //north or south
+            if backface{[1, 0, 3, 2]} else {[0, 1, 2, 3]}
+        },
+        _ => panic!("Unknown dimension")
+    };
+
+    let mut x = [current[0] as f32, current[1] as f32, current[2]  as f32];
+    if backface { x[dim] += 1.; }
+    let mut du = [0., 0., 0.];
+    du[u] = w;
+    let mut dv = [0., 0., 0.];
+    dv[v] = h;
+
+    let direction = Direction::from_axis(dim, backface);
+    let blocks = registry.block_registry();
+    let (block, rotation, layer) = match (blocks.state(current_state), blocks.by_state(current_state)){
+        (Some(state), Some(block_data)) => (state.get_face(direction), state.get_rotation(direction), block_data.layer()),
+        _ => ([0, 1], Rotation::R0, RenderLayer::Opaque)
+    };
+
+    let v = [
+        x,
+        [x[0] + du[0],         x[1] + du[1],         x[2] + du[2]],
+        [x[0] + dv[0],         x[1] + dv[1],         x[2] + dv[2]],
+        [x[0] + du[0] + dv[0], x[1] + du[1] + dv[1], x[2] + du[2] + dv[2]]
+    ];
+
+    // the shader projects textures on the face's own axes, so they tile one per
+    // block and keep the same orientation whatever the quad's size
+    let vertex = |i: usize| ChunkVertex::new(v[ix[i]], direction, rotation, block, light, ao[ix[i]]);
+    let vertices = [vertex(0), vertex(1), vertex(2), vertex(3)];
+
+    // split the quad along the brighter diagonal, so the occlusion
+    // looks the same whatever the quad's orientation
+    let indices = if ao[ix[0]] + ao[ix[3]] > ao[ix[1]] + ao[ix[2]] { FLIPPED_INDICES } else { INDICES };
+    mesh.layer_mut(layer).add(&vertices, &indices);
+}
+
+/// Appends one face of a model box in the block at `position`
+fn add_box_face(mesh: &mut MeshData<ChunkVertex>, position: [isize; 3], state_box: &StateBox, direction: Direction, light: u8){
+    let (tu, tv) = direction.texture_axes();
+    let normal = direction.normal();
+    let corner = |su: f32, sv: f32| {
+        let mut p = [0.; 3];
+        for (axis, value) in p.iter_mut().enumerate(){
+            let side = normal[axis] as f32 + tu[axis] * su + tv[axis] * sv;
+            *value = position[axis] as f32 + if side > 0. { state_box.max[axis] } else { state_box.min[axis] };
+        }
+        p
+    };
+
+    let rotation = state_box.rotations[direction as usize];
+    let block = state_box.faces[direction as usize];
+    let vertex = |p: [f32; 3]| ChunkVertex::new(p, direction, rotation, block, light, 3);
+    let vertices = [vertex(corner(-1., -1.)), vertex(corner(1., -1.)), vertex(corner(-1., 1.)), vertex(corner(1., 1.))];
+    mesh.add(&vertices, &MODEL_INDICES);
+}
+
+/// Appends the two diagonal quads of a cross in the block at `position`.
+///
+/// They're textured like north faces, both going east from one corner to the other.
+fn add_cross(mesh: &mut MeshData<ChunkVertex>, position: [isize; 3], block: [u32; 2], light: u8){
+    let [x, y, z] = [position[0] as f32, position[1] as f32, position[2] as f32];
+    for (start, end) in &[([0., 0.], [1., 1.]), ([0., 1.], [1., 0.])]{
+        let vertex = |end: [f32; 2], v: f32| ChunkVertex::new([x + end[0], y + v, z + end[1]], Direction::North, Rotation::R0, block, light, 3);
+        let vertices = [vertex(*start, 0.), vertex(*end, 0.), vertex(*start, 1.), vertex(*end, 1.)];
+        mesh.add(&vertices, &DOUBLE_SIDED_INDICES);
+    }
+}
+
+/// Appends every block of the chunk that isn't a full cube, box by box.
+///
+/// `block` and `light` look up positions in chunk coordinates up to one block
+/// outside of it, box faces on the block's sides are culled like cube faces and
+/// take the light in front of them, the others take the block's own light.
+pub fn mesh_models<B, L>(mesh: &mut ChunkMeshData, registry: &Registry, block: B, light: L)
+where B: Fn([isize; 3]) -> usize, L: Fn([isize; 3]) -> u8{
+    let blocks = registry.block_registry();
+    let size = CHUNKSIZE as isize;
+    for x in 0..size{
+        for y in 0..size{
+            for z in 0..size{
+                let position = [x, y, z];
+                let current = block(position);
+                let state = match blocks.state(current){
+                    Some(state) if !state.is_cube() => state,
+                    _ => continue
+                };
+                let layer = blocks.by_state(current).map(|data| data.layer()).unwrap_or(RenderLayer::Opaque);
+                let own_light = light(position);
+
+                if state.is_cross(){
+                    add_cross(mesh.layer_mut(layer), position, state.get_face(Direction::North), own_light);
+                }
+
+                for state_box in state.boxes(){
+                    for i in 0..6{
+                        let direction = Direction::try_from(i).expect("Unknown direction");
+                        let normal = direction.normal();
+                        let front = [x + normal[0], y + normal[1], z + normal[2]];
+                        let face_light = if state_box.touches(direction){
+                            if !is_visible(current, block(front), direction, registry) { continue }
+                            light(front)
+                        }else{
+                            own_light
+                        };
+                        add_box_face(mesh.layer_mut(layer), position, state_box, direction, face_light);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Builds the mesh of a chunk, merging neighbouring faces into larger quads.
+///
+/// `neighbors` are the surrounding chunks in `NEIGHBOR_OFFSETS` order, missing
+/// neighbours count as air.
+pub fn greedy_mesh(chunk: &Chunk, neighbors: &[Option<Arc<Chunk>>], registry: &Registry) -> ChunkMeshData{
+    mesh_padded(&PaddedChunk::new(chunk, neighbors), registry)
+}
+
+/// Greedy meshing over an already padded chunk, nothing is allocated besides the mesh itself
+pub fn mesh_padded(padded: &PaddedChunk, registry: &Registry) -> ChunkMeshData{
+    let mut mesh = ChunkMeshData::new();
+
+    for backface in &[false, true]{
+        for dim in 0..3{
+            let u = (dim + 1) % 3;
+            let v = (dim + 2) % 3;
+            let mut dir = [0, 0, 0];
+            dir[dim] = if !*backface {-1} else{1};
+
+            let direction = Direction::from_axis(dim, *backface);
+            let solid = |p: [isize; 3]| is_opaque(padded.block(p), registry);
+            let face = |position: [isize; 3]| -> Option<FaceKey>{
+                let block = padded.block(position);
+                if block == 0 || !is_cube(block, registry) { return None }
+                let front = [position[0] + dir[0], position[1] + dir[1], position[2] + dir[2]];
+                if !is_visible(block, padded.block(front), direction, registry) { return None }
+
+                Some((block, padded.light(front), face_ao(front, u, v, solid)))
+            };
+
+            let mut current = [0isize, 0, 0];
+            // goes through each 'layer' of blocks in that dim
+            for layer in 0..CHUNKSIZE as isize{
+                current[dim] = layer; //sets the current layer
+
+                // every face of the layer, indexed by [v][u]
+                let mut faces = [[None; CHUNKSIZE]; CHUNKSIZE];
+                for (d1, row) in faces.iter_mut().enumerate(){
+                    current[v] = d1 as isize;
+                    for (d2, cell) in row.iter_mut().enumerate(){
+                        current[u] = d2 as isize;
+                        *cell = face(current);
+                    }
+                }
+
+                let mut mask = [[false; CHUNKSIZE]; CHUNKSIZE];
+                for d1 in 0..CHUNKSIZE{
+                    for d2 in 0..CHUNKSIZE{
+                        let key = match faces[d1][d2]{
+                            Some(key) if !mask[d1][d2] => key,
+                            _ => continue
+                        };
+
+                        // grow along u while the faces match, then add rows along v
+                        let mut w = 1;
+                        while d2 + w < CHUNKSIZE && faces[d1][d2 + w] == Some(key) && !mask[d1][d2 + w]{
+                            w += 1;
+                        }
+                        let mut h = 1;
+                        while d1 + h < CHUNKSIZE && (d2..d2 + w).all(|i| faces[d1 + h][i] == Some(key) && !mask[d1 + h][i]){
+                            h += 1;
+                        }
+
+                        for row in mask.iter_mut().skip(d1).take(h){
+                            for masked in row.iter_mut().skip(d2).take(w){
+                                *masked = true;
+                            }
+                        }
+
+                        current[u] = d2 as isize;
+                        current[v] = d1 as isize;
+                        add_quad(&mut mesh, registry, dim, *backface, current, w, h, key);
+                    }
+                }
+            }
+        }
+    }
+
+    mesh_models(&mut mesh, registry, |p| padded.block(p), |p| padded.light(p));
+    mesh
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::game::game::create_registry;
+    use crate::game::terrain::chunk::NEIGHBOR_OFFSETS;
+
+    fn stone(registry: &Registry) -> usize{
+        let blocks = registry.block_registry();
+        blocks.default_state(blocks.id_of("stone").unwrap())
+    }
+
+    fn state(registry: &Registry, name: &str) -> usize{
+        let blocks = registry.block_registry();
+        blocks.default_state(blocks.id_of(name).unwrap())
+    }
+
+    fn tile(registry: &Registry, state: usize, direction: Direction) -> [u32; 2]{
+        registry.block_registry().state(state).unwrap().get_face(direction)
+    }
+
+    /// Vertices of a layer facing `direction` with the given tile
+    fn faces(mesh: &ChunkMeshData, layer: RenderLayer, direction: Direction, tile: [u32; 2]) -> Vec<[f32; 3]>{
+        mesh.layer(layer).vertices.iter()
+            .filter(|vertex| vertex.face & 0b111 == direction as u32 && vertex.get_tile() == tile)
+            .map(|vertex| vertex.get_position())
+            .collect()
+    }
+
+    /// Ambient occlusion of the top faces' vertices at a position
+    fn top_ao(mesh: &ChunkMeshData, position: [f32; 3]) -> Vec<u8>{
+        mesh.layer(RenderLayer::Opaque).vertices.iter()
+            .filter(|vertex| vertex.face & 0b111 == Direction::Top as u32 && vertex.get_position() == position)
+            .map(|vertex| vertex.get_ao())
+            .collect()
+    }
+
+    #[test]
+    fn grass_face_tiles(){
+        let registry = create_registry();
+        let blocks = registry.block_registry();
+        let mut chunk = Chunk::new(0);
+        chunk.set_block(3, 4, 5, blocks.default_state(blocks.id_of("grass").unwrap()));
+
+        let mesh = greedy_mesh(&chunk, &[], &registry);
+        let vertices = &mesh.layer(RenderLayer::Opaque).vertices;
+        assert_eq!(vertices.len(), 24);
+        for i in 0..6{
+            let direction = Direction::try_from(i).unwrap();
+            let expected = match direction{
+                Direction::Top => [0, 15],
+                Direction::Bottom => [2, 15],
+                _ => [3, 15]
+            };
+            let tiles: Vec<[u32; 2]> = vertices.iter()
+                .filter(|vertex| vertex.face & 0b111 == i as u32)
+                .map(|vertex| vertex.get_tile())
+                .collect();
+            assert_eq!(tiles, vec![expected; 4], "{:?} face", direction);
+        }
+    }
+
+    #[test]
+    fn vertex_ao_levels(){
+        assert_eq!(vertex_ao(false, false, false), 3);
+        assert_eq!(vertex_ao(true, false, false), 2);
+        assert_eq!(vertex_ao(false, true, false), 2);
+        assert_eq!(vertex_ao(false, false, true), 2);
+        assert_eq!(vertex_ao(true, false, true), 1);
+        // both sides hide the corner whatever it is
+        assert_eq!(vertex_ao(true, true, false), 0);
+        assert_eq!(vertex_ao(true, true, true), 0);
+    }
+
+    #[test]
+    fn face_ao_corners(){
+        // a top face, u is z and v is x
+        let front = [5, 5, 5];
+        let ao = |solid: &[[isize; 3]]| face_ao(front, 2, 0, |p| solid.contains(&p));
+        assert_eq!(ao(&[]), [3, 3, 3, 3]);
+        assert_eq!(ao(&[[5, 5, 4]]), [2, 3, 2, 3]);
+        assert_eq!(ao(&[[6, 5, 6]]), [3, 3, 3, 2]);
+        assert_eq!(ao(&[[5, 5, 4], [4, 5, 5]]), [0, 2, 2, 3]);
+        // below the face's level, nothing occludes it
+        assert_eq!(ao(&[[5, 4, 4], [4, 4, 5]]), [3, 3, 3, 3]);
+    }
+
+    #[test]
+    fn ao_next_to_a_pillar(){
+        let registry = create_registry();
+        let stone = stone(&registry);
+        let mut chunk = Chunk::new(0);
+        for x in 0..CHUNKSIZE{
+            for z in 0..CHUNKSIZE{
+                chunk.set_block(x, 0, z, stone);
+            }
+        }
+        chunk.set_block(5, 1, 5, stone);
+
+        let mesh = greedy_mesh(&chunk, &[], &registry);
+        let near = top_ao(&mesh, [5., 1., 5.]);
+        assert!(!near.is_empty());
+        assert!(near.iter().all(|ao| *ao == 2), "{:?}", near);
+        assert_eq!(top_ao(&mesh, [6., 1., 6.]), vec![2, 2, 2]);
+        // the floor's corners are far from it
+        assert_eq!(top_ao(&mesh, [0., 1., 0.]), vec![3]);
+        assert_eq!(top_ao(&mesh, [32., 1., 32.]), vec![3]);
+    }
+
+    #[test]
+    fn ao_across_a_chunk_corner(){
+        let registry = create_registry();
+        let stone = stone(&registry);
+        let mut chunk = Chunk::new(0);
+        for x in 0..CHUNKSIZE{
+            for z in 0..CHUNKSIZE{
+                chunk.set_block(x, 0, z, stone);
+            }
+        }
+        // only in the chunk sharing the floor's +x +z edge
+        let mut diagonal = Chunk::new(0);
+        diagonal.set_block(0, 1, 0, stone);
+        let mut neighbors = vec![None; 26];
+        neighbors[NEIGHBOR_OFFSETS.iter().position(|o| *o == [1, 0, 1]).unwrap()] = Some(Arc::new(diagonal));
+
+        let padded = PaddedChunk::new(&chunk, &neighbors);
+        assert_eq!(padded.block([32, 1, 32]), stone);
+        assert_eq!(PaddedChunk::new(&chunk, &neighbors[..6]).block([32, 1, 32]), 0);
+
+        let corner = [32., 1., 32.];
+        assert_eq!(top_ao(&greedy_mesh(&chunk, &neighbors, &registry), corner), vec![2]);
+        assert_eq!(top_ao(&greedy_mesh(&chunk, &neighbors[..6], &registry), corner), vec![3]);
+    }
+
+    #[test]
+    fn cross_only_adds_its_quads(){
+        let registry = create_registry();
+        let flower = state(&registry, "flower");
+        let mut chunk = Chunk::new(0);
+        chunk.set_block(3, 4, 5, flower);
+
+        let mesh = greedy_mesh(&chunk, &[], &registry);
+        assert!(mesh.layer(RenderLayer::Opaque).vertices.is_empty());
+        assert!(mesh.layer(RenderLayer::Translucent).vertices.is_empty());
+        let cutout = mesh.layer(RenderLayer::Cutout);
+        // two diagonal quads, each drawn from both sides
+        assert_eq!(cutout.vertices.len(), 8);
+        assert_eq!(cutout.indices.len(), 24);
+        for vertex in &cutout.vertices{
+            assert_eq!(vertex.get_tile(), tile(&registry, flower, Direction::North));
+            let [x, y, z] = vertex.get_position();
+            assert!((3. ..=4.).contains(&x) && (4. ..=5.).contains(&y) && (5. ..=6.).contains(&z));
+        }
+    }
+
+    #[test]
+    fn slab_culls_only_its_full_side(){
+        let registry = create_registry();
+        let stone = stone(&registry);
+        let slab = state(&registry, "planks_slab");
+        let mut chunk = Chunk::new(0);
+        // stone under a slab's bottom, and over a slab's top
+        chunk.set_block(2, 2, 2, stone);
+        chunk.set_block(2, 3, 2, slab);
+        chunk.set_block(8, 3, 8, stone);
+        chunk.set_block(8, 2, 8, slab);
+
+        let mesh = greedy_mesh(&chunk, &[], &registry);
+        let (stone_tile, slab_tile) = (tile(&registry, stone, Direction::Top), tile(&registry, slab, Direction::Top));
+        let layer = RenderLayer::Opaque;
+        let at = |vertices: Vec<[f32; 3]>, x: f32| vertices.into_iter().filter(|p| p[0] == x || p[0] == x + 1.).collect::<Vec<_>>();
+
+        assert!(at(faces(&mesh, layer, Direction::Top, stone_tile), 2.).is_empty());
+        assert!(at(faces(&mesh, layer, Direction::Bottom, slab_tile), 2.).is_empty());
+        assert_eq!(at(faces(&mesh, layer, Direction::Bottom, stone_tile), 8.).len(), 4);
+        let slab_top = at(faces(&mesh, layer, Direction::Top, slab_tile), 8.);
+        assert_eq!(slab_top.len(), 4);
+        assert!(slab_top.iter().all(|p| p[1] == 2.5));
+        // the faces around them are all there
+        assert_eq!(at(faces(&mesh, layer, Direction::Bottom, stone_tile), 2.).len(), 4);
+        assert_eq!(at(faces(&mesh, layer, Direction::North, slab_tile), 2.).len(), 4);
+    }
+
+    #[test]
+    fn box_faces_use_their_textures(){
+        let registry = create_registry();
+        let torch = state(&registry, "torch");
+        let mut chunk = Chunk::new(0);
+        chunk.set_block(3, 4, 5, torch);
+
+        let mesh = greedy_mesh(&chunk, &[], &registry);
+        let vertices = &mesh.layer(RenderLayer::Opaque).vertices;
+        assert_eq!(vertices.len(), 24);
+        for i in 0..6{
+            let direction = Direction::try_from(i).unwrap();
+            let expected = if direction == Direction::Top {[9, 14]} else {[8, 14]};
+            assert_eq!(faces(&mesh, RenderLayer::Opaque, direction, expected).len(), 4, "{:?} face", direction);
+        }
+        for vertex in vertices{
+            let [x, y, z] = vertex.get_position();
+            assert!((3.4375..=3.5625).contains(&x) && (4. ..=4.625).contains(&y) && (5.4375..=5.5625).contains(&z));
+        }
+    }
+}
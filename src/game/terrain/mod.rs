@@ -1,3 +1,17 @@
 pub mod chunk;
 pub mod manager;
 pub mod block;
+pub mod state;
+pub mod model;
+pub mod palette;
+pub mod region;
+pub mod spill;
+pub mod streaming;
+pub mod generation;
+pub mod light;
+pub mod mesher;
+pub mod vertex;
+pub mod lod;
+pub mod visibility;
+pub mod jobs;
+pub mod mesh_bench;
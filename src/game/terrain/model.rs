@@ -0,0 +1,55 @@
+/// Axis-aligned box of a block model, in block units from 0 to 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModelBox{
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+    /// Texture of each face, `None` to use the block's faces
+    pub faces: Option<[[u32; 2]; 6]>
+}
+
+impl ModelBox{
+    pub fn new(min: [f32; 3], max: [f32; 3]) -> Self{
+        Self{
+            min,
+            max,
+            faces: None
+        }
+    }
+
+    pub fn with_faces(mut self, faces: [[u32; 2]; 6]) -> Self{
+        self.faces = Some(faces);
+        self
+    }
+}
+
+/// Shape of a block in its default orientation
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlockModel{
+    /// Full block, merged with its neighbours by the greedy mesher
+    Cube,
+    /// Two diagonal quads crossing in the middle of the block, like plants,
+    /// textured with the block's north face
+    Cross,
+    /// Lower half of a block
+    Slab,
+    /// Lower half of a block with a step on its north half
+    Stairs,
+    Boxes(Vec<ModelBox>)
+}
+
+impl BlockModel{
+    pub fn is_cube(&self) -> bool{
+        *self == BlockModel::Cube
+    }
+
+    /// Boxes making up the model, crosses have none
+    pub fn boxes(&self) -> Vec<ModelBox>{
+        match self{
+            BlockModel::Cube => vec![ModelBox::new([0., 0., 0.], [1., 1., 1.])],
+            BlockModel::Cross => Vec::new(),
+            BlockModel::Slab => vec![ModelBox::new([0., 0., 0.], [1., 0.5, 1.])],
+            BlockModel::Stairs => vec![ModelBox::new([0., 0., 0.], [1., 0.5, 1.]), ModelBox::new([0., 0.5, 0.5], [1., 1., 1.])],
+            BlockModel::Boxes(boxes) => boxes.clone(),
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::mem;
+
+/// Largest index width before a palette stops paying off compared to a plain array
+pub const MAX_PALETTE_BITS: usize = 8;
+
+/// Block storage using a local palette and bit-packed indices into it.
+///
+/// Indices never straddle two words, so `bits` is always a power of two (1, 2, 4 or 8).
+#[derive(Clone)]
+pub struct PalettedStorage{
+    palette: Vec<usize>,
+    counts: Vec<usize>,
+    bits: usize,
+    data: Vec<u64>,
+    len: usize
+}
+
+impl PalettedStorage{
+    /// Creates a storage of `len` entries all set to `value`
+    pub fn new(len: usize, value: usize) -> Self{
+        let bits = 1;
+        Self{
+            palette: vec![value],
+            counts: vec![len],
+            bits,
+            data: vec![0; Self::words_for(len, bits)],
+            len
+        }
+    }
+
+    /// Builds the smallest storage able to hold `values`, or `None` if more than
+    /// `2^MAX_PALETTE_BITS` distinct values are present.
+    pub fn from_values(values: &[usize]) -> Option<Self>{
+        let mut palette = Vec::new();
+        let mut counts = Vec::new();
+        let mut indices = Vec::with_capacity(values.len());
+        for value in values{
+            let index = match palette.iter().position(|v| v == value){
+                Some(index) => index,
+                None => {
+                    if palette.len() == 1 << MAX_PALETTE_BITS { return None }
+                    palette.push(*value);
+                    counts.push(0);
+                    palette.len() - 1
+                }
+            };
+            counts[index] += 1;
+            indices.push(index);
+        }
+
+        let bits = Self::bits_for(palette.len());
+        let mut storage = Self{
+            palette,
+            counts,
+            bits,
+            data: vec![0; Self::words_for(values.len(), bits)],
+            len: values.len()
+        };
+        for (i, index) in indices.into_iter().enumerate(){
+            storage.write_index(i, index);
+        }
+
+        Some(storage)
+    }
+
+    fn bits_for(palette_len: usize) -> usize{
+        let mut bits = 1;
+        while (1 << bits) < palette_len{
+            bits *= 2;
+        }
+        bits
+    }
+
+    fn words_for(len: usize, bits: usize) -> usize{
+        let per_word = 64 / bits;
+        len.div_ceil(per_word)
+    }
+
+    fn read_index(&self, i: usize) -> usize{
+        let per_word = 64 / self.bits;
+        let shift = (i % per_word) * self.bits;
+        let mask = (1u64 << self.bits) - 1;
+        ((self.data[i / per_word] >> shift) & mask) as usize
+    }
+
+    fn write_index(&mut self, i: usize, index: usize){
+        let per_word = 64 / self.bits;
+        let shift = (i % per_word) * self.bits;
+        let mask = (1u64 << self.bits) - 1;
+        let word = &mut self.data[i / per_word];
+        *word = (*word & !(mask << shift)) | ((index as u64 & mask) << shift);
+    }
+
+    /// Doubles the index width, repacking every entry
+    fn grow(&mut self){
+        let bits = self.bits * 2;
+        let mut grown = Self{
+            palette: Vec::new(),
+            counts: Vec::new(),
+            bits,
+            data: vec![0; Self::words_for(self.len, bits)],
+            len: self.len
+        };
+        for i in 0..self.len{
+            grown.write_index(i, self.read_index(i));
+        }
+        grown.palette = mem::take(&mut self.palette);
+        grown.counts = mem::take(&mut self.counts);
+        *self = grown;
+    }
+
+    pub fn get(&self, i: usize) -> usize{
+        self.palette[self.read_index(i)]
+    }
+
+    /// Sets the entry `i` to `value`.
+    ///
+    /// Returns `false` (leaving the storage untouched) when `value` would need an
+    /// index wider than `MAX_PALETTE_BITS`, meaning the caller should fall back
+    /// to a plain array.
+    pub fn set(&mut self, i: usize, value: usize) -> bool{
+        let old = self.read_index(i);
+        if self.palette[old] == value { return true }
+
+        let index = match self.palette.iter().position(|v| *v == value){
+            Some(index) => index,
+            None => {
+                // reuse a slot that's no longer referenced before growing the palette
+                if let Some(free) = self.counts.iter().position(|c| *c == 0){
+                    self.palette[free] = value;
+                    free
+                }else{
+                    if self.palette.len() == 1 << MAX_PALETTE_BITS { return false }
+                    if self.palette.len() == 1 << self.bits { self.grow() }
+                    self.palette.push(value);
+                    self.counts.push(0);
+                    self.palette.len() - 1
+                }
+            }
+        };
+
+        self.counts[old] -= 1;
+        self.counts[index] += 1;
+        self.write_index(i, index);
+        true
+    }
+
+    /// Returns the only value stored, if every entry is the same
+    pub fn single_value(&self) -> Option<usize>{
+        let mut used = self.counts.iter().enumerate().filter(|(_, c)| **c > 0);
+        match (used.next(), used.next()){
+            (Some((index, _)), None) => Some(self.palette[index]),
+            _ => None
+        }
+    }
+
+    pub fn to_values(&self) -> Vec<usize>{
+        (0..self.len).map(|i| self.get(i)).collect()
+    }
+
+    /// Heap memory owned by this storage, in bytes
+    pub fn heap_size(&self) -> usize{
+        self.palette.capacity() * mem::size_of::<usize>()
+            + self.counts.capacity() * mem::size_of::<usize>()
+            + self.data.capacity() * mem::size_of::<u64>()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const LEN: usize = 4096;
+
+    #[test]
+    fn grows_while_keeping_values(){
+        let mut storage = PalettedStorage::new(LEN, 1000);
+        assert_eq!(storage.bits, 1);
+        let mut expected = vec![1000; LEN];
+        for value in 1..256{
+            let i = value * 13;
+            assert!(storage.set(i, value));
+            expected[i] = value;
+            let distinct = value + 1;
+            let bits = match distinct{
+                0..=2 => 1,
+                3..=4 => 2,
+                5..=16 => 4,
+                _ => 8
+            };
+            assert_eq!(storage.bits, bits, "{} values", distinct);
+            assert_eq!(storage.to_values(), expected);
+        }
+        assert_eq!(storage.palette.len(), 256);
+    }
+
+    #[test]
+    fn refuses_past_the_largest_palette(){
+        let values: Vec<usize> = (0..LEN).map(|i| i % 256).collect();
+        let mut storage = PalettedStorage::from_values(&values).unwrap();
+        assert_eq!(storage.bits, MAX_PALETTE_BITS);
+        assert!(!storage.set(0, 1000));
+        assert_eq!(storage.to_values(), values);
+
+        let more: Vec<usize> = (0..LEN).map(|i| i % 257).collect();
+        assert!(PalettedStorage::from_values(&more).is_none());
+    }
+
+    #[test]
+    fn reuses_freed_slots(){
+        let mut storage = PalettedStorage::new(LEN, 0);
+        storage.set(1, 1);
+        storage.set(2, 2);
+        storage.set(3, 3);
+        assert_eq!(storage.bits, 2);
+        // nothing refers to 2 anymore, so 4 takes its place without growing
+        storage.set(2, 0);
+        storage.set(5, 4);
+        assert_eq!(storage.palette, vec![0, 1, 4, 3]);
+        assert_eq!(storage.bits, 2);
+        assert_eq!((storage.get(1), storage.get(2), storage.get(3), storage.get(5)), (1, 0, 3, 4));
+    }
+
+    #[test]
+    fn single_value_after_edits(){
+        let mut storage = PalettedStorage::new(LEN, 5);
+        assert_eq!(storage.single_value(), Some(5));
+        storage.set(10, 6);
+        assert_eq!(storage.single_value(), None);
+        storage.set(10, 5);
+        assert_eq!(storage.single_value(), Some(5));
+        // a freed slot left in the palette doesn't count
+        for i in 0..LEN{
+            storage.set(i, 6);
+        }
+        assert_eq!(storage.single_value(), Some(6));
+    }
+
+    #[test]
+    fn heap_size_per_width(){
+        for (distinct, bits) in [(1, 1), (2, 1), (3, 2), (16, 4), (17, 8), (256, 8)]{
+            let values: Vec<usize> = (0..LEN).map(|i| i % distinct).collect();
+            let storage = PalettedStorage::from_values(&values).unwrap();
+            assert_eq!(storage.bits, bits);
+            let data = LEN * bits / 8;
+            // the palette and its counts, give or take the vectors' spare capacity
+            let palette = |len: usize| 2 * len * mem::size_of::<usize>();
+            let range = data + palette(distinct)..=data + palette(distinct.next_power_of_two().max(4));
+            assert!(range.contains(&storage.heap_size()), "{} values", distinct);
+        }
+    }
+}
@@ -0,0 +1,475 @@
+use super::chunk::{Chunk, ChunkPosition, CHUNKSIZE};
+use super::spill::ALL_SOURCES;
+
+use cgmath::Point3;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Amount of chunks stored per axis in a single region file
+pub const REGIONSIZE: isize = 16;
+pub const REGION_CHUNKS: usize = (REGIONSIZE * REGIONSIZE * REGIONSIZE) as usize;
+
+const MAGIC: &[u8; 4] = b"VXRG";
+const VERSION: u32 = 1;
+/// Magic + version, followed by the offset table
+const HEADER_SIZE: u64 = 8;
+/// Each table entry holds the payload offset and length as two `u32`
+const TABLE_SIZE: u64 = REGION_CHUNKS as u64 * 8;
+
+pub type RegionPosition = Point3<isize>;
+
+pub fn region_of(position: &ChunkPosition) -> RegionPosition{
+    Point3::new(position.x.div_euclid(REGIONSIZE), position.y.div_euclid(REGIONSIZE), position.z.div_euclid(REGIONSIZE))
+}
+
+/// Index of the chunk inside its region's offset table
+fn local_index(position: &ChunkPosition) -> usize{
+    let x = position.x.rem_euclid(REGIONSIZE);
+    let y = position.y.rem_euclid(REGIONSIZE);
+    let z = position.z.rem_euclid(REGIONSIZE);
+    ((x * REGIONSIZE + y) * REGIONSIZE + z) as usize
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize){
+    while value >= 0x80{
+        out.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], cursor: &mut usize) -> io::Result<usize>{
+    let mut value = 0usize;
+    let mut shift = 0;
+    loop{
+        let byte = *data.get(*cursor).ok_or_else(|| invalid_data("Truncated chunk payload"))?;
+        *cursor += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 { return Ok(value) }
+        shift += 7;
+        if shift >= usize::BITS { return Err(invalid_data("Varint overflow")) }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Compresses the chunk as a varint of the spills it received, followed by its block
+/// states as run-length encoded `(length, state)` varint pairs
+pub fn encode_chunk(chunk: &Chunk) -> Vec<u8>{
+    let mut out = Vec::new();
+    write_varint(&mut out, chunk.received_spills() as usize);
+
+    let mut run: Option<(usize, usize)> = None;
+    for x in 0..CHUNKSIZE{
+        for y in 0..CHUNKSIZE{
+            for z in 0..CHUNKSIZE{
+                let block = chunk.get_block(x, y, z);
+                run = match run{
+                    Some((id, len)) if id == block => Some((id, len + 1)),
+                    Some((id, len)) => {
+                        write_varint(&mut out, len);
+                        write_varint(&mut out, id);
+                        Some((block, 1))
+                    },
+                    None => Some((block, 1))
+                };
+            }
+        }
+    }
+    if let Some((id, len)) = run{
+        write_varint(&mut out, len);
+        write_varint(&mut out, id);
+    }
+    out
+}
+
+pub fn decode_chunk(data: &[u8]) -> io::Result<Chunk>{
+    let mut cursor = 0;
+    let spills = read_varint(data, &mut cursor)?;
+    if spills & !(ALL_SOURCES as usize) != 0 { return Err(invalid_data("Unknown spill sources")) }
+
+    let mut chunk = Chunk::new(0);
+    chunk.set_received_spills(spills as u32);
+    let mut index = 0usize;
+    while cursor < data.len(){
+        let len = read_varint(data, &mut cursor)?;
+        let id = read_varint(data, &mut cursor)?;
+        let end = index.checked_add(len).ok_or_else(|| invalid_data("Chunk payload overflows the chunk"))?;
+        if end > CHUNKSIZE * CHUNKSIZE * CHUNKSIZE { return Err(invalid_data("Chunk payload overflows the chunk")) }
+        if id != 0{
+            for i in index..end{
+                chunk.set_block(i / (CHUNKSIZE * CHUNKSIZE), (i / CHUNKSIZE) % CHUNKSIZE, i % CHUNKSIZE, id);
+            }
+        }
+        index = end;
+    }
+    if index != CHUNKSIZE * CHUNKSIZE * CHUNKSIZE { return Err(invalid_data("Chunk payload is incomplete")) }
+
+    chunk.compact();
+    Ok(chunk)
+}
+
+/// Bytes of unused payloads a region file may hold before it's compacted, as long as
+/// they also outweigh the payloads in use
+const MAX_DEAD_SPACE: u64 = 64 * 1024;
+
+/// Magic, version and offset table of a region file
+fn encode_header(table: &[(u32, u32)]) -> Vec<u8>{
+    let mut header = Vec::with_capacity((HEADER_SIZE + TABLE_SIZE) as usize);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    for (offset, length) in table{
+        header.extend_from_slice(&offset.to_le_bytes());
+        header.extend_from_slice(&length.to_le_bytes());
+    }
+    header
+}
+
+/// A single region file: a header, an offset table with one `(offset, length)`
+/// entry per chunk and the compressed chunk payloads.
+///
+/// A rewritten chunk goes back in its old place when it fits and at the end of
+/// the file otherwise. Once the space left unused grows past `MAX_DEAD_SPACE`
+/// the file is rewritten with only the payloads in use.
+pub struct RegionFile{
+    path: PathBuf,
+    file: File,
+    table: Vec<(u32, u32)>,
+    /// Length of the file
+    end: u64,
+    /// Bytes of the payloads in use
+    live: u64
+}
+
+impl RegionFile{
+    pub fn open(path: &Path) -> io::Result<Self>{
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut table = vec![(0, 0); REGION_CHUNKS];
+
+        if file.metadata()?.len() == 0{
+            file.write_all(&encode_header(&table))?;
+        }else{
+            let mut header = vec![0u8; (HEADER_SIZE + TABLE_SIZE) as usize];
+            file.read_exact(&mut header)?;
+            if &header[0..4] != MAGIC { return Err(invalid_data("Not a region file")) }
+            let mut version = [0u8; 4];
+            version.copy_from_slice(&header[4..8]);
+            let version = u32::from_le_bytes(version);
+            if version != VERSION { return Err(invalid_data("Unsupported region file version")) }
+
+            for (i, entry) in header[HEADER_SIZE as usize..].chunks_exact(8).enumerate(){
+                let mut offset = [0u8; 4];
+                let mut length = [0u8; 4];
+                offset.copy_from_slice(&entry[0..4]);
+                length.copy_from_slice(&entry[4..8]);
+                table[i] = (u32::from_le_bytes(offset), u32::from_le_bytes(length));
+            }
+        }
+
+        let end = file.metadata()?.len();
+        let live = table.iter().map(|(_, length)| *length as u64).sum();
+        Ok(Self{
+            path: path.to_path_buf(),
+            file,
+            table,
+            end,
+            live
+        })
+    }
+
+    /// Bytes of the file that no chunk uses anymore
+    pub fn dead_space(&self) -> u64{
+        self.end.saturating_sub(HEADER_SIZE + TABLE_SIZE + self.live)
+    }
+
+    pub fn read_chunk(&mut self, position: &ChunkPosition) -> io::Result<Option<Chunk>>{
+        let (offset, length) = self.table[local_index(position)];
+        if length == 0 { return Ok(None) }
+
+        let mut data = vec![0u8; length as usize];
+        self.file.seek(SeekFrom::Start(offset as u64))?;
+        self.file.read_exact(&mut data)?;
+        decode_chunk(&data).map(Some)
+    }
+
+    pub fn write_chunk(&mut self, position: &ChunkPosition, chunk: &Chunk) -> io::Result<()>{
+        self.write_payload(local_index(position), &encode_chunk(chunk))?;
+        let dead = self.dead_space();
+        if dead > MAX_DEAD_SPACE && dead > self.live{
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    fn write_payload(&mut self, index: usize, data: &[u8]) -> io::Result<()>{
+        let (old_offset, old_length) = self.table[index];
+        let offset = if old_length > 0 && data.len() <= old_length as usize { old_offset as u64 } else { self.end };
+        if offset + data.len() as u64 > u32::MAX as u64 { return Err(io::Error::other("Region file is full")) }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)?;
+        self.end = self.end.max(offset + data.len() as u64);
+
+        let entry = (offset as u32, data.len() as u32);
+        let mut bytes = [0u8; 8];
+        bytes[0..4].copy_from_slice(&entry.0.to_le_bytes());
+        bytes[4..8].copy_from_slice(&entry.1.to_le_bytes());
+        self.file.seek(SeekFrom::Start(HEADER_SIZE + index as u64 * 8))?;
+        self.file.write_all(&bytes)?;
+        self.table[index] = entry;
+        self.live = self.live - old_length as u64 + data.len() as u64;
+
+        Ok(())
+    }
+
+    /// Rewrites the file with the payloads in use back to back.
+    ///
+    /// The new file is written next to the old one and replaces it once complete,
+    /// so a crash midway leaves the old file intact.
+    fn compact(&mut self) -> io::Result<()>{
+        let mut table = vec![(0, 0); REGION_CHUNKS];
+        let mut payloads = Vec::with_capacity(self.live as usize);
+        for (index, (offset, length)) in self.table.iter().enumerate(){
+            if *length == 0 { continue }
+            let mut data = vec![0u8; *length as usize];
+            self.file.seek(SeekFrom::Start(*offset as u64))?;
+            self.file.read_exact(&mut data)?;
+            table[index] = ((HEADER_SIZE + TABLE_SIZE + payloads.len() as u64) as u32, *length);
+            payloads.extend_from_slice(&data);
+        }
+
+        let compacted = self.path.with_extension("region.tmp");
+        let mut file = File::create(&compacted)?;
+        file.write_all(&encode_header(&table))?;
+        file.write_all(&payloads)?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&compacted, &self.path)?;
+
+        *self = Self::open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Saves and loads chunks to region files inside a world directory
+pub struct RegionStorage{
+    path: PathBuf,
+    regions: Mutex<HashMap<RegionPosition, RegionFile>>
+}
+
+impl RegionStorage{
+    pub fn new(path: &Path) -> io::Result<Self>{
+        fs::create_dir_all(path)?;
+
+        Ok(Self{
+            path: path.to_path_buf(),
+            regions: Mutex::new(HashMap::new())
+        })
+    }
+
+    fn region_path(&self, region: &RegionPosition) -> PathBuf{
+        self.path.join(format!("r.{}.{}.{}.region", region.x, region.y, region.z))
+    }
+
+    /// Loads a chunk, returning `None` if it was never saved
+    pub fn load_chunk(&self, position: &ChunkPosition) -> io::Result<Option<Chunk>>{
+        let region = region_of(position);
+        let mut regions = self.regions.lock().expect("Region lock poisoned");
+        let file = match regions.entry(region){
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let path = self.region_path(&region);
+                if !path.exists() { return Ok(None) }
+                entry.insert(RegionFile::open(&path)?)
+            }
+        };
+
+        file.read_chunk(position)
+    }
+
+    pub fn save_chunk(&self, position: &ChunkPosition, chunk: &Chunk) -> io::Result<()>{
+        let region = region_of(position);
+        let mut regions = self.regions.lock().expect("Region lock poisoned");
+        let file = match regions.entry(region){
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(RegionFile::open(&self.region_path(&region))?)
+        };
+
+        file.write_chunk(position, chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn sample_chunk() -> Chunk{
+        let mut chunk = Chunk::new(0);
+        for x in 0..CHUNKSIZE{
+            for y in 0..10{
+                for z in 0..CHUNKSIZE{
+                    chunk.set_block(x, y, z, (x + z) % 5 + 1000);
+                }
+            }
+        }
+        chunk
+    }
+
+    fn assert_same_blocks(a: &Chunk, b: &Chunk){
+        for x in 0..CHUNKSIZE{
+            for y in 0..CHUNKSIZE{
+                for z in 0..CHUNKSIZE{
+                    assert_eq!(a.get_block(x, y, z), b.get_block(x, y, z), "at {} {} {}", x, y, z);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn encode_roundtrip(){
+        let chunk = sample_chunk();
+        assert_same_blocks(&decode_chunk(&encode_chunk(&chunk)).unwrap(), &chunk);
+
+        let mut uniform = Chunk::new(7);
+        uniform.set_received_spills(0b101);
+        let data = encode_chunk(&uniform);
+        // the spills and a single run
+        assert_eq!(data.len(), 5);
+        let decoded = decode_chunk(&data).unwrap();
+        assert_same_blocks(&decoded, &uniform);
+        assert_eq!(decoded.received_spills(), 0b101);
+    }
+
+    #[test]
+    fn corrupt_payloads(){
+        let data = encode_chunk(&sample_chunk());
+        assert!(decode_chunk(&data[..data.len() - 1]).is_err());
+        assert!(decode_chunk(&data[..data.len() / 2]).is_err());
+
+        // a run longer than the chunk, and one whose length overflows when added
+        let mut long = vec![0];
+        write_varint(&mut long, CHUNKSIZE * CHUNKSIZE * CHUNKSIZE + 1);
+        write_varint(&mut long, 1);
+        assert!(decode_chunk(&long).is_err());
+
+        let mut overflow = vec![0];
+        write_varint(&mut overflow, 10);
+        write_varint(&mut overflow, 1);
+        write_varint(&mut overflow, usize::MAX);
+        write_varint(&mut overflow, 1);
+        assert_eq!(decode_chunk(&overflow).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+
+        let mut spills = Vec::new();
+        write_varint(&mut spills, 1 << 13);
+        spills.extend_from_slice(&data[1..]);
+        assert!(decode_chunk(&spills).is_err());
+    }
+
+    #[test]
+    fn storage_roundtrip(){
+        let dir = tempfile::tempdir().unwrap();
+        let mut chunk = sample_chunk();
+        chunk.set_received_spills(1 << 26);
+        let position = Point3::new(-1, -17, 5);
+        {
+            let storage = RegionStorage::new(dir.path()).unwrap();
+            storage.save_chunk(&position, &chunk).unwrap();
+            assert!(storage.load_chunk(&Point3::new(0, 0, 0)).unwrap().is_none());
+        }
+
+        // reopened from disk
+        let storage = RegionStorage::new(dir.path()).unwrap();
+        let loaded = storage.load_chunk(&position).unwrap().unwrap();
+        assert_same_blocks(&loaded, &chunk);
+        assert_eq!(loaded.received_spills(), 1 << 26);
+        // same region, never saved
+        assert!(storage.load_chunk(&Point3::new(-2, -17, 5)).unwrap().is_none());
+    }
+
+    #[test]
+    fn storage_overwrite(){
+        let dir = tempfile::tempdir().unwrap();
+        let position = Point3::new(3, 0, 3);
+        let storage = RegionStorage::new(dir.path()).unwrap();
+        storage.save_chunk(&position, &sample_chunk()).unwrap();
+        let replacement = Chunk::new(4);
+        storage.save_chunk(&position, &replacement).unwrap();
+        assert_same_blocks(&storage.load_chunk(&position).unwrap().unwrap(), &replacement);
+
+        let storage = RegionStorage::new(dir.path()).unwrap();
+        assert_same_blocks(&storage.load_chunk(&position).unwrap().unwrap(), &replacement);
+    }
+
+    #[test]
+    fn corrupt_region_file(){
+        let dir = tempfile::tempdir().unwrap();
+        let position = Point3::new(0, 0, 0);
+        RegionStorage::new(dir.path()).unwrap().save_chunk(&position, &sample_chunk()).unwrap();
+
+        // cut the payload short, the table still claims its full length
+        let path = dir.path().join("r.0.0.0.region");
+        let length = fs::metadata(&path).unwrap().len();
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(length - 3).unwrap();
+        assert!(RegionStorage::new(dir.path()).unwrap().load_chunk(&position).is_err());
+
+        fs::write(&path, b"nope").unwrap();
+        assert!(RegionStorage::new(dir.path()).unwrap().load_chunk(&position).is_err());
+    }
+
+    #[test]
+    fn rewrites_reuse_space(){
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("r.0.0.0.region");
+        let mut region = RegionFile::open(&path).unwrap();
+        let (first, second) = (Point3::new(1, 2, 3), Point3::new(3, 2, 1));
+        region.write_chunk(&first, &sample_chunk()).unwrap();
+        region.write_chunk(&second, &sample_chunk()).unwrap();
+        let length = fs::metadata(&path).unwrap().len();
+
+        // a smaller payload goes in the old one's place
+        region.write_chunk(&first, &Chunk::new(4)).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), length);
+        assert!(region.dead_space() > 0);
+        // and a larger one after the others
+        let mut larger = sample_chunk();
+        larger.set_block(5, 20, 5, 3);
+        region.write_chunk(&first, &larger).unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > length);
+
+        let mut region = RegionFile::open(&path).unwrap();
+        assert_same_blocks(&region.read_chunk(&first).unwrap().unwrap(), &larger);
+        assert_same_blocks(&region.read_chunk(&second).unwrap().unwrap(), &sample_chunk());
+    }
+
+    #[test]
+    fn growing_chunks_are_compacted(){
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("r.0.0.0.region");
+        let mut region = RegionFile::open(&path).unwrap();
+        let positions: Vec<ChunkPosition> = (0..4).map(|x| Point3::new(x, 0, 0)).collect();
+
+        // every save is larger than the last, so none fits in its old place
+        let mut chunk = Chunk::new(0);
+        for i in 0..400{
+            for y in 0..8{
+                chunk.set_block(i % CHUNKSIZE, y, i / CHUNKSIZE, i + y);
+            }
+            region.write_chunk(&positions[i % positions.len()], &chunk).unwrap();
+            assert!(region.dead_space() <= MAX_DEAD_SPACE.max(region.live), "after {} saves", i + 1);
+        }
+
+        let length = fs::metadata(&path).unwrap().len();
+        assert!(length <= HEADER_SIZE + TABLE_SIZE + 2 * MAX_DEAD_SPACE.max(region.live), "{} bytes", length);
+        assert!(!dir.path().join("r.0.0.0.region.tmp").exists());
+
+        let mut region = RegionFile::open(&path).unwrap();
+        assert_same_blocks(&region.read_chunk(&positions[3]).unwrap().unwrap(), &chunk);
+        assert!(region.read_chunk(&positions[0]).unwrap().is_some());
+        assert!(region.read_chunk(&Point3::new(0, 1, 0)).unwrap().is_none());
+    }
+}
@@ -0,0 +1,232 @@
+use super::chunk::{ChunkPosition, world_to_local};
+use super::generation::PendingWrite;
+
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Every bit of `source_bit`, anything else in a saved chunk is corrupt
+pub const ALL_SOURCES: u32 = ((1 << 27) - 1) & !(1 << 13);
+
+const MAGIC: &[u8; 4] = b"VXSP";
+const VERSION: u32 = 1;
+
+/// Bit of the chunk a spill came from in the target's `Chunk::received_spills`,
+/// `None` if it isn't one of its 26 neighbours
+pub fn source_bit(target: &ChunkPosition, source: &ChunkPosition) -> Option<u32>{
+    let offset = [source.x - target.x, source.y - target.y, source.z - target.z];
+    if offset.iter().any(|o| o.abs() > 1) || offset == [0, 0, 0] { return None }
+
+    Some(1 << ((offset[0] + 1) * 9 + (offset[1] + 1) * 3 + offset[2] + 1))
+}
+
+/// Feature blocks that spilled out of the chunk that generated them, waiting for
+/// their chunk to be loaded.
+///
+/// Writes are grouped by the chunk they came from, so regenerating an evicted chunk
+/// replaces its spills instead of queueing them twice. Features only reach into the
+/// neighbouring chunks, writes further away are dropped.
+#[derive(Default)]
+pub struct SpillMap{
+    targets: DashMap<ChunkPosition, HashMap<ChunkPosition, Vec<PendingWrite>>>
+}
+
+impl SpillMap{
+    /// Queues the blocks a chunk placed outside of itself
+    pub fn queue(&self, source: ChunkPosition, writes: Vec<PendingWrite>){
+        let mut grouped: HashMap<ChunkPosition, Vec<PendingWrite>> = HashMap::new();
+        for (position, block) in writes{
+            let (target, _) = world_to_local(position);
+            if source_bit(&target, &source).is_none() { continue }
+            grouped.entry(target).or_default().push((position, block));
+        }
+
+        for (target, writes) in grouped{
+            self.targets.entry(target).or_default().insert(source, writes);
+        }
+    }
+
+    /// Chunks with spills waiting for them
+    pub fn targets(&self) -> Vec<ChunkPosition>{
+        self.targets.iter().map(|entry| *entry.key()).collect()
+    }
+
+    /// Removes the spills waiting for a chunk, along with the chunk each came from
+    pub fn take(&self, target: &ChunkPosition) -> Vec<(ChunkPosition, Vec<PendingWrite>)>{
+        self.targets.remove(target).map(|(_, sources)| sources.into_iter().collect()).unwrap_or_default()
+    }
+
+    /// Amount of blocks waiting to be written
+    pub fn len(&self) -> usize{
+        self.targets.iter().map(|entry| entry.value().values().map(|writes| writes.len()).sum::<usize>()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.targets.is_empty()
+    }
+
+    /// Writes every waiting spill to a file, so they aren't lost with the chunks
+    /// that generated them
+    pub fn save(&self, path: &Path) -> io::Result<()>{
+        let mut out = Vec::new();
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        for entry in self.targets.iter(){
+            for (source, writes) in entry.value(){
+                for position in &[*entry.key(), *source]{
+                    write_position(&mut out, position);
+                }
+                out.extend_from_slice(&(writes.len() as u32).to_le_bytes());
+                for (position, block) in writes{
+                    write_position(&mut out, position);
+                    out.extend_from_slice(&(*block as u32).to_le_bytes());
+                }
+            }
+        }
+
+        // written aside first, a crash mid-write keeps the previous file
+        let temporary = path.with_extension("tmp");
+        fs::write(&temporary, out)?;
+        fs::rename(&temporary, path)
+    }
+
+    /// Reads the spills written by `save`, an empty map if there is no file yet
+    pub fn load(path: &Path) -> io::Result<Self>{
+        let map = Self::default();
+        if !path.exists() { return Ok(map) }
+
+        let data = fs::read(path)?;
+        if data.len() < 8 || &data[0..4] != MAGIC { return Err(invalid_data("Not a spill file")) }
+        let mut cursor = 4;
+        if read_u32(&data, &mut cursor)? != VERSION { return Err(invalid_data("Unsupported spill file version")) }
+
+        while cursor < data.len(){
+            let target = read_position(&data, &mut cursor)?;
+            let source = read_position(&data, &mut cursor)?;
+            if source_bit(&target, &source).is_none() { return Err(invalid_data("Spill from a chunk that isn't a neighbour")) }
+
+            let count = read_u32(&data, &mut cursor)? as usize;
+            let mut writes = Vec::new();
+            for _ in 0..count{
+                let position = read_position(&data, &mut cursor)?;
+                let block = read_u32(&data, &mut cursor)? as usize;
+                writes.push((position, block));
+            }
+            map.targets.entry(target).or_default().insert(source, writes);
+        }
+
+        Ok(map)
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error{
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn write_position(out: &mut Vec<u8>, position: &ChunkPosition){
+    for coordinate in &[position.x, position.y, position.z]{
+        out.extend_from_slice(&(*coordinate as i64).to_le_bytes());
+    }
+}
+
+fn read_bytes<'a>(data: &'a [u8], cursor: &mut usize, length: usize) -> io::Result<&'a [u8]>{
+    let bytes = data.get(*cursor..*cursor + length).ok_or_else(|| invalid_data("Truncated spill file"))?;
+    *cursor += length;
+    Ok(bytes)
+}
+
+fn read_u32(data: &[u8], cursor: &mut usize) -> io::Result<u32>{
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(read_bytes(data, cursor, 4)?);
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_position(data: &[u8], cursor: &mut usize) -> io::Result<ChunkPosition>{
+    let mut coordinates = [0isize; 3];
+    for coordinate in coordinates.iter_mut(){
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(read_bytes(data, cursor, 8)?);
+        *coordinate = isize::try_from(i64::from_le_bytes(bytes)).map_err(|_| invalid_data("Position out of range"))?;
+    }
+    Ok(ChunkPosition::new(coordinates[0], coordinates[1], coordinates[2]))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::game::terrain::chunk::BlockPosition;
+
+    #[test]
+    fn source_bits(){
+        let target = ChunkPosition::new(4, -2, 7);
+        let mut seen = 0;
+        for dx in -1..=1{
+            for dy in -1..=1{
+                for dz in -1..=1{
+                    let source = ChunkPosition::new(target.x + dx, target.y + dy, target.z + dz);
+                    match source_bit(&target, &source){
+                        Some(bit) => {
+                            assert_eq!(seen & bit, 0, "bit reused");
+                            seen |= bit;
+                        },
+                        None => assert_eq!((dx, dy, dz), (0, 0, 0)),
+                    }
+                }
+            }
+        }
+        assert_eq!(seen, ALL_SOURCES);
+        assert_eq!(source_bit(&target, &ChunkPosition::new(6, -2, 7)), None);
+    }
+
+    #[test]
+    fn requeueing_replaces_spills(){
+        let map = SpillMap::default();
+        let source = ChunkPosition::new(0, 0, 0);
+        let writes = vec![
+            (BlockPosition::new(32, 5, 5), 3),
+            (BlockPosition::new(33, 5, 5), 3),
+            (BlockPosition::new(-1, 40, 5), 4),
+            // two chunks away
+            (BlockPosition::new(70, 5, 5), 5)
+        ];
+        map.queue(source, writes.clone());
+        map.queue(source, writes);
+        map.queue(ChunkPosition::new(2, 0, 0), vec![(BlockPosition::new(63, 1, 1), 6)]);
+
+        assert_eq!(map.len(), 4);
+        let mut targets = map.targets();
+        targets.sort_by_key(|position| (position.x, position.y, position.z));
+        assert_eq!(targets, vec![ChunkPosition::new(-1, 1, 0), ChunkPosition::new(1, 0, 0)]);
+
+        let mut spills = map.take(&ChunkPosition::new(1, 0, 0));
+        spills.sort_by_key(|(source, _)| source.x);
+        assert_eq!(spills.len(), 2);
+        assert_eq!(spills[0], (source, vec![(BlockPosition::new(32, 5, 5), 3), (BlockPosition::new(33, 5, 5), 3)]));
+        assert_eq!(spills[1].0, ChunkPosition::new(2, 0, 0));
+        assert!(map.take(&ChunkPosition::new(1, 0, 0)).is_empty());
+        assert_eq!(map.len(), 1);
+    }
+
+    #[test]
+    fn save_and_load(){
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("spills");
+        assert!(SpillMap::load(&path).unwrap().is_empty());
+
+        let map = SpillMap::default();
+        map.queue(ChunkPosition::new(-5, 2, 9), vec![(BlockPosition::new(-161, 70, 300), 12), (BlockPosition::new(-160, 96, 300), 13)]);
+        map.save(&path).unwrap();
+
+        let loaded = SpillMap::load(&path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.take(&ChunkPosition::new(-6, 2, 9)), vec![(ChunkPosition::new(-5, 2, 9), vec![(BlockPosition::new(-161, 70, 300), 12)])]);
+        assert_eq!(loaded.take(&ChunkPosition::new(-5, 3, 9)), vec![(ChunkPosition::new(-5, 2, 9), vec![(BlockPosition::new(-160, 96, 300), 13)])]);
+
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 2]).unwrap();
+        assert!(SpillMap::load(&path).is_err());
+    }
+}
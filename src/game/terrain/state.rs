@@ -0,0 +1,392 @@
+use super::block::{BlockData, Direction, Rotation};
+use super::model::BlockModel;
+
+use std::convert::TryFrom;
+
+/// Per-instance value a block can declare, every combination of its
+/// properties' values is a separate block state
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Property{
+    /// Direction the block's north face is turned towards, values are `Direction`s
+    Facing,
+    /// Axis the block's top face is turned towards: 0 for x, 1 for y and 2 for z
+    Axis,
+    /// Closed (0) or open (1), like doors
+    Open,
+    /// Fill level from 0 to 15, like liquids
+    Level
+}
+
+impl Property{
+    /// Amount of values the property can take
+    pub fn values(&self) -> usize{
+        match self{
+            Property::Facing => 6,
+            Property::Axis => 3,
+            Property::Open => 2,
+            Property::Level => 16,
+        }
+    }
+
+    /// Value in the block's default state
+    pub fn default_value(&self) -> usize{
+        match self{
+            Property::Facing => Direction::North as usize,
+            Property::Axis => 1,
+            Property::Open | Property::Level => 0,
+        }
+    }
+}
+
+/// Rotation turning a block from its default orientation (facing north, axis
+/// along y) into the orientation of one of its states
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Orientation([[isize; 3]; 3]);
+
+impl Orientation{
+    pub const IDENTITY: Orientation = Orientation([[1, 0, 0], [0, 1, 0], [0, 0, 1]]);
+
+    /// Quarter turns around an axis: y turns north to east, x turns north to the top
+    /// and z turns the top to east
+    fn turns(axis: usize, quarters: usize) -> Self{
+        let quarter = match axis{
+            0 => Orientation([[1, 0, 0], [0, 0, 1], [0, -1, 0]]),
+            1 => Orientation([[0, 0, 1], [0, 1, 0], [-1, 0, 0]]),
+            2 => Orientation([[0, 1, 0], [-1, 0, 0], [0, 0, 1]]),
+            _ => panic!("Unknown axis")
+        };
+        (0..quarters % 4).fold(Self::IDENTITY, |orientation, _| quarter.after(orientation))
+    }
+
+    /// Orientation of a block state from its property values
+    pub fn of(properties: &[Property], values: &[usize]) -> Self{
+        let mut orientation = Self::IDENTITY;
+        for (property, value) in properties.iter().zip(values){
+            let turn = match (property, Direction::try_from(*value).ok()){
+                (Property::Facing, Some(Direction::East)) => Self::turns(1, 1),
+                (Property::Facing, Some(Direction::South)) => Self::turns(1, 2),
+                (Property::Facing, Some(Direction::West)) => Self::turns(1, 3),
+                (Property::Facing, Some(Direction::Top)) => Self::turns(0, 1),
+                (Property::Facing, Some(Direction::Bottom)) => Self::turns(0, 3),
+                (Property::Axis, _) if *value == 0 => Self::turns(2, 1),
+                (Property::Axis, _) if *value == 2 => Self::turns(0, 3),
+                _ => continue
+            };
+            orientation = turn.after(orientation);
+        }
+        orientation
+    }
+
+    pub fn apply(&self, v: [isize; 3]) -> [isize; 3]{
+        let m = &self.0;
+        [
+            m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2],
+            m[1][0] * v[0] + m[1][1] * v[1] + m[1][2] * v[2],
+            m[2][0] * v[0] + m[2][1] * v[1] + m[2][2] * v[2]
+        ]
+    }
+
+    /// Turns a point of the block around the block's center
+    pub fn apply_point(&self, p: [f32; 3]) -> [f32; 3]{
+        let mut turned = [0.5; 3];
+        for (i, value) in turned.iter_mut().enumerate(){
+            *value += (0..3).map(|j| self.0[i][j] as f32 * (p[j] - 0.5)).sum::<f32>();
+        }
+        turned
+    }
+
+    /// `other` followed by this rotation
+    fn after(&self, other: Orientation) -> Self{
+        let mut m = [[0; 3]; 3];
+        for (i, row) in m.iter_mut().enumerate(){
+            for (j, value) in row.iter_mut().enumerate(){
+                *value = (0..3).map(|k| self.0[i][k] * other.0[k][j]).sum();
+            }
+        }
+        Orientation(m)
+    }
+
+    pub fn inverse(&self) -> Self{
+        let m = &self.0;
+        Orientation([[m[0][0], m[1][0], m[2][0]], [m[0][1], m[1][1], m[2][1]], [m[0][2], m[1][2], m[2][2]]])
+    }
+}
+
+/// Box of a state's model, turned into the state's orientation, with the texture
+/// and texture rotation of each world face
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StateBox{
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+    pub faces: [[u32; 2]; 6],
+    pub rotations: [Rotation; 6]
+}
+
+impl StateBox{
+    /// Whether the box's face in a direction lies on the block's side
+    pub fn touches(&self, direction: Direction) -> bool{
+        let axis = direction as usize / 2;
+        if direction.normal()[axis] > 0 { self.max[axis] >= 1. } else { self.min[axis] <= 0. }
+    }
+
+    /// Whether the box's face in a direction covers a point of the block's side,
+    /// given by its coordinates along the two other axes
+    fn covers(&self, direction: Direction, point: [f32; 2]) -> bool{
+        let axis = direction as usize / 2;
+        let (a, b) = ((axis + 1) % 3, (axis + 2) % 3);
+        self.touches(direction)
+            && self.min[a] <= point[0] && point[0] <= self.max[a]
+            && self.min[b] <= point[1] && point[1] <= self.max[b]
+    }
+}
+
+/// A block with a value for each of its properties, along with the faces and
+/// model it resolves to once the block is turned into the state's orientation
+pub struct BlockState{
+    block: usize,
+    values: Vec<usize>,
+    faces: [[u32; 2]; 6],
+    rotations: [Rotation; 6],
+    cube: bool,
+    cross: bool,
+    boxes: Vec<StateBox>,
+    /// Sides of the block entirely covered by the model
+    full: [bool; 6]
+}
+
+impl BlockState{
+    pub fn new(block: usize, data: &BlockData, values: Vec<usize>) -> Self{
+        let orientation = Orientation::of(data.properties(), &values);
+        let inverse = orientation.inverse();
+        let axes = |direction: Direction| {
+            let (u, v) = direction.texture_axes();
+            let round = |a: [f32; 3]| [a[0] as isize, a[1] as isize, a[2] as isize];
+            (round(u), round(v))
+        };
+
+        // the world face shows the model face that was turned onto it, with its
+        // texture turned along
+        let mut models = [Direction::North; 6];
+        let mut turns = [Rotation::R0; 6];
+        for (i, (model, turn)) in models.iter_mut().zip(turns.iter_mut()).enumerate(){
+            let direction = Direction::try_from(i).expect("Unknown direction");
+            *model = Direction::from_normal(inverse.apply(direction.normal()));
+            let up = orientation.apply(axes(*model).1);
+            let (u, v) = axes(direction);
+            let neg = |a: [isize; 3]| [-a[0], -a[1], -a[2]];
+            *turn = if up == v{
+                Rotation::R0
+            }else if up == u{
+                Rotation::R90
+            }else if up == neg(v){
+                Rotation::R180
+            }else{
+                Rotation::R270
+            };
+        }
+
+        let resolve = |model_faces: &[[u32; 2]; 6]| {
+            let mut faces = [[0, 0]; 6];
+            let mut rotations = [Rotation::R0; 6];
+            for i in 0..6{
+                faces[i] = model_faces[models[i] as usize];
+                rotations[i] = data.get_rotation(models[i]).then(turns[i]);
+            }
+            (faces, rotations)
+        };
+
+        let block_faces: [[u32; 2]; 6] = {
+            let mut faces = [[0, 0]; 6];
+            for (i, face) in faces.iter_mut().enumerate(){
+                *face = data.get_face(Direction::try_from(i).expect("Unknown direction"));
+            }
+            faces
+        };
+        let (faces, rotations) = resolve(&block_faces);
+
+        let boxes: Vec<StateBox> = data.model().boxes().iter().map(|model_box| {
+            let (a, b) = (orientation.apply_point(model_box.min), orientation.apply_point(model_box.max));
+            let (faces, rotations) = resolve(model_box.faces.as_ref().unwrap_or(&block_faces));
+            StateBox{
+                min: [a[0].min(b[0]), a[1].min(b[1]), a[2].min(b[2])],
+                max: [a[0].max(b[0]), a[1].max(b[1]), a[2].max(b[2])],
+                faces,
+                rotations
+            }
+        }).collect();
+
+        // a side is full when the boxes touching it cover the center of each of its texels
+        let mut full = [false; 6];
+        for (i, side) in full.iter_mut().enumerate(){
+            let direction = Direction::try_from(i).expect("Unknown direction");
+            let texel = |i: usize| (i as f32 + 0.5) / 16.;
+            *side = (0..16 * 16).all(|i| {
+                let point = [texel(i / 16), texel(i % 16)];
+                boxes.iter().any(|state_box| state_box.covers(direction, point))
+            });
+        }
+
+        Self{
+            block,
+            values,
+            faces,
+            rotations,
+            cube: data.model().is_cube(),
+            cross: *data.model() == BlockModel::Cross,
+            boxes,
+            full
+        }
+    }
+    /// Id of the block this is a state of
+    pub fn block(&self) -> usize{
+        self.block
+    }
+
+    /// Values of the block's properties, in the order they were declared
+    pub fn values(&self) -> &[usize]{
+        &self.values
+    }
+
+    pub fn get_face(&self, dir: Direction) -> [u32; 2]{
+        self.faces[dir as usize]
+    }
+
+    pub fn get_rotation(&self, dir: Direction) -> Rotation{
+        self.rotations[dir as usize]
+    }
+
+    /// Full cubes are greedy meshed, other models are meshed block by block
+    pub fn is_cube(&self) -> bool{
+        self.cube
+    }
+
+    pub fn is_cross(&self) -> bool{
+        self.cross
+    }
+
+    pub fn boxes(&self) -> &[StateBox]{
+        &self.boxes
+    }
+
+    /// Whether the model covers the whole side of the block in a direction,
+    /// only such sides hide their neighbour's faces
+    pub fn is_full(&self, direction: Direction) -> bool{
+        self.full[direction as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::game::game::create_registry;
+    use crate::game::registry::Registry;
+
+    const DIRECTIONS: [Direction; 6] = [Direction::East, Direction::West, Direction::Top, Direction::Bottom, Direction::North, Direction::South];
+
+    fn block(registry: &Registry, name: &str) -> usize{
+        registry.block_registry().id_of(name).unwrap()
+    }
+
+    fn faces(registry: &Registry, state: usize) -> Vec<[u32; 2]>{
+        let state = registry.block_registry().state(state).unwrap();
+        DIRECTIONS.iter().map(|direction| state.get_face(*direction)).collect()
+    }
+
+    #[test]
+    fn state_ids_are_contiguous(){
+        let registry = create_registry();
+        let blocks = registry.block_registry();
+
+        let mut owners = Vec::new();
+        while let Some(state) = blocks.state(owners.len()){
+            owners.push(state.block());
+        }
+        assert!(owners.windows(2).all(|pair| pair[0] <= pair[1]));
+        for (id, data) in (0..).map_while(|id| blocks.by_id(id).map(|data| (id, data))){
+            assert_eq!(owners.iter().filter(|owner| **owner == id).count(), data.state_count());
+        }
+
+        let furnace = block(&registry, "furnace");
+        let first = blocks.state_of(furnace, &[(Property::Facing, 0)]).unwrap();
+        for facing in 0..6{
+            let state = blocks.state_of(furnace, &[(Property::Facing, facing)]).unwrap();
+            assert_eq!(state, first + facing);
+            assert_eq!(blocks.block_of(state), furnace);
+            assert_eq!(blocks.property(state, Property::Facing), Some(facing));
+            assert_eq!(blocks.with_property(first, Property::Facing, facing), Some(state));
+        }
+        assert_eq!(blocks.property(blocks.default_state(furnace), Property::Facing), Some(Direction::North as usize));
+        assert_eq!(blocks.state_of(furnace, &[(Property::Facing, 6)]), None);
+        assert_eq!(blocks.state_of(furnace, &[(Property::Axis, 0)]), None);
+        assert_eq!(blocks.property(first, Property::Open), None);
+    }
+
+    #[test]
+    fn orientations_are_rotations(){
+        for facing in 0..6{
+            let orientation = Orientation::of(&[Property::Facing], &[facing]);
+            assert_eq!(orientation.after(orientation.inverse()), Orientation::IDENTITY);
+            // the north face is turned to face where the block faces
+            assert_eq!(orientation.apply(Direction::North.normal()), Direction::try_from(facing).unwrap().normal());
+        }
+        assert_eq!(Orientation::turns(1, 4), Orientation::IDENTITY);
+        assert_eq!(Orientation::of(&[Property::Axis], &[0]).apply_point([0.5, 1., 0.5]), [1., 0.5, 0.5]);
+    }
+
+    #[test]
+    fn facing_turns_the_faces(){
+        let registry = create_registry();
+        let blocks = registry.block_registry();
+        let furnace = block(&registry, "furnace");
+        let (side, front, top) = ([2, 14], [3, 14], [4, 14]);
+
+        let state = |facing: Direction| blocks.state_of(furnace, &[(Property::Facing, facing as usize)]).unwrap();
+        // east, west, top, bottom, north, south
+        assert_eq!(faces(&registry, state(Direction::North)), vec![side, side, top, top, front, side]);
+        assert_eq!(faces(&registry, state(Direction::East)), vec![front, side, top, top, side, side]);
+        assert_eq!(faces(&registry, state(Direction::South)), vec![side, side, top, top, side, front]);
+        assert_eq!(faces(&registry, state(Direction::West)), vec![side, front, top, top, side, side]);
+        // turned over towards the south, the top ends up there
+        assert_eq!(faces(&registry, state(Direction::Top)), vec![side, side, front, side, top, top]);
+
+        // the texture turns along with the face it's on
+        let east = blocks.state(state(Direction::East)).unwrap();
+        assert_eq!(east.get_rotation(Direction::Top), Rotation::R270);
+        assert_eq!(east.get_rotation(Direction::East), Rotation::R0);
+    }
+
+    #[test]
+    fn axis_turns_the_faces(){
+        let registry = create_registry();
+        let blocks = registry.block_registry();
+        let log = block(&registry, "log");
+        let (bark, rings) = ([10, 15], [11, 15]);
+
+        let state = |axis: usize| blocks.state_of(log, &[(Property::Axis, axis)]).unwrap();
+        assert_eq!(faces(&registry, state(0)), vec![rings, rings, bark, bark, bark, bark]);
+        assert_eq!(faces(&registry, state(1)), vec![bark, bark, rings, rings, bark, bark]);
+        assert_eq!(faces(&registry, state(2)), vec![bark, bark, bark, bark, rings, rings]);
+        assert_eq!(blocks.default_state(log), state(1));
+    }
+
+    #[test]
+    fn full_sides(){
+        let registry = create_registry();
+        let blocks = registry.block_registry();
+        let stone = blocks.state(blocks.default_state(block(&registry, "stone"))).unwrap();
+        assert!(stone.is_cube());
+        assert!(DIRECTIONS.iter().all(|direction| stone.is_full(*direction)));
+
+        let slab = blocks.state(blocks.default_state(block(&registry, "planks_slab"))).unwrap();
+        assert!(!slab.is_cube());
+        assert!(slab.is_full(Direction::Bottom));
+        assert!(!slab.is_full(Direction::Top));
+        assert!(!slab.is_full(Direction::North));
+        assert!(slab.boxes()[0].touches(Direction::North));
+
+        let torch = blocks.state(blocks.default_state(block(&registry, "torch"))).unwrap();
+        assert!(DIRECTIONS.iter().all(|direction| !torch.is_full(*direction)));
+        assert!(torch.boxes()[0].touches(Direction::Bottom));
+    }
+}
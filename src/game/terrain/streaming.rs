@@ -0,0 +1,136 @@
+use super::chunk::{ChunkPosition, CHUNKSIZE};
+
+use cgmath::Point3;
+
+/// How far around the player chunks are kept loaded, in chunks
+#[derive(Debug, Clone, Copy)]
+pub struct StreamingConfig{
+    pub horizontal: isize,
+    pub vertical: isize,
+    /// Extra distance a chunk may drift past the load radius before it's evicted
+    pub hysteresis: isize,
+    /// Maximum amount of chunks requested each tick
+    pub requests_per_tick: usize,
+    /// Distances at which chunks start being meshed at 2x, 4x and 8x lower resolution,
+    /// measured like the load radius
+    pub lod_distances: [isize; 3]
+}
+
+impl Default for StreamingConfig{
+    fn default() -> Self{
+        let horizontal = 4;
+        Self{
+            horizontal,
+            vertical: 2,
+            hysteresis: 2,
+            requests_per_tick: 16,
+            // the outer ring gets coarser and chunks only kept by the hysteresis coarser still
+            lod_distances: [horizontal - 1, horizontal, horizontal + 1]
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StreamingStats{
+    /// Chunks currently resident in memory
+    pub loaded: usize,
+    /// Chunks requested but not generated or loaded yet
+    pub pending: usize,
+    /// Chunks waiting for or being meshed
+    pub meshing: usize,
+    /// Meshed chunks without any face
+    pub empty: usize,
+    /// Chunks evicted since the manager was created
+    pub evicted: usize,
+    /// Feature blocks waiting for their chunk to be loaded
+    pub spilled: usize,
+    /// Bytes of the chunk meshes in the renderer
+    pub mesh_memory: usize,
+    /// Bytes the same meshes would take with full-precision vertices
+    pub unpacked_mesh_memory: usize
+}
+
+/// Chunk containing a world position
+pub fn chunk_at(position: Point3<f64>) -> ChunkPosition{
+    let size = CHUNKSIZE as f64;
+    ChunkPosition::new((position.x / size).floor() as isize, (position.y / size).floor() as isize, (position.z / size).floor() as isize)
+}
+
+fn distance_squared(a: &ChunkPosition, b: &ChunkPosition) -> isize{
+    let (dx, dy, dz) = (a.x - b.x, a.y - b.y, a.z - b.z);
+    dx * dx + dy * dy + dz * dz
+}
+
+/// Every chunk within the load radius of `center`, nearest first
+pub fn chunks_in_range(center: &ChunkPosition, config: &StreamingConfig) -> Vec<ChunkPosition>{
+    let mut positions = Vec::new();
+    for x in -config.horizontal..=config.horizontal{
+        for y in -config.vertical..=config.vertical{
+            for z in -config.horizontal..=config.horizontal{
+                positions.push(ChunkPosition::new(center.x + x, center.y + y, center.z + z));
+            }
+        }
+    }
+
+    positions.sort_by_key(|position| distance_squared(position, center));
+    positions
+}
+
+/// Whether a loaded chunk drifted past the eviction radius
+pub fn should_evict(position: &ChunkPosition, center: &ChunkPosition, config: &StreamingConfig) -> bool{
+    let horizontal = config.horizontal + config.hysteresis;
+    let vertical = config.vertical + config.hysteresis;
+    (position.x - center.x).abs() > horizontal || (position.z - center.z).abs() > horizontal || (position.y - center.y).abs() > vertical
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn config() -> StreamingConfig{
+        StreamingConfig{
+            horizontal: 3,
+            vertical: 1,
+            hysteresis: 2,
+            ..StreamingConfig::default()
+        }
+    }
+
+    #[test]
+    fn chunks_nearest_first(){
+        let center = ChunkPosition::new(5, -2, -7);
+        let positions = chunks_in_range(&center, &config());
+        assert_eq!(positions.len(), 7 * 3 * 7);
+        assert_eq!(positions[0], center);
+        let distances: Vec<isize> = positions.iter().map(|position| distance_squared(position, &center)).collect();
+        assert!(distances.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", distances);
+        assert!(positions.iter().all(|p| (p.x - center.x).abs() <= 3 && (p.y - center.y).abs() <= 1 && (p.z - center.z).abs() <= 3));
+    }
+
+    #[test]
+    fn eviction_waits_for_the_hysteresis(){
+        let config = config();
+        let center = ChunkPosition::new(0, 0, 0);
+        let evicted = |x: isize, y: isize, z: isize| should_evict(&ChunkPosition::new(x, y, z), &center, &config);
+
+        // just past the load radius, and up to the hysteresis past it
+        assert!(!evicted(4, 0, 0));
+        assert!(!evicted(5, 0, -5));
+        assert!(!evicted(0, 2, 0));
+        assert!(!evicted(-5, -3, 5));
+        assert!(evicted(6, 0, 0));
+        assert!(evicted(0, 0, -6));
+        assert!(evicted(0, 4, 0));
+        assert!(evicted(0, -4, 0));
+        // the radii are relative to the player
+        let moved = ChunkPosition::new(10, 10, 10);
+        assert!(should_evict(&ChunkPosition::new(4, 10, 10), &moved, &config));
+        assert!(!should_evict(&ChunkPosition::new(5, 10, 10), &moved, &config));
+    }
+
+    #[test]
+    fn chunk_at_rounds_down(){
+        assert_eq!(chunk_at(Point3::new(0.5, 31.9, 32.)), ChunkPosition::new(0, 0, 1));
+        assert_eq!(chunk_at(Point3::new(-0.5, -32., -32.1)), ChunkPosition::new(-1, -1, -2));
+    }
+}
@@ -0,0 +1,104 @@
+use crate::game::terrain::block::{Direction, Rotation};
+
+/// Steps per block of packed positions, enough for model boxes drawn on 16×16 textures
+pub const POSITION_STEPS: f32 = 16.;
+/// Packed positions go from 0 up to, but not including, this many blocks
+pub const MAX_POSITION: f32 = 1024. / POSITION_STEPS;
+
+/// Chunk mesh vertex packed in two `u32`s, decoded in `vertex.glsl`.
+///
+/// `position` holds the chunk-local position in 16ths of a block, 10 bits per
+/// axis, then the ambient occlusion from 0 to 3 in the last 2 bits.
+/// `face` holds the direction the face points to (3 bits), its texture rotation
+/// in quarter turns (2 bits), its atlas tile (4 bits per axis) and the light in
+/// front of it (8 bits, as stored in chunks).
+///
+/// Texture coordinates aren't stored: like `add_quad` does, the shader projects
+/// the position on the texture axes of the face, then rotates them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ChunkVertex{
+    pub position: u32,
+    pub face: u32
+}
+
+#[allow(dead_code)]
+impl ChunkVertex{
+    pub fn new(position: [f32; 3], direction: Direction, rotation: Rotation, tile: [u32; 2], light: u8, ao: u8) -> Self{
+        let step = |axis: usize| {
+            let step = (position[axis] * POSITION_STEPS).round();
+            debug_assert!((0. ..1024.).contains(&step), "Vertex position {:?} outside of 0..{}", position, MAX_POSITION);
+            step as u32 & 0x3FF
+        };
+
+        Self{
+            position: step(0) | step(1) << 10 | step(2) << 20 | (ao as u32 & 0b11) << 30,
+            face: direction as u32 | (rotation.quarters() as u32) << 3 | (tile[0] & 0xF) << 5 | (tile[1] & 0xF) << 9 | (light as u32) << 13
+        }
+    }
+
+    /// Chunk-local position
+    pub fn get_position(&self) -> [f32; 3]{
+        let step = |shift: u32| ((self.position >> shift) & 0x3FF) as f32 / POSITION_STEPS;
+        [step(0), step(10), step(20)]
+    }
+
+    pub fn get_ao(&self) -> u8{
+        (self.position >> 30) as u8
+    }
+
+    pub fn get_tile(&self) -> [u32; 2]{
+        [(self.face >> 5) & 0xF, (self.face >> 9) & 0xF]
+    }
+
+    pub fn get_light(&self) -> u8{
+        (self.face >> 13) as u8
+    }
+}
+
+implement_vertex!(ChunkVertex, position, face);
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn pack_roundtrip(){
+        let vertex = ChunkVertex::new([32., 0.5, 7. / 16.], Direction::South, Rotation::R270, [15, 14], 0xFF, 3);
+        assert_eq!(vertex.get_position(), [32., 0.5, 7. / 16.]);
+        assert_eq!(vertex.get_ao(), 3);
+        assert_eq!(vertex.get_light(), 0xFF);
+        assert_eq!(vertex.get_tile(), [15, 14]);
+        // read by the shader only
+        assert_eq!(vertex.face & 0b111, Direction::South as u32);
+        assert_eq!((vertex.face >> 3) & 0b11, 3);
+
+        let vertex = ChunkVertex::new([0., 31.9375, 16.], Direction::East, Rotation::R0, [0, 0], 0, 0);
+        assert_eq!(vertex.get_position(), [0., 31.9375, 16.]);
+        assert_eq!((vertex.get_ao(), vertex.get_light(), vertex.get_tile()), (0, 0, [0, 0]));
+        assert_eq!(std::mem::size_of::<ChunkVertex>(), 8);
+    }
+
+    #[test]
+    fn position_bounds(){
+        let last = MAX_POSITION - 1. / POSITION_STEPS;
+        let vertex = ChunkVertex::new([0., last, last], Direction::Top, Rotation::R0, [0, 0], 0, 0);
+        assert_eq!(vertex.get_position(), [0., 63.9375, 63.9375]);
+        // rounded to the nearest step
+        let vertex = ChunkVertex::new([-0.01, 63.96, 1. / 64.], Direction::Top, Rotation::R0, [0, 0], 0, 0);
+        assert_eq!(vertex.get_position(), [0., 63.9375, 0.]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "outside of 0..64")]
+    fn position_past_the_end(){
+        ChunkVertex::new([0., MAX_POSITION, 0.], Direction::Top, Rotation::R0, [0, 0], 0, 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "outside of 0..64")]
+    fn position_before_the_start(){
+        ChunkVertex::new([-1. / POSITION_STEPS, 0., 0.], Direction::Top, Rotation::R0, [0, 0], 0, 0);
+    }
+}
@@ -0,0 +1,208 @@
+use crate::game::registry::Registry;
+use crate::game::terrain::block::Direction;
+use super::chunk::{Chunk, ChunkPosition, CHUNKSIZE};
+use super::mesher::is_opaque;
+
+use std::collections::{HashSet, VecDeque};
+use std::convert::TryFrom;
+
+/// Which sides of a chunk can see each other through its non-opaque blocks, as a
+/// symmetric 6×6 bit matrix indexed in `Direction` order
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Connectivity(u64);
+
+impl Connectivity{
+    /// Every side sees every other one, like in an empty chunk
+    pub const OPEN: Connectivity = Connectivity((1 << 36) - 1);
+
+    fn bit(a: Direction, b: Direction) -> u64{
+        1 << (a as usize * 6 + b as usize)
+    }
+
+    pub fn connect(&mut self, a: Direction, b: Direction){
+        self.0 |= Self::bit(a, b) | Self::bit(b, a);
+    }
+
+    pub fn connects(&self, a: Direction, b: Direction) -> bool{
+        self.0 & Self::bit(a, b) != 0
+    }
+
+    /// Flood fills the chunk's non-opaque blocks, connecting every pair of sides
+    /// touched by the same open region
+    pub fn of_chunk(chunk: &Chunk, registry: &Registry) -> Self{
+        if let Some(block) = chunk.uniform_block(){
+            return if is_opaque(block, registry) { Self::default() } else { Self::OPEN };
+        }
+
+        let index = |p: [usize; 3]| (p[0] * CHUNKSIZE + p[1]) * CHUNKSIZE + p[2];
+        let mut visited = vec![false; CHUNKSIZE * CHUNKSIZE * CHUNKSIZE];
+        let mut connectivity = Self::default();
+        let mut stack = Vec::new();
+
+        for x in 0..CHUNKSIZE{
+            for y in 0..CHUNKSIZE{
+                for z in 0..CHUNKSIZE{
+                    let start = [x, y, z];
+                    if visited[index(start)] || is_opaque(chunk.get_block(x, y, z), registry) { continue }
+
+                    // sides touched by this region, as a bit per direction
+                    let mut sides = 0u8;
+                    visited[index(start)] = true;
+                    stack.push(start);
+                    while let Some(p) = stack.pop(){
+                        for i in 0..6{
+                            let direction = Direction::try_from(i).expect("Unknown direction");
+                            let normal = direction.normal();
+                            let next = [p[0] as isize + normal[0], p[1] as isize + normal[1], p[2] as isize + normal[2]];
+                            if next.iter().any(|c| *c < 0 || *c >= CHUNKSIZE as isize){
+                                sides |= 1 << i;
+                                continue;
+                            }
+
+                            let next = [next[0] as usize, next[1] as usize, next[2] as usize];
+                            if visited[index(next)] || is_opaque(chunk.get_block(next[0], next[1], next[2]), registry) { continue }
+                            visited[index(next)] = true;
+                            stack.push(next);
+                        }
+                    }
+
+                    for a in 0..6{
+                        for b in 0..6{
+                            if sides & (1 << a) != 0 && sides & (1 << b) != 0{
+                                connectivity.connect(Direction::try_from(a).expect("Unknown direction"), Direction::try_from(b).expect("Unknown direction"));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        connectivity
+    }
+}
+
+/// Chunks that may be seen from the `start` chunk, found by a breadth-first search
+/// through open sides.
+///
+/// A chunk is only left through a side connected to the one it was entered by, the
+/// search never heads back in a direction opposite to one it already took, and
+/// chunks rejected by `in_view` (like the ones outside the view frustum) are skipped.
+/// `connectivity` returns `None` for chunks the search shouldn't go through.
+pub fn visible_chunks<C, V>(start: ChunkPosition, connectivity: C, in_view: V) -> HashSet<ChunkPosition>
+where C: Fn(&ChunkPosition) -> Option<Connectivity>, V: Fn(&ChunkPosition) -> bool{
+    let mut visible = HashSet::new();
+    let mut queue = VecDeque::new();
+    visible.insert(start);
+    queue.push_back((start, None, 0u8));
+
+    while let Some((position, entered, travelled)) = queue.pop_front(){
+        let connectivity = match connectivity(&position){
+            Some(connectivity) => connectivity,
+            None => continue
+        };
+
+        for i in 0..6{
+            let direction = Direction::try_from(i).expect("Unknown direction");
+            if travelled & (1 << direction.opposite() as usize) != 0 { continue }
+            if let Some(entered) = entered{
+                if !connectivity.connects(entered, direction) { continue }
+            }
+
+            let normal = direction.normal();
+            let neighbor = ChunkPosition::new(position.x + normal[0], position.y + normal[1], position.z + normal[2]);
+            if visible.contains(&neighbor) || !in_view(&neighbor) { continue }
+
+            visible.insert(neighbor);
+            queue.push_back((neighbor, Some(direction.opposite()), travelled | 1 << i));
+        }
+    }
+
+    visible
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::game::game::create_registry;
+    use Direction::*;
+
+    fn state(registry: &Registry, name: &str) -> usize{
+        let blocks = registry.block_registry();
+        blocks.default_state(blocks.id_of(name).unwrap())
+    }
+
+    #[test]
+    fn uniform_chunks(){
+        let registry = create_registry();
+        assert_eq!(Connectivity::of_chunk(&Chunk::new(0), &registry), Connectivity::OPEN);
+        assert_eq!(Connectivity::of_chunk(&Chunk::new(state(&registry, "stone")), &registry), Connectivity::default());
+    }
+
+    #[test]
+    fn tunnels(){
+        let registry = create_registry();
+        let mut chunk = Chunk::new(state(&registry, "stone"));
+        for x in 0..CHUNKSIZE{
+            chunk.set_block(x, 10, 10, 0);
+        }
+        let connectivity = Connectivity::of_chunk(&chunk, &registry);
+        assert!(connectivity.connects(East, West) && connectivity.connects(West, East));
+        assert!(!connectivity.connects(East, Top) && !connectivity.connects(North, South) && !connectivity.connects(Top, Bottom));
+
+        // a shaft apart from the tunnel
+        for y in 0..CHUNKSIZE{
+            chunk.set_block(20, y, 20, 0);
+        }
+        let connectivity = Connectivity::of_chunk(&chunk, &registry);
+        assert!(connectivity.connects(Top, Bottom) && !connectivity.connects(Top, East));
+
+        // joined to it
+        for z in 10..=20{
+            chunk.set_block(20, 10, z, 0);
+        }
+        let connectivity = Connectivity::of_chunk(&chunk, &registry);
+        assert!(connectivity.connects(Top, East) && connectivity.connects(Bottom, West));
+        assert!(!connectivity.connects(North, South));
+
+        // leaves can be seen through
+        let mut chunk = Chunk::new(state(&registry, "stone"));
+        for z in 0..CHUNKSIZE{
+            chunk.set_block(5, 5, z, state(&registry, "leaves"));
+        }
+        assert!(Connectivity::of_chunk(&chunk, &registry).connects(North, South));
+    }
+
+    /// Open chunks in a 7×7×11 box, with a closed wall at z = 2 that has a hole at x = 1 when `hole` is set
+    fn walled(hole: bool) -> impl Fn(&ChunkPosition) -> Option<Connectivity>{
+        move |position: &ChunkPosition| {
+            if position.x.abs() > 3 || position.y.abs() > 3 || position.z.abs() > 5 { return None }
+            if position.z == 2 && !(hole && position.x == 1 && position.y == 0) { Some(Connectivity::default()) } else { Some(Connectivity::OPEN) }
+        }
+    }
+
+    #[test]
+    fn visible_through_open_chunks(){
+        let start = ChunkPosition::new(0, 0, 0);
+        let visible = visible_chunks(start, walled(true), |_| true);
+        // the wall itself is seen, and what's behind it through the hole
+        assert!(visible.contains(&ChunkPosition::new(0, 0, 2)));
+        assert!(visible.contains(&ChunkPosition::new(1, 0, 3)));
+        assert!(visible.contains(&ChunkPosition::new(1, 0, 5)));
+        assert!(visible.contains(&ChunkPosition::new(0, 0, -5)));
+        // going through the hole then back sideways isn't allowed
+        assert!(!visible.contains(&ChunkPosition::new(-3, 0, 3)));
+        // nothing past the box
+        assert!(!visible.iter().any(|position| position.z.abs() > 6 || position.x.abs() > 4));
+
+        let visible = visible_chunks(start, walled(false), |_| true);
+        assert!(visible.contains(&ChunkPosition::new(1, 0, 2)));
+        assert!(!visible.iter().any(|position| position.z > 2));
+    }
+
+    #[test]
+    fn visible_in_view_only(){
+        let visible = visible_chunks(ChunkPosition::new(0, 0, 0), walled(true), |position| position.z >= 0);
+        assert!(!visible.iter().any(|position| position.z < 0));
+        assert!(visible.contains(&ChunkPosition::new(1, 0, 3)));
+    }
+}
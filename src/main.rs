@@ -8,9 +8,50 @@ mod engine;
 mod game;
 mod utils;
 
+use crate::engine::headless::HeadlessRenderer;
+use crate::engine::renderer::{Context, DEFAULT_HEIGHT, DEFAULT_WIDTH};
 use crate::game::game::Game;
 
+use std::path::Path;
+use std::time::Duration;
+
+/// Frames played by `--headless`, about 10 seconds
+const HEADLESS_FRAMES: usize = 600;
+
 fn main() {
-    let mut game = Game::new("Cave game v0.1.0");
+    // times the chunk mesher instead of starting the game
+    if std::env::args().any(|arg| arg == "--bench-mesher") {
+        game::terrain::mesh_bench::run();
+        return;
+    }
+
+    // plays without a window, for machines without a GPU or a display
+    if std::env::args().any(|arg| arg == "--headless") {
+        // in a world of its own, so the player's world isn't touched
+        let world_path = std::env::temp_dir().join(format!("voxel_game_headless_{}", std::process::id()));
+        let renderer = HeadlessRenderer::new(DEFAULT_WIDTH as f64 / DEFAULT_HEIGHT as f64);
+        let mut game = Game::new(renderer, &world_path);
+        game.setup();
+        for _ in 0..HEADLESS_FRAMES {
+            game.tick();
+            // there's no vsync to wait for
+            std::thread::sleep(Duration::from_millis(16));
+        }
+
+        let stats = game.terrain().streaming_stats();
+        println!("{} frames, {} chunks loaded, {} meshes uploaded, {} drawn in the last frame",
+            game.renderer().frame_count(), stats.loaded, game.renderer().meshes().len(), game.renderer().drawn_meshes());
+        println!("Chunk meshes take {} bytes, {} with full-precision vertices", stats.mesh_memory, stats.unpacked_mesh_memory);
+
+        drop(game);
+        if let Err(e) = std::fs::remove_dir_all(&world_path) {
+            println!("Couldn't remove the headless world: {}", e);
+        }
+        return;
+    }
+
+    let world_path = Path::new("saves").join("world");
+    let context = Context::new("Cave game v0.1.0");
+    let mut game = Game::new(context, &world_path);
     game.run();
 }
@@ -0,0 +1,139 @@
+use cgmath::{InnerSpace, Matrix, Matrix4, Point3, Vector3, Vector4};
+
+/// Plane where `normal · p + distance` is zero, positive on the side the normal points to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Plane{
+    pub normal: Vector3<f64>,
+    pub distance: f64
+}
+
+impl Plane{
+    fn from_row(row: Vector4<f64>) -> Self{
+        let normal = Vector3::new(row.x, row.y, row.z);
+        let length = normal.magnitude();
+
+        Self{
+            normal: normal / length,
+            distance: row.w / length
+        }
+    }
+
+    /// Signed distance from the plane, positive on the normal's side
+    pub fn distance_to(&self, point: Point3<f64>) -> f64{
+        self.normal.x * point.x + self.normal.y * point.y + self.normal.z * point.z + self.distance
+    }
+}
+
+/// View frustum as six planes facing inwards: left, right, bottom, top, near and far
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frustum{
+    planes: [Plane; 6]
+}
+
+impl Frustum{
+    /// Extracts the planes from a projection × view matrix, for OpenGL clip space
+    pub fn from_matrix(matrix: Matrix4<f64>) -> Self{
+        let row = |i: usize| matrix.row(i);
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+
+        Self{
+            planes: [
+                Plane::from_row(w + x),
+                Plane::from_row(w - x),
+                Plane::from_row(w + y),
+                Plane::from_row(w - y),
+                Plane::from_row(w + z),
+                Plane::from_row(w - z)
+            ]
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn contains_point(&self, point: Point3<f64>) -> bool{
+        self.planes.iter().all(|plane| plane.distance_to(point) >= 0.)
+    }
+
+    /// Whether an axis-aligned box is at least partly inside.
+    ///
+    /// Conservative: boxes near a corner of the frustum may pass while being outside.
+    pub fn intersects_aabb(&self, min: Point3<f64>, max: Point3<f64>) -> bool{
+        self.planes.iter().all(|plane| {
+            // the corner farthest along the plane's normal
+            let corner = Point3::new(
+                if plane.normal.x >= 0. { max.x } else { min.x },
+                if plane.normal.y >= 0. { max.y } else { min.y },
+                if plane.normal.z >= 0. { max.z } else { min.z }
+            );
+            plane.distance_to(corner) >= 0.
+        })
+    }
+}
+
+/// Chunks drawn and skipped by culling during the last frame
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CullingStats{
+    pub drawn: usize,
+    /// Outside the view frustum
+    pub culled: usize,
+    /// Inside the frustum but hidden behind other chunks
+    pub occluded: usize
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use cgmath::{Deg, Rad};
+
+    /// Square frustum 90° wide, looking along +z from the origin, from 0.1 to 100
+    fn frustum() -> Frustum{
+        let projection = cgmath::perspective(Rad::from(Deg(90f64)), 1., 0.1, 100.);
+        let view = Matrix4::look_at(Point3::new(0., 0., 0.), Point3::new(0., 0., 1.), Vector3::unit_y());
+        Frustum::from_matrix(projection * view)
+    }
+
+    fn intersects(frustum: &Frustum, min: [f64; 3], max: [f64; 3]) -> bool{
+        frustum.intersects_aabb(Point3::new(min[0], min[1], min[2]), Point3::new(max[0], max[1], max[2]))
+    }
+
+    #[test]
+    fn planes_from_matrix(){
+        let frustum = frustum();
+        for plane in &frustum.planes{
+            assert!((plane.normal.magnitude() - 1.).abs() < 1e-9);
+            // every plane faces the inside
+            assert!(plane.distance_to(Point3::new(0., 0., 10.)) > 0.);
+        }
+
+        let on_plane = |plane: usize, point: [f64; 3]| frustum.planes[plane].distance_to(Point3::new(point[0], point[1], point[2])).abs() < 1e-6;
+        // the camera's left is +x when looking along +z
+        assert!(on_plane(0, [10., 0., 10.]));
+        assert!(on_plane(1, [-10., 0., 10.]));
+        assert!(on_plane(2, [0., -10., 10.]));
+        assert!(on_plane(3, [0., 10., 10.]));
+        assert!(on_plane(4, [3., 2., 0.1]));
+        assert!(on_plane(5, [-3., 2., 100.]));
+
+        assert!(frustum.contains_point(Point3::new(0., 0., 50.)));
+        assert!(!frustum.contains_point(Point3::new(0., 0., -1.)));
+        assert!(!frustum.contains_point(Point3::new(0., 0., 0.05)));
+    }
+
+    #[test]
+    fn boxes_against_the_frustum(){
+        let frustum = frustum();
+        // in front, behind and to either side
+        assert!(intersects(&frustum, [-1., -1., 5.], [1., 1., 7.]));
+        assert!(!intersects(&frustum, [-1., -1., -7.], [1., 1., -5.]));
+        assert!(!intersects(&frustum, [20., -1., 5.], [22., 1., 7.]));
+        assert!(!intersects(&frustum, [-22., -1., 5.], [-20., 1., 7.]));
+        assert!(!intersects(&frustum, [-1., 20., 5.], [1., 22., 7.]));
+        // straddling a side plane, the near plane and the far plane
+        assert!(intersects(&frustum, [4., -1., 5.], [8., 1., 7.]));
+        assert!(intersects(&frustum, [-1., -1., -1.], [1., 1., 1.]));
+        assert!(intersects(&frustum, [-1., -1., 99.], [1., 1., 101.]));
+        // past the far plane
+        assert!(!intersects(&frustum, [-1., -1., 101.], [1., 1., 103.]));
+        // big enough to hold the whole frustum
+        assert!(intersects(&frustum, [-200., -200., -200.], [200., 200., 200.]));
+    }
+}
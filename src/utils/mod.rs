@@ -1,4 +1,6 @@
 pub mod timer;
 pub mod camera;
+pub mod frustum;
 pub mod texture;
 pub mod raycast;
+pub mod random;
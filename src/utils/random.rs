@@ -0,0 +1,37 @@
+/// Small deterministic SplitMix64 generator, used where results must be reproducible from a seed
+#[derive(Debug, Clone)]
+pub struct Random{
+    state: u64
+}
+
+impl Random{
+    pub fn new(seed: u64) -> Self{
+        Self{
+            state: seed
+        }
+    }
+
+    /// Derives a seed from a world seed and a position, so every chunk gets its own stream
+    pub fn hash(seed: u64, position: [isize; 3], salt: u64) -> u64{
+        let mut random = Self::new(seed ^ salt.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        let mut hash = random.next_u64();
+        for coordinate in &position{
+            random.state ^= hash ^ (*coordinate as u64);
+            hash = random.next_u64();
+        }
+        hash
+    }
+
+    pub fn next_u64(&mut self) -> u64{
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in `[min, max)`, `max` must be greater than `min`
+    pub fn range(&mut self, min: isize, max: isize) -> isize{
+        min + (self.next_u64() % (max - min) as u64) as isize
+    }
+}